@@ -0,0 +1,88 @@
+//! Throughput of appending to a batcher, run with `cargo bench`.
+//!
+//! Prints the time per value appended, for a runner which finishes every
+//! batch right away, on a plain batcher and on one which lingers and weighs
+//! its values.
+
+extern crate atomic_batcher;
+
+use atomic_batcher::*;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const VALUES: usize = 1_000_000;
+
+type Setup = fn() -> Arc<Batcher<u64>>;
+
+fn plain() -> Arc<Batcher<u64>> {
+  Batcher::builder()
+    .max_batch_size(1_000)
+    .build(|_val: Vec<u64>, done: Done| done.ok())
+}
+
+fn lingering() -> Arc<Batcher<u64>> {
+  Batcher::builder()
+    .max_batch_size(1_000)
+    .max_batch_delay(Duration::from_millis(1))
+    .max_batch_bytes(8_000, |_| 8)
+    .build(|_val: Vec<u64>, done: Done| done.ok())
+}
+
+/// Time `append` on `batcher`, running the rest once it returns.
+fn bench<F>(name: &str, batcher: Arc<Batcher<u64>>, mut append: F)
+where
+  F: FnMut(&Batcher<u64>),
+{
+  let started = Instant::now();
+  append(&batcher);
+  batcher.flush();
+  let elapsed = started.elapsed();
+  let per_value = elapsed.as_nanos() as f64 / VALUES as f64;
+  println!("{:<36} {:>8.1} ns/value", name, per_value);
+}
+
+fn chunks(size: usize) -> impl Iterator<Item = Vec<u64>> {
+  (0..(VALUES / size) as u64).map(move |chunk| {
+    let start = chunk * size as u64;
+    (start..start + size as u64).collect()
+  })
+}
+
+fn main() {
+  bench("plain append_one", plain(), |batcher| {
+    for i in 0..VALUES as u64 {
+      batcher.append_one(i, None);
+    }
+  });
+  bench("plain append_one with callback", plain(), |batcher| {
+    for i in 0..VALUES as u64 {
+      batcher.append_one(i, Some(Box::new(|_| {})));
+    }
+  });
+  bench("lingering append_one", lingering(), |batcher| {
+    for i in 0..VALUES as u64 {
+      batcher.append_one(i, None);
+    }
+  });
+  for &size in &[10, 1_000] {
+    let batchers: [(&str, Setup); 2] =
+      [("plain", plain), ("lingering", lingering)];
+    for &(kind, batcher) in &batchers {
+      bench(
+        &format!("{} append x{}", kind, size),
+        batcher(),
+        |batcher| {
+          for chunk in chunks(size) {
+            batcher.append(chunk, None);
+          }
+        },
+      );
+      let name = format!("{} append_unchecked x{}", kind, size);
+      bench(&name, batcher(), |batcher| {
+        for chunk in chunks(size) {
+          batcher.append_unchecked(chunk, None);
+        }
+      });
+    }
+  }
+}
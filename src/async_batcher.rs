@@ -0,0 +1,71 @@
+use futures::sync::oneshot;
+use std::sync::Arc;
+use tokio;
+use tokio::prelude::*;
+use {BatchError, Batcher, Done};
+
+/// Batcher whose run function returns a future.
+///
+/// Each batch is spawned on the current tokio executor, so appends must
+/// happen from within a tokio runtime. The batch is done when the future
+/// returned by the run function resolves.
+pub struct AsyncBatcher<T> {
+  batcher: Arc<Batcher<T>>,
+}
+
+impl<T: Send + 'static> AsyncBatcher<T> {
+  /// Create a new async batcher with a run function.
+  pub fn new<F, R>(mut run: F) -> Self
+  where
+    F: FnMut(Vec<T>) -> R + Send + 'static,
+    R: Future<Item = (), Error = BatchError> + Send + 'static,
+  {
+    let batcher = Batcher::new(move |val: Vec<T>, done: Done| {
+      tokio::spawn(run(val).then(move |res| {
+        done.finish(res);
+        Ok(())
+      }));
+    });
+    AsyncBatcher { batcher }
+  }
+
+  /// Accept an array of values. The returned future resolves when the batch
+  /// containing the values has been run.
+  pub fn append(&self, val: Vec<T>) -> Appended {
+    let (tx, rx) = oneshot::channel();
+    self.batcher.append(
+      val,
+      Some(Box::new(move |res| {
+        let _ = tx.send(res);
+      })),
+    );
+    Appended { rx }
+  }
+}
+
+impl<T> Clone for AsyncBatcher<T> {
+  fn clone(&self) -> Self {
+    AsyncBatcher {
+      batcher: self.batcher.clone(),
+    }
+  }
+}
+
+/// Future returned by `AsyncBatcher::append`.
+pub struct Appended {
+  rx: oneshot::Receiver<Result<(), BatchError>>,
+}
+
+impl Future for Appended {
+  type Item = ();
+  type Error = BatchError;
+
+  fn poll(&mut self) -> Poll<(), BatchError> {
+    match self.rx.poll() {
+      Ok(Async::Ready(Ok(()))) => Ok(Async::Ready(())),
+      Ok(Async::Ready(Err(err))) => Err(err),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(_) => Err(BatchError::Closed),
+    }
+  }
+}
@@ -0,0 +1,152 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use {BatchId, Done};
+
+/// Key telling a batch apart from every other, e.g. for a backend which
+/// drops requests it saw before, see `Done::idempotency_key`.
+///
+/// It stays the same when a layer like `RetryLayer` runs the batch again.
+/// Unless a `FlushPolicy` supplies it, it is made up of a random number
+/// drawn once per process and a counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IdempotencyKey(u128);
+
+impl IdempotencyKey {
+  /// Wrap a key of your own.
+  pub fn new(key: u128) -> Self {
+    IdempotencyKey(key)
+  }
+
+  /// A key no other batch of this process has.
+  pub(crate) fn generate() -> Self {
+    static SEED: AtomicU64 = AtomicU64::new(0);
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let mut seed = SEED.load(Ordering::Relaxed);
+    if seed == 0 {
+      // Racing threads may draw different seeds; the first one stays.
+      let drawn = RandomState::new().build_hasher().finish() | 1;
+      seed = match SEED.compare_exchange(
+        0,
+        drawn,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+      ) {
+        Ok(_) => drawn,
+        Err(seed) => seed,
+      };
+    }
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    IdempotencyKey(u128::from(seed) << 64 | u128::from(count))
+  }
+
+  /// The key as a number.
+  pub fn get(self) -> u128 {
+    self.0
+  }
+}
+
+impl fmt::Display for IdempotencyKey {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:032x}", self.0)
+  }
+}
+
+/// What started a batch, see `Done::trigger`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Trigger {
+  /// The batcher was free to run it, either when its values were appended or
+  /// when the batch before it was done.
+  Ready,
+  /// It reached `max_batch_size` or `max_batch_bytes`.
+  Size,
+  /// It lingered for `max_batch_delay`, or its window ended.
+  Time,
+  /// A flush started it.
+  Flush,
+  /// The batcher was dropped with it waiting, see `DropPolicy::Flush`.
+  Drop,
+}
+
+/// What `Batcher::try_flush` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FlushOutcome {
+  /// A batch was handed to the run function.
+  Dispatched,
+  /// Values are waiting, but as many batches as may are running.
+  Running,
+  /// Values are waiting, held back by the rate limit or circuit breaker.
+  Throttled,
+  /// Values are waiting for the batcher to be resumed, see `Batcher::pause`.
+  Paused,
+  /// No values are waiting.
+  Empty,
+}
+
+/// What `Batcher::poll_complete` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct PollOutcome {
+  /// How many running batches failed with `BatchError::Timeout`.
+  pub timed_out: usize,
+  /// How many batches were handed to the run function.
+  pub dispatched: u64,
+  /// When to poll again at the latest, if anything waits for the time.
+  pub next_due: Option<Instant>,
+}
+
+/// Context of a batch, carried by its `Done`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Meta {
+  pub(crate) created_at: Instant,
+  pub(crate) appends: usize,
+  pub(crate) trigger: Trigger,
+  pub(crate) key: IdempotencyKey,
+}
+
+impl Meta {
+  pub(crate) fn new(trigger: Trigger, created_at: Instant) -> Self {
+    Meta {
+      created_at,
+      appends: 0,
+      trigger,
+      key: IdempotencyKey::generate(),
+    }
+  }
+}
+
+/// The values of a batch with its metadata, see `Batcher::with_batches`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Batch<T> {
+  /// The values, in append order.
+  pub items: Vec<T>,
+  /// The id of the batch.
+  pub id: BatchId,
+  /// When the first values of the batch were appended.
+  pub created_at: Instant,
+  /// How many appends the values came from.
+  pub appends: usize,
+  /// What started the batch.
+  pub trigger: Trigger,
+  /// See `Done::idempotency_key`.
+  pub idempotency_key: IdempotencyKey,
+}
+
+impl<T> Batch<T> {
+  /// Combine the values of a batch with the metadata of its `Done`.
+  pub fn new(items: Vec<T>, done: &Done) -> Self {
+    Batch {
+      items,
+      id: done.id(),
+      created_at: done.created_at(),
+      appends: done.appends(),
+      trigger: done.trigger(),
+      idempotency_key: done.idempotency_key(),
+    }
+  }
+}
@@ -0,0 +1,97 @@
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use {BatchError, Batcher, BatcherBuilder, Done};
+
+/// Function whose concurrent calls are coalesced into batched calls of a
+/// backend, like a DataLoader.
+///
+/// The backend gets the requests of a batch and answers each of them, in
+/// order. Requests made while it runs wait for it and go into the next
+/// batch together.
+pub struct BatchedFn<Req, Resp> {
+  batcher: Arc<Batcher<Req>>,
+  responses: PhantomData<fn() -> Resp>,
+}
+
+impl<Req, Resp> BatchedFn<Req, Resp>
+where
+  Req: Send + 'static,
+  Resp: Send + 'static,
+{
+  /// Create a batched function with the default configuration.
+  pub fn new<F>(load: F) -> Self
+  where
+    F: FnMut(Vec<Req>) -> Result<Vec<Resp>, BatchError> + Send + 'static,
+  {
+    BatchedFn::with_builder(BatcherBuilder::new(), load)
+  }
+
+  /// Create a batched function whose batcher is configured with `builder`,
+  /// e.g. to cap the requests per call of `load`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `BatcherBuilder::try_build`.
+  pub fn with_builder<F>(builder: BatcherBuilder<Req>, mut load: F) -> Self
+  where
+    F: FnMut(Vec<Req>) -> Result<Vec<Resp>, BatchError> + Send + 'static,
+  {
+    let batcher = builder.build(move |val, done: Done| match load(val) {
+      Ok(responses) => done.respond(responses),
+      Err(err) => done.err(err),
+    });
+    BatchedFn {
+      batcher,
+      responses: PhantomData,
+    }
+  }
+
+  /// Make a request. The returned future resolves with its response once
+  /// its batch is done, or fails with the error of the batch.
+  pub fn call(&self, req: Req) -> Call<Resp> {
+    let (tx, rx) = oneshot::channel();
+    self
+      .batcher
+      .append_map(vec![req], move |res: Result<Vec<Resp>, _>| {
+        let res = res.and_then(|mut responses| {
+          responses.pop().ok_or(BatchError::NoResult)
+        });
+        let _ = tx.send(res);
+      });
+    Call { rx }
+  }
+
+  /// The batcher the calls go through, e.g. for its metrics.
+  pub fn batcher(&self) -> &Arc<Batcher<Req>> {
+    &self.batcher
+  }
+}
+
+impl<Req, Resp> Clone for BatchedFn<Req, Resp> {
+  fn clone(&self) -> Self {
+    BatchedFn {
+      batcher: self.batcher.clone(),
+      responses: PhantomData,
+    }
+  }
+}
+
+/// Future returned by `BatchedFn::call`.
+pub struct Call<Resp> {
+  rx: oneshot::Receiver<Result<Resp, BatchError>>,
+}
+
+impl<Resp> Future for Call<Resp> {
+  type Item = Resp;
+  type Error = BatchError;
+
+  fn poll(&mut self) -> Poll<Resp, BatchError> {
+    match self.rx.poll() {
+      Ok(Async::Ready(Ok(resp))) => Ok(Async::Ready(resp)),
+      Ok(Async::Ready(Err(err))) => Err(err),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(_) => Err(BatchError::Closed),
+    }
+  }
+}
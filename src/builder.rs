@@ -0,0 +1,1073 @@
+use clock::SharedClock;
+#[cfg(feature = "async")]
+use futures::Future;
+#[cfg(feature = "log")]
+use log::Level;
+use metrics::SharedSink;
+use runner::Dispatcher;
+use std::fmt;
+use std::marker::PhantomData;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use timer::Timer;
+use {Batch, BatchContainer, BatchRunner, Batcher, BatcherHandle};
+use {BatchError, BatchId, CancellationToken, ScopedBatcher};
+#[cfg(feature = "async")]
+use {BatchStream, Runtime, TokioBatcher, TokioRuntime};
+use {Clock, Codec, CompressedSize, FlushPolicy, Journal, MetricsSink, Run};
+use {ConfigError, Done};
+
+/// What dropping a batcher does with values which did not run yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+  /// Run them, blocking until each batch is done. Like `Batcher::flush`,
+  /// this blocks forever on a batch which is never done.
+  Flush,
+  /// Fail their callbacks with `BatchError::Closed`.
+  #[default]
+  Discard,
+  /// Panic, after failing their callbacks with `BatchError::Closed`.
+  PanicIfPending,
+}
+
+/// Which error a callback gets whose values ran in several chunks, e.g. split
+/// by `max_items_per_dispatch` or over the shards of a `ShardedBatcher`, and
+/// more than one of them failed. Callbacks of `Batcher::append_each` get
+/// every value's own result regardless.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkErrors {
+  /// The error of the first chunk which failed, in value order.
+  #[default]
+  First,
+  /// The error of the last chunk which failed, in value order.
+  Last,
+  /// `BatchError::Chunks` with the error of every chunk which failed.
+  All,
+}
+
+impl ChunkErrors {
+  /// The result for the errors of the failed chunks, in value order.
+  pub(crate) fn pick(
+    self,
+    mut errors: Vec<BatchError>,
+  ) -> Result<(), BatchError> {
+    if errors.is_empty() {
+      return Ok(());
+    }
+    Err(match self {
+      ChunkErrors::First => errors.swap_remove(0),
+      ChunkErrors::Last => errors.pop().expect("errors are not empty"),
+      ChunkErrors::All => BatchError::Chunks(errors),
+    })
+  }
+}
+
+/// Tunes `max_batch_size` to how long batches take to run, see
+/// `BatcherBuilder::adaptive_batch_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptivePolicy {
+  min: usize,
+  max: usize,
+  target: Duration,
+}
+
+impl AdaptivePolicy {
+  /// Keep the batch size between `min` and `max`. Once a batch took longer
+  /// than `target` to run, the size doubles, so the cost of running is spread
+  /// over more values. Once one took less than half of it, the size halves,
+  /// so values wait less.
+  pub fn new(min: usize, max: usize, target: Duration) -> Self {
+    AdaptivePolicy { min, max, target }
+  }
+
+  /// The batch size to use after a batch of `size` took `elapsed` to run.
+  pub fn next_size(&self, size: usize, elapsed: Duration) -> usize {
+    let size = if elapsed > self.target {
+      size.saturating_mul(2)
+    } else if elapsed < self.target / 2 {
+      size / 2
+    } else {
+      size
+    };
+    size.clamp(self.min, self.max)
+  }
+}
+
+/// Stops running batches while they keep failing, see
+/// `BatcherBuilder::circuit_breaker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreaker {
+  pub(crate) failures: usize,
+  pub(crate) cooldown: Duration,
+  pub(crate) fail_fast: bool,
+}
+
+impl CircuitBreaker {
+  /// Open the circuit once `failures` batches in a row failed, and keep it
+  /// open for `cooldown`. Values wait meanwhile. The first batch to run
+  /// afterwards is a probe: if it fails too, the circuit opens again, if it
+  /// succeeds, it closes.
+  pub fn new(failures: usize, cooldown: Duration) -> Self {
+    CircuitBreaker {
+      failures,
+      cooldown,
+      fail_fast: false,
+    }
+  }
+
+  /// Fail appends with `BatchError::CircuitOpen` while the circuit is open,
+  /// rather than have the values wait.
+  pub fn fail_fast(mut self) -> Self {
+    self.fail_fast = true;
+    self
+  }
+}
+
+/// Settings shared by every batch of a batcher.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Config {
+  pub(crate) name: Option<String>,
+  pub(crate) max_batch_size: Option<usize>,
+  pub(crate) adaptive: Option<AdaptivePolicy>,
+  pub(crate) max_batch_delay: Option<Duration>,
+  pub(crate) max_item_latency: Option<Duration>,
+  pub(crate) min_batch: Option<(usize, Duration)>,
+  pub(crate) window: Option<Duration>,
+  pub(crate) flush_policy: bool,
+  pub(crate) batch_timeout: Option<Duration>,
+  #[cfg(feature = "async")]
+  pub(crate) abort_timed_out: bool,
+  pub(crate) max_batches_per_second: Option<u32>,
+  pub(crate) breaker: Option<CircuitBreaker>,
+  pub(crate) max_items_per_dispatch: Option<usize>,
+  pub(crate) chunk_errors: ChunkErrors,
+  pub(crate) pipeline: Option<usize>,
+  pub(crate) max_sync_dispatches: Option<usize>,
+  pub(crate) without_timer: bool,
+  pub(crate) unordered: bool,
+  pub(crate) max_batch_bytes: Option<usize>,
+  pub(crate) capacity: Option<usize>,
+  pub(crate) callback_capacity: usize,
+  pub(crate) drop_policy: DropPolicy,
+  pub(crate) metrics_sink: Option<SharedSink>,
+  pub(crate) cancellation: Option<CancellationToken>,
+  pub(crate) clock: SharedClock,
+  /// Whether `clock` was set, rather than the system clock.
+  pub(crate) custom_clock: bool,
+  /// Shared with other batchers, rather than one of their own.
+  pub(crate) timer: Option<Timer>,
+  #[cfg(feature = "log")]
+  pub(crate) log_levels: Option<(Level, Level)>,
+}
+
+impl Config {
+  pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+    if self.max_batch_size == Some(0) {
+      return Err(ConfigError::ZeroBatchSize);
+    }
+    if let Some(policy) = self.adaptive {
+      if policy.min == 0 || policy.min > policy.max {
+        return Err(ConfigError::InvalidAdaptiveRange);
+      }
+    }
+    if self.max_batch_delay == Some(Duration::from_secs(0)) {
+      return Err(ConfigError::ZeroBatchDelay);
+    }
+    if self.max_item_latency == Some(Duration::from_secs(0)) {
+      return Err(ConfigError::ZeroItemLatency);
+    }
+    if let Some((min, wait)) = self.min_batch {
+      if min == 0 || wait == Duration::from_secs(0) {
+        return Err(ConfigError::ZeroMinBatch);
+      }
+    }
+    if self.window == Some(Duration::from_secs(0)) {
+      return Err(ConfigError::ZeroWindow);
+    }
+    if self.batch_timeout == Some(Duration::from_secs(0)) {
+      return Err(ConfigError::ZeroBatchTimeout);
+    }
+    if self.max_batch_bytes == Some(0) {
+      return Err(ConfigError::ZeroBatchBytes);
+    }
+    if self.max_items_per_dispatch == Some(0) {
+      return Err(ConfigError::ZeroItemsPerDispatch);
+    }
+    if self.pipeline == Some(0) {
+      return Err(ConfigError::ZeroPipeline);
+    }
+    if self.max_sync_dispatches == Some(0) {
+      return Err(ConfigError::ZeroSyncDispatches);
+    }
+    if self.max_batches_per_second == Some(0) {
+      return Err(ConfigError::ZeroRate);
+    }
+    if self.breaker.is_some_and(|breaker| breaker.failures == 0) {
+      return Err(ConfigError::ZeroBreakerFailures);
+    }
+    if let Some(capacity) = self.capacity {
+      if capacity == 0 {
+        return Err(ConfigError::ZeroCapacity);
+      }
+      if capacity < self.batch_size_limit().unwrap_or(0) {
+        return Err(ConfigError::CapacityBelowBatchSize);
+      }
+    }
+    Ok(())
+  }
+
+  /// The first setting made which a scoped batcher cannot apply, see
+  /// `BatcherBuilder::scope`.
+  fn not_scoped(&self) -> Option<&'static str> {
+    self.first_made(&[])
+  }
+
+  /// The first setting made which a `TokioBatcher` cannot apply, see
+  /// `BatcherBuilder::build_on`.
+  #[cfg(feature = "async")]
+  fn not_tokio(&self) -> Option<&'static str> {
+    self.first_made(&[
+      "max_batch_delay",
+      "batch_timeout",
+      "capacity",
+      "abort_timed_out",
+    ])
+  }
+
+  /// The first setting made, other than `name`, `max_batch_size` and those
+  /// in `applied`.
+  fn first_made(&self, applied: &[&str]) -> Option<&'static str> {
+    let settings = [
+      ("adaptive_batch_size", self.adaptive.is_some()),
+      ("max_batch_delay", self.max_batch_delay.is_some()),
+      ("max_item_latency", self.max_item_latency.is_some()),
+      ("min_batch_size", self.min_batch.is_some()),
+      ("window", self.window.is_some()),
+      ("flush_policy", self.flush_policy),
+      ("batch_timeout", self.batch_timeout.is_some()),
+      (
+        "max_batches_per_second",
+        self.max_batches_per_second.is_some(),
+      ),
+      ("circuit_breaker", self.breaker.is_some()),
+      (
+        "max_items_per_dispatch",
+        self.max_items_per_dispatch.is_some(),
+      ),
+      ("chunk_errors", self.chunk_errors != ChunkErrors::default()),
+      ("pipeline", self.pipeline.is_some()),
+      ("max_sync_dispatches", self.max_sync_dispatches.is_some()),
+      ("without_timer", self.without_timer),
+      ("unordered", self.unordered),
+      ("max_batch_bytes", self.max_batch_bytes.is_some()),
+      ("capacity", self.capacity.is_some()),
+      ("callback_capacity", self.callback_capacity != 0),
+      ("drop_policy", self.drop_policy != DropPolicy::default()),
+      ("metrics_sink", self.metrics_sink.is_some()),
+      ("cancellation", self.cancellation.is_some()),
+      ("clock", self.custom_clock),
+    ];
+    #[cfg(feature = "async")]
+    let settings = {
+      let mut settings = settings.to_vec();
+      settings.push(("abort_timed_out", self.abort_timed_out));
+      settings
+    };
+    #[cfg(feature = "log")]
+    let settings = {
+      let mut settings = settings.to_vec();
+      settings.push(("log_levels", self.log_levels.is_some()));
+      settings
+    };
+    let mut made = settings
+      .iter()
+      .filter(|(setting, made)| *made && !applied.contains(setting));
+    made.next().map(|(setting, _)| *setting)
+  }
+
+  /// The least time between starting two batches.
+  pub(crate) fn dispatch_interval(&self) -> Option<Duration> {
+    let rate = self.max_batches_per_second?;
+    Some(Duration::from_secs(1) / rate)
+  }
+
+  /// The batch size to start with.
+  pub(crate) fn initial_batch_size(&self) -> Option<usize> {
+    match self.adaptive {
+      Some(policy) => {
+        let size = self.max_batch_size.unwrap_or(policy.min);
+        Some(size.clamp(policy.min, policy.max))
+      }
+      None => self.max_batch_size,
+    }
+  }
+
+  /// Whether values may be held back so more join their batch.
+  pub(crate) fn lingers(&self) -> bool {
+    self.max_batch_delay.is_some()
+      || self.max_item_latency.is_some()
+      || self.min_batch.is_some()
+      || self.window.is_some()
+      || self.flush_policy
+  }
+
+  /// When the window of a value appended now ends, see
+  /// `BatcherBuilder::window`.
+  pub(crate) fn window_end(&self) -> Option<Instant> {
+    let period = self.window?.as_nanos();
+    let now = self.clock.now();
+    let wall = self
+      .clock
+      .system_time()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_nanos();
+    let left = (period - wall % period) as u64;
+    Some(now + Duration::from_nanos(left))
+  }
+
+  /// The levels at which events and failures are logged.
+  #[cfg(feature = "log")]
+  pub(crate) fn log_levels(&self) -> (Level, Level) {
+    self.log_levels.unwrap_or((Level::Debug, Level::Warn))
+  }
+
+  /// The largest batch size there may be.
+  fn batch_size_limit(&self) -> Option<usize> {
+    match self.adaptive {
+      Some(policy) => Some(policy.max),
+      None => self.max_batch_size,
+    }
+  }
+}
+
+type Coalesce<T> = Arc<dyn Fn(&mut Vec<T>) + Send + Sync>;
+type Weigher<T> = Arc<dyn Fn(&T) -> usize + Send + Sync>;
+type BeforeDispatch<T> = Arc<dyn Fn(BatchId, &[T]) + Send + Sync>;
+type AfterComplete =
+  Arc<dyn Fn(BatchId, &Result<(), BatchError>) + Send + Sync>;
+pub(crate) type Policy<T> = Box<dyn FlushPolicy<T> + Send>;
+type NewPolicy<T> = Arc<dyn Fn() -> Policy<T> + Send + Sync>;
+
+/// Functions of a batcher working on its values.
+pub(crate) struct Hooks<T> {
+  pub(crate) coalesce: Option<Coalesce<T>>,
+  pub(crate) weigher: Option<Weigher<T>>,
+  pub(crate) journal: Option<Arc<Journal<T>>>,
+  pub(crate) policy: Option<NewPolicy<T>>,
+  pub(crate) before_dispatch: Option<BeforeDispatch<T>>,
+  pub(crate) after_complete: Option<AfterComplete>,
+}
+
+impl<T> Default for Hooks<T> {
+  fn default() -> Self {
+    Hooks {
+      coalesce: None,
+      weigher: None,
+      journal: None,
+      policy: None,
+      before_dispatch: None,
+      after_complete: None,
+    }
+  }
+}
+
+impl<T> Hooks<T> {
+  /// The first hook set, which neither a scoped batcher nor a
+  /// `TokioBatcher` can apply.
+  fn first_set(&self) -> Option<&'static str> {
+    let hooks = [
+      ("coalesce", self.coalesce.is_some()),
+      ("max_batch_bytes", self.weigher.is_some()),
+      ("journal", self.journal.is_some()),
+      ("flush_policy", self.policy.is_some()),
+      ("before_dispatch", self.before_dispatch.is_some()),
+      ("after_complete", self.after_complete.is_some()),
+    ];
+    let mut set = hooks.iter().filter(|(_, set)| *set);
+    set.next().map(|(hook, _)| *hook)
+  }
+}
+
+impl<T> Clone for Hooks<T> {
+  fn clone(&self) -> Self {
+    Hooks {
+      coalesce: self.coalesce.clone(),
+      weigher: self.weigher.clone(),
+      journal: self.journal.clone(),
+      policy: self.policy.clone(),
+      before_dispatch: self.before_dispatch.clone(),
+      after_complete: self.after_complete.clone(),
+    }
+  }
+}
+
+// Like `MetricsSink`, the hooks hold no state of the batcher, so a panic
+// inside them cannot leave it broken.
+impl<T> UnwindSafe for Hooks<T> {}
+impl<T> RefUnwindSafe for Hooks<T> {}
+
+impl<T> fmt::Debug for Hooks<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Hooks")
+      .field("coalesce", &self.coalesce.is_some())
+      .field("weigher", &self.weigher.is_some())
+      .field("journal", &self.journal)
+      .field("policy", &self.policy.is_some())
+      .field("before_dispatch", &self.before_dispatch.is_some())
+      .field("after_complete", &self.after_complete.is_some())
+      .finish()
+  }
+}
+
+/// Builder for a configured `Batcher`, see `Batcher::builder`.
+#[derive(Debug)]
+pub struct BatcherBuilder<T> {
+  config: Config,
+  hooks: Hooks<T>,
+  items: PhantomData<fn(T)>,
+}
+
+impl<T> BatcherBuilder<T> {
+  /// Create a builder with the default configuration.
+  pub fn new() -> Self {
+    BatcherBuilder {
+      config: Config::default(),
+      hooks: Hooks::default(),
+      items: PhantomData,
+    }
+  }
+
+  /// Name the batcher, e.g. for telling apart its background threads.
+  pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+    self.config.name = Some(name.into());
+    self
+  }
+
+  /// Close the pending batch once it holds `size` values, instead of letting
+  /// it grow while a batch is running. Closed batches run one after another.
+  pub fn max_batch_size(mut self, size: usize) -> Self {
+    self.config.max_batch_size = Some(size);
+    self
+  }
+
+  /// Adjust the batch size after every batch according to `policy`. A
+  /// `max_batch_size` given as well is the size to start with.
+  pub fn adaptive_batch_size(mut self, policy: AdaptivePolicy) -> Self {
+    self.config.adaptive = Some(policy);
+    self
+  }
+
+  /// Hand the run function at most `max` values at once. Larger batches,
+  /// e.g. from a single large append, run in parts one after another, each
+  /// with its own `Done`. A callback whose values are split up is called once
+  /// every part is done.
+  pub fn max_items_per_dispatch(mut self, max: usize) -> Self {
+    self.config.max_items_per_dispatch = Some(max);
+    self
+  }
+
+  /// Choose which error a callback whose values were split up gets, see
+  /// `ChunkErrors`. By default it is the error of the first part which
+  /// failed.
+  pub fn chunk_errors(mut self, policy: ChunkErrors) -> Self {
+    self.config.chunk_errors = policy;
+    self
+  }
+
+  /// Run at most `max` batches in a row on the thread which started the
+  /// first, e.g. an appending one, when the run function signals them done
+  /// before returning. Such batches run in a loop rather than recursively,
+  /// so the stack does not grow either way, but without a limit an append
+  /// may keep running batches for as long as other threads append. Past the
+  /// limit the timer thread runs the rest.
+  pub fn max_sync_dispatches(mut self, max: usize) -> Self {
+    self.config.max_sync_dispatches = Some(max);
+    self
+  }
+
+  /// Spawn no timer thread for settings kept by the timer, such as
+  /// `max_batch_delay` or `batch_timeout`, e.g. to drive the batcher from an
+  /// event loop. Call `Batcher::poll_complete` instead, at the latest by the
+  /// time it returns.
+  pub fn without_timer(mut self) -> Self {
+    self.config.without_timer = true;
+    self
+  }
+
+  /// Keep up to `depth` batches running at once: the next batch starts once
+  /// the run function returns rather than once the batch is done. Batches
+  /// are still handed over one at a time and in order, and their callbacks
+  /// are called in batch order, a batch done early waiting for those before
+  /// it.
+  pub fn pipeline(mut self, depth: usize) -> Self {
+    self.config.pipeline = Some(depth);
+    self.config.unordered = false;
+    self
+  }
+
+  /// Keep up to `max` batches running at once, like `pipeline`, but call
+  /// the callbacks of each batch as soon as it is done, whatever the batches
+  /// before it. Suits work whose batches may finish in any order.
+  pub fn max_concurrent_batches(mut self, max: usize) -> Self {
+    self.config.pipeline = Some(max);
+    self.config.unordered = true;
+    self
+  }
+
+  /// Close the pending batch once the values in it weigh `max` in total, as
+  /// told by `weigher`, e.g. their serialized size in bytes. An append which
+  /// would take the batch beyond `max` starts the next batch instead, so a
+  /// batch only weighs more when a single append does.
+  pub fn max_batch_bytes<F>(mut self, max: usize, weigher: F) -> Self
+  where
+    F: Fn(&T) -> usize + Send + Sync + 'static,
+  {
+    self.config.max_batch_bytes = Some(max);
+    self.hooks.weigher = Some(Arc::new(weigher));
+    self
+  }
+
+  /// Hold values appended while nothing is running for up to `delay`, so
+  /// they run as one batch. The batch runs earlier once it reaches
+  /// `max_batch_size`, or on `flush`.
+  pub fn max_batch_delay(mut self, delay: Duration) -> Self {
+    self.config.max_batch_delay = Some(delay);
+    self
+  }
+
+  /// Let no value wait longer than `latency` for its batch to start, while
+  /// otherwise holding values back so batches fill up: the pending batch
+  /// lingers, also once the batch before it is done, until its oldest value
+  /// waited for `latency`. It runs earlier once full, or on `flush`. A value
+  /// may still wait longer while as many batches as may are running.
+  pub fn max_item_latency(mut self, latency: Duration) -> Self {
+    self.config.max_item_latency = Some(latency);
+    self
+  }
+
+  /// Hold the pending batch back until it has `min` values, or its oldest
+  /// value waited for `max_wait`, also once the batch before it is done.
+  /// Without it, values appended while a batch runs start as soon as it is
+  /// done, which with a fast run function makes for many tiny batches. The
+  /// batch runs earlier on `flush`.
+  pub fn min_batch_size(mut self, min: usize, max_wait: Duration) -> Self {
+    self.config.min_batch = Some((min, max_wait));
+    self
+  }
+
+  /// Batch values by fixed wall-clock windows of `period`, e.g. every 10
+  /// seconds from the full minute: the values appended within a window form
+  /// its batch, which closes when the window ends, no matter when the batch
+  /// before it is done. A window with no values has no batch. The batch also
+  /// closes once full, or on `flush`.
+  pub fn window(mut self, period: Duration) -> Self {
+    self.config.window = Some(period);
+    self
+  }
+
+  /// Decide when the pending batch closes with a `FlushPolicy` of your own.
+  /// Like with `max_batch_delay`, values appended while the batcher is free
+  /// are held back, until the policy flushes or its deadline passes.
+  pub fn flush_policy<P>(mut self, policy: P) -> Self
+  where
+    P: FlushPolicy<T> + Clone + Send + Sync + 'static,
+  {
+    self.config.flush_policy = true;
+    self.hooks.policy = Some(Arc::new(move || Box::new(policy.clone())));
+    self
+  }
+
+  /// Fail batches which are not done within `timeout` of being run with
+  /// `BatchError::Timeout`, and go on with the next batch. Signalling a
+  /// timed out batch has no effect. Reporting progress with `Done::progress`
+  /// restarts the timeout.
+  ///
+  /// The timeout is kept by a background thread, which also runs the next
+  /// batch; a run function blocking that thread holds up the timeout.
+  pub fn batch_timeout(mut self, timeout: Duration) -> Self {
+    self.config.batch_timeout = Some(timeout);
+    self
+  }
+
+  /// Drop the future of a batch of a `TokioBatcher` which timed out, see
+  /// `batch_timeout`, cancelling it. By default it is left running in the
+  /// background, with its result ignored.
+  #[cfg(feature = "async")]
+  pub fn abort_timed_out(mut self) -> Self {
+    self.config.abort_timed_out = true;
+    self
+  }
+
+  /// Start at most `rate` batches per second, spaced out evenly, however
+  /// quickly batches are done. Values wait in the pending batch meanwhile.
+  pub fn max_batches_per_second(mut self, rate: u32) -> Self {
+    self.config.max_batches_per_second = Some(rate);
+    self
+  }
+
+  /// Stop running batches for a while once they keep failing, see
+  /// `CircuitBreaker`.
+  pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+    self.config.breaker = Some(breaker);
+    self
+  }
+
+  /// Accept at most `capacity` values waiting for a batch to run. Appends
+  /// which do not fit fail with `BatchError::CapacityExceeded`. A
+  /// `TokioBatcher` counts appends rather than values, and has appends
+  /// which do not fit wait for room.
+  pub fn capacity(mut self, capacity: usize) -> Self {
+    self.config.capacity = Some(capacity);
+    self
+  }
+
+  /// Choose what dropping the batcher does with values which did not run
+  /// yet. Defaults to `DropPolicy::Discard`.
+  pub fn drop_policy(mut self, policy: DropPolicy) -> Self {
+    self.config.drop_policy = policy;
+    self
+  }
+
+  /// Shut the batcher down once `token` is cancelled: it closes like with
+  /// `Batcher::close`, and every value waiting to run fails with
+  /// `BatchError::Cancelled`, so no batch starts anymore. Running batches
+  /// go on.
+  pub fn cancellation(mut self, token: CancellationToken) -> Self {
+    self.config.cancellation = Some(token);
+    self
+  }
+
+  /// Log batches starting and finishing, flushes and values left when the
+  /// batcher is dropped at level `events`, and failed or timed out batches
+  /// at level `failures`, with the target `atomic_batcher`. Defaults to
+  /// `Debug` and `Warn`.
+  #[cfg(feature = "log")]
+  pub fn log_levels(mut self, events: Level, failures: Level) -> Self {
+    self.config.log_levels = Some((events, failures));
+    self
+  }
+
+  /// Report every dispatched and completed batch to `sink`.
+  pub fn metrics_sink<S: MetricsSink + 'static>(mut self, sink: S) -> Self {
+    self.config.metrics_sink = Some(SharedSink(Arc::new(sink)));
+    self
+  }
+
+  /// Take the time from `clock` rather than the system, e.g. a
+  /// `testing::MockClock` to test lingering, timeouts and TTLs without
+  /// waiting for them. The timer thread sleeps through `Clock::park_until`.
+  pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+    self.config.clock = SharedClock(Arc::new(clock));
+    self.config.custom_clock = true;
+    self
+  }
+
+  /// Rework each batch with `coalesce` right before it runs, e.g. to drop
+  /// repeated keys or merge updates. As values may move, callbacks get the
+  /// first error of the batch rather than the results of their own values.
+  pub fn coalesce<F>(mut self, coalesce: F) -> Self
+  where
+    F: Fn(&mut Vec<T>) + Send + Sync + 'static,
+  {
+    self.hooks.coalesce = Some(Arc::new(coalesce));
+    self
+  }
+
+  /// Call `hook` with each batch right before it is handed to the run
+  /// function, after `coalesce`, e.g. to take a lease or log it.
+  pub fn before_dispatch<F>(mut self, hook: F) -> Self
+  where
+    F: Fn(BatchId, &[T]) + Send + Sync + 'static,
+  {
+    self.hooks.before_dispatch = Some(Arc::new(hook));
+    self
+  }
+
+  /// Call `hook` with the first error of each batch, if any, once it is done
+  /// and its callbacks were called, e.g. to release a lease taken in
+  /// `before_dispatch`. Batches run by the drop of the batcher, see
+  /// `DropPolicy::Flush`, skip both hooks.
+  pub fn after_complete<F>(mut self, hook: F) -> Self
+  where
+    F: Fn(BatchId, &Result<(), BatchError>) + Send + Sync + 'static,
+  {
+    self.hooks.after_complete = Some(Arc::new(hook));
+    self
+  }
+
+  /// Write appended values to `journal` before queueing them, and append the
+  /// values left in it from an earlier run once the batcher is built. See
+  /// `Journal` for what this guarantees.
+  pub fn journal(mut self, journal: Journal<T>) -> Self {
+    self.hooks.journal = Some(Arc::new(journal));
+    self
+  }
+
+  /// Reserve room for `capacity` callbacks per batch up front.
+  pub fn callback_capacity(mut self, capacity: usize) -> Self {
+    self.config.callback_capacity = capacity;
+    self
+  }
+}
+
+impl<T: Send + 'static> BatcherBuilder<T> {
+  pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+    self.config.validate()
+  }
+
+  pub(crate) fn chunk_errors_policy(&self) -> ChunkErrors {
+    self.config.chunk_errors
+  }
+
+  /// Have every batcher built from clones of this builder share one timer
+  /// thread.
+  pub(crate) fn shared_timer(mut self) -> Self {
+    let config = &self.config;
+    let timer = Timer::named(config.name.as_deref(), config.clock.clone());
+    self.config.timer = Some(timer);
+    self
+  }
+
+  /// Create the batcher with a run function.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build<F>(self, run: F) -> Arc<Batcher<T>>
+  where
+    F: FnMut(Vec<T>, Done) + Send + 'static,
+  {
+    self.build_runner(run)
+  }
+
+  /// Create the batcher with a `BatchRunner`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build_runner<R>(self, runner: R) -> Arc<Batcher<T>>
+  where
+    R: BatchRunner<T> + Send + 'static,
+  {
+    match self.try_build_runner(runner) {
+      Ok(batcher) => batcher,
+      Err(err) => panic!("invalid batcher configuration: {}", err),
+    }
+  }
+
+  /// Create the batcher with a run function which is done with each batch
+  /// once it returns, see `Batcher::for_each_batch`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build_for_each<F>(self, mut run: F) -> Arc<Batcher<T>>
+  where
+    F: FnMut(Vec<T>) -> Result<(), BatchError> + Send + 'static,
+  {
+    self.build(move |items, done: Done| done.finish(run(items)))
+  }
+
+  /// Create the batcher with a run function getting each batch with its
+  /// metadata, see `Batcher::with_batches`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build_batches<F>(self, mut run: F) -> Arc<Batcher<T>>
+  where
+    F: FnMut(Batch<T>, Done) + Send + 'static,
+  {
+    self.build(move |items, done: Done| run(Batch::new(items, &done), done))
+  }
+
+  /// Create the batcher with a run function getting each batch as turned
+  /// into `U` by `transform`, e.g. serialized into a request body. The
+  /// transform runs once per batch, right before the run function and on
+  /// the same thread; should it panic, the batch fails with
+  /// `BatchError::RunnerPanicked`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build_transformed<X, U, F>(
+    self,
+    mut transform: X,
+    mut run: F,
+  ) -> Arc<Batcher<T>>
+  where
+    X: FnMut(Vec<T>) -> U + Send + 'static,
+    F: FnMut(U, Done) + Send + 'static,
+  {
+    self.build(move |items, done| run(transform(items), done))
+  }
+
+  /// Create the batcher as the first stage of a pipeline: each batch is
+  /// turned into the values of `next` by `transform` and appended to it, and
+  /// is done once the downstream batches holding those values are, with
+  /// their first error.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build_chained<U, X>(
+    self,
+    next: Arc<Batcher<U>>,
+    mut transform: X,
+  ) -> Arc<Batcher<T>>
+  where
+    U: Send + 'static,
+    X: FnMut(Vec<T>) -> Vec<U> + Send + 'static,
+  {
+    self.build(move |items, done: Done| {
+      let values = transform(items);
+      next.append(values, Some(Box::new(move |res| done.finish(res))))
+    })
+  }
+
+  /// Create the batcher with a run function getting each batch in a
+  /// `BatchContainer` of its choice, e.g. a `VecDeque`. The values wait in
+  /// containers of that type from the start, so nothing is copied on the
+  /// way to the run function. Hooks taking a `Vec`, such as `coalesce`, get
+  /// the values moved into one and back.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build_into<C, F>(self, run: F) -> Arc<Batcher<T, C>>
+  where
+    C: BatchContainer<T> + Send + 'static,
+    F: FnMut(C, Done) + Send + 'static,
+  {
+    if let Err(err) = self.config.validate() {
+      panic!("invalid batcher configuration: {}", err);
+    }
+    let run = Run::Owned(Box::new(run));
+    Batcher::with_config(run, self.config, self.hooks)
+  }
+
+  /// Create the batcher with a run function getting each batch encoded and
+  /// compressed, e.g. for uploading telemetry. Batches close once they take
+  /// `threshold` compressed bytes, see `CompressedSize`, and run earlier on
+  /// `flush` or the other limits set.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build_compressed<E, C, F>(
+    self,
+    threshold: usize,
+    encode: E,
+    codec: C,
+    run: F,
+  ) -> Arc<Batcher<T>>
+  where
+    T: 'static,
+    E: Fn(&T, &mut Vec<u8>) + Send + Sync + 'static,
+    C: Codec + Send + Sync + 'static,
+    F: FnMut(Vec<u8>, Done) + Send + 'static,
+  {
+    let policy = CompressedSize::new(threshold, encode, codec);
+    let compress = policy.clone();
+    self
+      .flush_policy(policy)
+      .build_transformed(move |items: Vec<T>| compress.compress(&items), run)
+  }
+
+  /// Create the batcher with a run function called on a thread of its own,
+  /// see `Batcher::spawn`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn spawn<R>(self, runner: R) -> BatcherHandle<T>
+  where
+    R: BatchRunner<T> + Send + 'static,
+  {
+    let dispatcher = Dispatcher::spawn(self.config.name.as_deref(), runner);
+    BatcherHandle::from(self.build_runner(dispatcher))
+  }
+
+  /// Create the batcher together with the stream of its batches, see
+  /// `Batcher::batch_stream`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  #[cfg(feature = "async")]
+  pub fn build_stream(self) -> (Arc<Batcher<T>>, BatchStream<T>) {
+    let (run, stream) = BatchStream::channel();
+    (self.build(run), stream)
+  }
+
+  /// Create a batcher gathering values on a tokio task, with a run function
+  /// returning a future, see `TokioBatcher`. Must be called from within a
+  /// tokio runtime.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid or does not apply, see
+  /// `try_build_on`.
+  #[cfg(feature = "async")]
+  pub fn build_tokio<F, R>(self, run: F) -> TokioBatcher<T>
+  where
+    F: FnMut(Vec<T>) -> R + Send + 'static,
+    R: Future<Item = (), Error = BatchError> + Send + 'static,
+  {
+    self.build_on(TokioRuntime, run)
+  }
+
+  /// Create a batcher gathering values on a task spawned with `runtime`,
+  /// like `build_tokio`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid or does not apply, see
+  /// `try_build_on`.
+  #[cfg(feature = "async")]
+  pub fn build_on<S, F, R>(self, runtime: S, run: F) -> TokioBatcher<T>
+  where
+    S: Runtime,
+    F: FnMut(Vec<T>) -> R + Send + 'static,
+    R: Future<Item = (), Error = BatchError> + Send + 'static,
+  {
+    match self.try_build_on(runtime, run) {
+      Ok(batcher) => batcher,
+      Err(err) => panic!("invalid batcher configuration: {}", err),
+    }
+  }
+
+  /// Create a batcher gathering values on a task spawned with `runtime`,
+  /// see `build_on`, or report why the configuration is invalid or does not
+  /// apply. Of the configuration only `name`, `max_batch_size`,
+  /// `max_batch_delay`, `capacity`, `batch_timeout` and `abort_timed_out`
+  /// apply; any other setting, or a hook such as `coalesce`, is rejected
+  /// with `ConfigError::NotTokio`.
+  #[cfg(feature = "async")]
+  pub fn try_build_on<S, F, R>(
+    self,
+    runtime: S,
+    run: F,
+  ) -> Result<TokioBatcher<T>, ConfigError>
+  where
+    S: Runtime,
+    F: FnMut(Vec<T>) -> R + Send + 'static,
+    R: Future<Item = (), Error = BatchError> + Send + 'static,
+  {
+    self.config.validate()?;
+    if let Some(setting) = self.config.not_tokio().or(self.hooks.first_set()) {
+      return Err(ConfigError::NotTokio(setting));
+    }
+    Ok(TokioBatcher::spawn(Arc::new(runtime), &self.config, run))
+  }
+
+  /// Create the batcher with a run function, or report why the
+  /// configuration is invalid.
+  pub fn try_build<F>(self, run: F) -> Result<Arc<Batcher<T>>, ConfigError>
+  where
+    F: FnMut(Vec<T>, Done) + Send + 'static,
+  {
+    self.try_build_runner(run)
+  }
+
+  /// Create the batcher with a `BatchRunner`, or report why the
+  /// configuration is invalid.
+  pub fn try_build_runner<R>(
+    self,
+    runner: R,
+  ) -> Result<Arc<Batcher<T>>, ConfigError>
+  where
+    R: BatchRunner<T> + Send + 'static,
+  {
+    self.config.validate()?;
+    let mut runner = runner;
+    let run = Run::Owned(Box::new(move |batch, done| runner.run(batch, done)));
+    Ok(Batcher::with_config(run, self.config, self.hooks))
+  }
+
+  /// Create the batcher with a run function borrowing each batch, see
+  /// `Batcher::pooled`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build_pooled<F>(self, run: F) -> Arc<Batcher<T>>
+  where
+    F: FnMut(&mut Vec<T>, Done) + Send + 'static,
+  {
+    self.build_pooled_into(run)
+  }
+
+  /// Create the batcher with a run function borrowing each batch in a
+  /// `BatchContainer` of its choice, like `build_pooled` and `build_into`.
+  /// Cleared containers are filled again, e.g. the segments of a
+  /// `SegmentedQueue`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `try_build`.
+  pub fn build_pooled_into<C, F>(self, run: F) -> Arc<Batcher<T, C>>
+  where
+    C: BatchContainer<T> + Send + 'static,
+    F: FnMut(&mut C, Done) + Send + 'static,
+  {
+    if let Err(err) = self.config.validate() {
+      panic!("invalid batcher configuration: {}", err);
+    }
+    let run = Run::Borrowed(Box::new(run));
+    Batcher::with_config(run, self.config, self.hooks)
+  }
+}
+
+impl<T: Send> BatcherBuilder<T> {
+  /// Batch values borrowed from the enclosing scope, like
+  /// `std::thread::scope`: `body` gets a `ScopedBatcher`, which it may share
+  /// with scoped threads, and every value appended to it has run once
+  /// `scope` returns. Batches run right away on an appending thread. Of the
+  /// configuration only `name` and `max_batch_size` apply; any other
+  /// setting, or a hook such as `coalesce`, is rejected with
+  /// `ConfigError::NotScoped`. Returns what `body` returns, or the first
+  /// error of a batch. Start from `BatcherBuilder::new`, as
+  /// `Batcher::builder` needs values which are `'static`.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid or does not apply, see
+  /// `try_scope`.
+  pub fn scope<'a, F, S, R>(self, run: F, body: S) -> Result<R, BatchError>
+  where
+    T: 'a,
+    F: FnMut(Vec<T>) -> Result<(), BatchError> + Send + 'a,
+    S: FnOnce(&ScopedBatcher<'a, T>) -> R,
+  {
+    match self.try_scope(run, body) {
+      Ok(res) => res,
+      Err(err) => panic!("invalid batcher configuration: {}", err),
+    }
+  }
+
+  /// Batch values borrowed from the enclosing scope, see `scope`, or report
+  /// why the configuration is invalid or does not apply, before `body`
+  /// runs.
+  pub fn try_scope<'a, F, S, R>(
+    self,
+    run: F,
+    body: S,
+  ) -> Result<Result<R, BatchError>, ConfigError>
+  where
+    T: 'a,
+    F: FnMut(Vec<T>) -> Result<(), BatchError> + Send + 'a,
+    S: FnOnce(&ScopedBatcher<'a, T>) -> R,
+  {
+    self.config.validate()?;
+    if let Some(setting) = self.config.not_scoped().or(self.hooks.first_set()) {
+      return Err(ConfigError::NotScoped(setting));
+    }
+    let batcher = ScopedBatcher::new(Box::new(run), self.config.max_batch_size);
+    let res = body(&batcher);
+    Ok(batcher.finish().map(|()| res))
+  }
+}
+
+impl<T> Default for BatcherBuilder<T> {
+  fn default() -> Self {
+    BatcherBuilder::new()
+  }
+}
+
+impl<T> Clone for BatcherBuilder<T> {
+  fn clone(&self) -> Self {
+    BatcherBuilder {
+      config: self.config.clone(),
+      hooks: self.hooks.clone(),
+      items: PhantomData,
+    }
+  }
+}
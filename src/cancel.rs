@@ -0,0 +1,140 @@
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Implemented by batchers so `CancelHandle` does not carry their item type.
+pub(crate) trait Cancel: Send + Sync {
+  fn cancel(&self, token: u64) -> bool;
+}
+
+/// Handle for taking back an append before its values run, see
+/// `Batcher::append_cancellable`.
+pub struct CancelHandle {
+  batcher: Weak<dyn Cancel>,
+  token: u64,
+}
+
+impl CancelHandle {
+  pub(crate) fn new(batcher: Weak<dyn Cancel>, token: u64) -> Self {
+    CancelHandle { batcher, token }
+  }
+
+  /// Remove the values from the batcher and fail their callback with
+  /// `BatchError::Cancelled`. Returns whether they were still waiting; once
+  /// their batch has started, or they were split across batches, they run
+  /// as usual.
+  pub fn cancel(self) -> bool {
+    match self.batcher.upgrade() {
+      Some(batcher) => batcher.cancel(self.token),
+      None => false,
+    }
+  }
+}
+
+impl fmt::Debug for CancelHandle {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("CancelHandle")
+      .field("token", &self.token)
+      .finish()
+  }
+}
+
+type Listener = Box<dyn FnOnce() + Send>;
+
+/// Signal shutting down the batchers built with it, see
+/// `BatcherBuilder::cancellation`.
+///
+/// Clones share the signal, so hand one to the shutdown logic of your
+/// framework and cancel it from there.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+  inner: Arc<Signal>,
+}
+
+#[derive(Default)]
+struct Signal {
+  cancelled: AtomicBool,
+  /// Listeners with the id of their `Subscription`.
+  listeners: Mutex<Vec<(u64, Listener)>>,
+  /// The latest id handed out.
+  ids: AtomicU64,
+}
+
+/// A listener registered with a token. Dropping it removes the listener,
+/// e.g. once its batcher is gone.
+pub(crate) struct Subscription {
+  signal: Weak<Signal>,
+  id: u64,
+}
+
+impl CancellationToken {
+  /// Create a token which is not cancelled.
+  pub fn new() -> Self {
+    CancellationToken::default()
+  }
+
+  /// Cancel the token. Only the first call has an effect.
+  pub fn cancel(&self) {
+    let listeners = {
+      let mut listeners = self.inner.listeners.lock().unwrap();
+      if self.inner.cancelled.swap(true, Ordering::SeqCst) {
+        return;
+      }
+      mem::take(&mut *listeners)
+    };
+    for (_, listener) in listeners {
+      listener();
+    }
+  }
+
+  /// Whether the token is cancelled.
+  pub fn is_cancelled(&self) -> bool {
+    self.inner.cancelled.load(Ordering::SeqCst)
+  }
+
+  /// Call `listener` once the token is cancelled, right away if it is.
+  /// Unless it was called already, it is kept until the subscription is
+  /// dropped.
+  pub(crate) fn on_cancel(&self, listener: Listener) -> Option<Subscription> {
+    {
+      let mut listeners = self.inner.listeners.lock().unwrap();
+      if !self.is_cancelled() {
+        let id = self.inner.ids.fetch_add(1, Ordering::Relaxed) + 1;
+        listeners.push((id, listener));
+        return Some(Subscription {
+          signal: Arc::downgrade(&self.inner),
+          id,
+        });
+      }
+    }
+    listener();
+    None
+  }
+}
+
+impl Drop for Subscription {
+  fn drop(&mut self) {
+    let signal = match self.signal.upgrade() {
+      Some(signal) => signal,
+      None => return,
+    };
+    let listener = {
+      let mut listeners = signal.listeners.lock().unwrap();
+      let at = listeners.iter().position(|(id, _)| *id == self.id);
+      at.map(|at| listeners.remove(at))
+    };
+    // Dropped without holding the lock.
+    drop(listener);
+  }
+}
+
+impl fmt::Debug for CancellationToken {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let listeners = self.inner.listeners.lock().unwrap().len();
+    f.debug_struct("CancellationToken")
+      .field("cancelled", &self.is_cancelled())
+      .field("listeners", &listeners)
+      .finish()
+  }
+}
@@ -0,0 +1,81 @@
+#[cfg(feature = "check")]
+use {BatchContainer, State};
+
+/// Counts of values and callbacks passing through a batcher, which
+/// `Batcher::invariants` balances against what it holds.
+#[derive(Debug, Default)]
+#[cfg_attr(not(feature = "check"), allow(dead_code))]
+pub(crate) struct Counts {
+  /// Values moved into the running slot.
+  pub(crate) started: u64,
+  /// Values taken out without running, e.g. by `Batcher::cancel_pending`.
+  pub(crate) discarded: u64,
+  /// Callbacks bound to queued values.
+  pub(crate) bound: u64,
+  /// Callbacks taken out to be called.
+  pub(crate) called: u64,
+}
+
+#[cfg(feature = "check")]
+impl<T, C: BatchContainer<T>> State<T, C> {
+  /// Panic if the state is inconsistent, see `Batcher::invariants`.
+  pub(crate) fn check(&self) {
+    let counts = &self.counts;
+    let queued = self.queued() as u64;
+    assert_eq!(
+      self.metrics.appended,
+      counts.started + counts.discarded + queued,
+      "appended values are neither started, discarded nor queued: {:?}",
+      counts
+    );
+    let mut held = self.urgent_callbacks.len() + self.pending_callbacks.len();
+    held += self
+      .ready
+      .iter()
+      .map(|(_, cbs, _)| cbs.len())
+      .sum::<usize>();
+    held += self
+      .in_flight
+      .iter()
+      .map(|f| f.callbacks.len())
+      .sum::<usize>();
+    assert_eq!(
+      counts.bound,
+      counts.called + held as u64,
+      "bound callbacks are neither called nor held: {:?}",
+      counts
+    );
+    assert_eq!(
+      self.dispatched,
+      self.completed + self.in_flight.len() as u64,
+      "started batches are neither done nor in flight"
+    );
+    assert!(
+      self.in_flight.len() <= self.max_in_flight,
+      "more batches in flight than allowed"
+    );
+    let ids = self.in_flight.iter().map(|flight| flight.id);
+    assert!(
+      ids.clone().zip(ids.skip(1)).all(|(a, b)| a < b),
+      "batches in flight out of order"
+    );
+    let lanes = self
+      .ready
+      .iter()
+      .map(|(batch, cbs, _)| (batch.len(), cbs))
+      .chain(Some((self.urgent_batch.len(), &self.urgent_callbacks)))
+      .chain(Some((self.pending_batch.len(), &self.pending_callbacks)));
+    for (len, callbacks) in lanes {
+      let mut end = 0;
+      for (range, _) in callbacks {
+        assert!(
+          range.start >= end && range.end <= len,
+          "callback range {:?} outside its batch of {}",
+          range,
+          len
+        );
+        end = range.end;
+      }
+    }
+  }
+}
@@ -0,0 +1,109 @@
+use done::Outcome;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use {BatchContainer, BatchError, BatchId, Callback, ChunkErrors, Entry};
+
+/// Split a batch into runs of at most `max` values. A callback whose values
+/// end up in several runs is called once all of them are done, with the
+/// error `errors` picks.
+pub(crate) fn split<T, C: BatchContainer<T>>(
+  mut batch: C,
+  callbacks: Vec<Entry>,
+  max: usize,
+  errors: ChunkErrors,
+) -> Vec<(C, Vec<Entry>)> {
+  // Split the runs off from the back, so every value moves once, rather
+  // than splitting the rest off again for every run of a huge batch.
+  let runs = batch.len().div_ceil(max).max(1);
+  let mut chunks = Vec::with_capacity(runs);
+  for run in (1..runs).rev() {
+    chunks.push((batch.split_off(run * max), Vec::new()));
+  }
+  chunks.push((batch, Vec::new()));
+  chunks.reverse();
+  let last = chunks.len() - 1;
+  for (range, cb) in callbacks {
+    let first = (range.start / max).min(last);
+    let end = if range.is_empty() {
+      first
+    } else {
+      (range.end - 1) / max
+    };
+    if first == end {
+      let offset = first * max;
+      let range = range.start - offset..range.end - offset;
+      chunks[first].1.push((range, cb));
+      continue;
+    }
+    let joint = Arc::new(Joint {
+      state: Mutex::new(JointState {
+        cb: Some(cb),
+        results: vec![Ok(()); range.len()],
+        failures: Vec::new(),
+        parts: end - first + 1,
+      }),
+      errors,
+    });
+    for (i, chunk) in chunks.iter_mut().enumerate().take(end + 1).skip(first) {
+      let start = range.start.max(i * max);
+      let stop = range.end.min((i + 1) * max);
+      let part = Callback::Part(joint.clone(), start - range.start);
+      chunk.1.push((start - i * max..stop - i * max, part));
+    }
+  }
+  chunks
+}
+
+/// A callback whose values were split across several runs.
+pub(crate) struct Joint {
+  state: Mutex<JointState>,
+  errors: ChunkErrors,
+}
+
+struct JointState {
+  cb: Option<Callback>,
+  results: Vec<Result<(), BatchError>>,
+  /// The first error of each part which failed, with the part's offset.
+  failures: Vec<(usize, BatchError)>,
+  parts: usize,
+}
+
+impl Joint {
+  /// Record the results of one part, starting at `offset` of the callback's
+  /// values, calling the callback once every part is in.
+  pub(crate) fn finish(
+    &self,
+    offset: usize,
+    results: Vec<Result<(), BatchError>>,
+    id: Option<BatchId>,
+  ) {
+    let mut state = self.state.lock().unwrap();
+    if let Some(err) = results.iter().find_map(|res| res.clone().err()) {
+      state.failures.push((offset, err));
+    }
+    for (i, res) in results.into_iter().enumerate() {
+      state.results[offset + i] = res;
+    }
+    state.parts -= 1;
+    if state.parts > 0 {
+      return;
+    }
+    let cb = state.cb.take();
+    let results = mem::take(&mut state.results);
+    let mut failures = mem::take(&mut state.failures);
+    drop(state);
+    let cb = match cb {
+      Some(cb) => cb,
+      None => return,
+    };
+    let len = results.len();
+    let outcome = if failures.is_empty() || cb.wants_items() {
+      Outcome::Items(results)
+    } else {
+      failures.sort_by_key(|(offset, _)| *offset);
+      let errors = failures.into_iter().map(|(_, err)| err).collect();
+      Outcome::Batch(self.errors.pick(errors))
+    };
+    cb.call(0..len, id, &outcome);
+  }
+}
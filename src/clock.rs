@@ -0,0 +1,68 @@
+use std::fmt;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Instant, SystemTime};
+
+/// Source of the time for the timing features of a batcher: lingering,
+/// batch timeouts, TTLs, the rate limit and the circuit breaker cooldown,
+/// see `BatcherBuilder::clock`.
+pub trait Clock: Send + Sync {
+  /// The current time.
+  fn now(&self) -> Instant;
+
+  /// The current wall-clock time, which `BatcherBuilder::window` aligns
+  /// windows to. A clock not going by the real time should move it along
+  /// with `now`.
+  fn system_time(&self) -> SystemTime {
+    SystemTime::now()
+  }
+
+  /// Block the timer thread of the batcher until `due`, or until it is
+  /// unparked. Returning early is fine, the timer checks again.
+  fn park_until(&self, due: Instant) {
+    let now = self.now();
+    if due > now {
+      thread::park_timeout(due - now);
+    }
+  }
+}
+
+/// The real time, which batchers use by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedClock(pub(crate) Arc<dyn Clock>);
+
+impl SharedClock {
+  pub(crate) fn now(&self) -> Instant {
+    self.0.now()
+  }
+
+  pub(crate) fn system_time(&self) -> SystemTime {
+    self.0.system_time()
+  }
+}
+
+impl Default for SharedClock {
+  fn default() -> Self {
+    SharedClock(Arc::new(SystemClock))
+  }
+}
+
+impl fmt::Debug for SharedClock {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("Clock")
+  }
+}
+
+// Like `SharedSink`, a clock only answers what time it is.
+impl UnwindSafe for SharedClock {}
+impl RefUnwindSafe for SharedClock {}
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::sync::Arc;
+use {Decision, FlushPolicy};
+
+type Encode<T> = Arc<dyn Fn(&T, &mut Vec<u8>) + Send + Sync>;
+
+/// Compression for `CompressedSize` and `BatcherBuilder::build_compressed`,
+/// implemented by any `Fn(&[u8]) -> Vec<u8>`.
+pub trait Codec {
+  /// Compress `bytes`.
+  fn compress(&self, bytes: &[u8]) -> Vec<u8>;
+}
+
+impl<F: Fn(&[u8]) -> Vec<u8>> Codec for F {
+  fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+    self(bytes)
+  }
+}
+
+/// `FlushPolicy` closing the pending batch once its values, encoded and
+/// compressed, take `threshold` bytes.
+///
+/// Values are encoded once, but the encoded batch is compressed again on
+/// every append, so it suits codecs which are quick on the batch sizes at
+/// hand.
+pub struct CompressedSize<T> {
+  threshold: usize,
+  encode: Encode<T>,
+  codec: Arc<dyn Codec + Send + Sync>,
+  /// The pending values encoded so far, and how many.
+  bytes: Vec<u8>,
+  encoded: usize,
+}
+
+impl<T> CompressedSize<T> {
+  /// Create the policy. `encode` appends a value to the given bytes.
+  pub fn new<E, C>(threshold: usize, encode: E, codec: C) -> Self
+  where
+    E: Fn(&T, &mut Vec<u8>) + Send + Sync + 'static,
+    C: Codec + Send + Sync + 'static,
+  {
+    CompressedSize {
+      threshold,
+      encode: Arc::new(encode),
+      codec: Arc::new(codec),
+      bytes: Vec::new(),
+      encoded: 0,
+    }
+  }
+
+  /// Encode and compress `batch` as a whole.
+  pub(crate) fn compress(&self, batch: &[T]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for val in batch {
+      (self.encode)(val, &mut bytes);
+    }
+    self.codec.compress(&bytes)
+  }
+}
+
+impl<T> FlushPolicy<T> for CompressedSize<T> {
+  fn on_append(&mut self, batch: &[T]) -> Decision {
+    if batch.len() < self.encoded {
+      // Values were cancelled, start over.
+      self.reset();
+    }
+    for val in &batch[self.encoded..] {
+      (self.encode)(val, &mut self.bytes);
+    }
+    self.encoded = batch.len();
+    if self.codec.compress(&self.bytes).len() >= self.threshold {
+      Decision::Flush
+    } else {
+      Decision::Wait
+    }
+  }
+
+  fn reset(&mut self) {
+    self.bytes.clear();
+    self.encoded = 0;
+  }
+}
+
+impl<T> Clone for CompressedSize<T> {
+  fn clone(&self) -> Self {
+    CompressedSize {
+      threshold: self.threshold,
+      encode: self.encode.clone(),
+      codec: self.codec.clone(),
+      bytes: self.bytes.clone(),
+      encoded: self.encoded,
+    }
+  }
+}
+
+impl<T> fmt::Debug for CompressedSize<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("CompressedSize")
+      .field("threshold", &self.threshold)
+      .field("bytes", &self.bytes.len())
+      .finish()
+  }
+}
@@ -0,0 +1,119 @@
+use std::collections::{vec_deque, VecDeque};
+use std::iter::FromIterator;
+use std::slice;
+
+/// Storage of the values of a batch, from appends to the run function.
+/// Batchers use `Vec` by default; `BatcherBuilder::build_into` picks
+/// another, e.g. a `VecDeque` or a type of the caller's which grows
+/// differently.
+pub trait BatchContainer<T>:
+  Default + Extend<T> + FromIterator<T> + IntoIterator<Item = T>
+{
+  /// Iterates over the values in order.
+  type Iter<'a>: Iterator<Item = &'a T>
+  where
+    Self: 'a,
+    T: 'a;
+
+  /// Number of values held.
+  fn len(&self) -> usize;
+
+  /// Whether no values are held.
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// The values in order.
+  fn iter(&self) -> Self::Iter<'_>;
+
+  /// Split the values from `at` on off into a container of their own.
+  fn split_off(&mut self, at: usize) -> Self;
+
+  /// Remove every value, keeping the storage for the next batch, see
+  /// `BatcherBuilder::build_pooled_into`.
+  fn clear(&mut self);
+
+  /// The values in one slice, e.g. for a flush policy. May move them to
+  /// make them contiguous.
+  fn as_mut_slice(&mut self) -> &mut [T];
+
+  /// Take over the values of a `Vec`, e.g. one passed to `append`.
+  fn from_vec(values: Vec<T>) -> Self {
+    values.into_iter().collect()
+  }
+
+  /// Hand the values over in a `Vec`, e.g. to `coalesce`.
+  fn into_vec(self) -> Vec<T> {
+    self.into_iter().collect()
+  }
+}
+
+impl<T> BatchContainer<T> for Vec<T> {
+  type Iter<'a>
+    = slice::Iter<'a, T>
+  where
+    T: 'a;
+
+  fn len(&self) -> usize {
+    Vec::len(self)
+  }
+
+  fn iter(&self) -> Self::Iter<'_> {
+    self[..].iter()
+  }
+
+  fn split_off(&mut self, at: usize) -> Self {
+    Vec::split_off(self, at)
+  }
+
+  fn clear(&mut self) {
+    Vec::clear(self)
+  }
+
+  fn as_mut_slice(&mut self) -> &mut [T] {
+    self
+  }
+
+  fn from_vec(values: Vec<T>) -> Self {
+    values
+  }
+
+  fn into_vec(self) -> Vec<T> {
+    self
+  }
+}
+
+impl<T> BatchContainer<T> for VecDeque<T> {
+  type Iter<'a>
+    = vec_deque::Iter<'a, T>
+  where
+    T: 'a;
+
+  fn len(&self) -> usize {
+    VecDeque::len(self)
+  }
+
+  fn iter(&self) -> Self::Iter<'_> {
+    VecDeque::iter(self)
+  }
+
+  fn split_off(&mut self, at: usize) -> Self {
+    VecDeque::split_off(self, at)
+  }
+
+  fn clear(&mut self) {
+    VecDeque::clear(self)
+  }
+
+  fn as_mut_slice(&mut self) -> &mut [T] {
+    self.make_contiguous()
+  }
+
+  fn from_vec(values: Vec<T>) -> Self {
+    VecDeque::from(values)
+  }
+
+  fn into_vec(self) -> Vec<T> {
+    Vec::from(self)
+  }
+}
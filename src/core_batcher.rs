@@ -0,0 +1,133 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+type CoreCb = Box<dyn FnOnce()>;
+/// Describing optional callback function of a `CoreBatcher`, called once the
+/// batch holding the values is done.
+pub type CoreCbOption = Option<CoreCb>;
+type CoreRun<T> = Box<dyn FnMut(Vec<T>)>;
+
+/// Tells a `CoreBatcher` that its running batch is done, e.g. raised by the
+/// interrupt handler of a radio once a transmission went out.
+pub trait Signal {
+  /// Whether the running batch is done since last asked, clearing the
+  /// signal.
+  fn take(&self) -> bool;
+}
+
+impl Signal for AtomicBool {
+  fn take(&self) -> bool {
+    self.swap(false, Ordering::SeqCst)
+  }
+}
+
+impl<S: Signal + ?Sized> Signal for &S {
+  fn take(&self) -> bool {
+    (**self).take()
+  }
+}
+
+impl<S: Signal + ?Sized> Signal for Arc<S> {
+  fn take(&self) -> bool {
+    (**self).take()
+  }
+}
+
+/// Batcher built on `alloc` alone, for firmware without `std`, e.g. to batch
+/// sensor readings for one radio transmission each.
+///
+/// The first append runs right away, and later ones collect in the pending
+/// batch while one runs. The run function only starts a batch; whoever
+/// finishes it raises the signal, and `poll`, called from the main loop,
+/// sees it, calls the callbacks and runs the next batch. Nothing is locked,
+/// so share the batcher the way the firmware shares its other state.
+pub struct CoreBatcher<T, S> {
+  run: CoreRun<T>,
+  signal: S,
+  pending_batch: Vec<T>,
+  pending_callbacks: Vec<CoreCb>,
+  /// The callbacks of the running batch, if there is one.
+  running: Option<Vec<CoreCb>>,
+}
+
+impl<T, S: Signal> CoreBatcher<T, S> {
+  /// Create a new batcher with the signal its batches raise once done, and a
+  /// run function starting them.
+  pub fn new<F>(signal: S, run: F) -> Self
+  where
+    F: FnMut(Vec<T>) + 'static,
+  {
+    CoreBatcher {
+      run: Box::new(run),
+      signal,
+      pending_batch: Vec::new(),
+      pending_callbacks: Vec::new(),
+      running: None,
+    }
+  }
+
+  /// Accept an array of values and a callback, called once the batch
+  /// containing the values is done.
+  pub fn append(&mut self, val: Vec<T>, cb: CoreCbOption) {
+    self.pending_batch.extend(val);
+    self.pending_callbacks.extend(cb);
+    if self.running.is_none() {
+      self.start();
+    }
+  }
+
+  /// Accept a single value and a callback, see `append`.
+  pub fn append_one(&mut self, val: T, cb: CoreCbOption) {
+    self.append(vec![val], cb)
+  }
+
+  /// Check the signal: once the running batch is done, call its callbacks
+  /// and run the next batch. Returns whether a batch was done.
+  pub fn poll(&mut self) -> bool {
+    if self.running.is_none() || !self.signal.take() {
+      return false;
+    }
+    for cb in self.running.take().into_iter().flatten() {
+      cb();
+    }
+    self.start();
+    true
+  }
+
+  /// How many values wait for a batch.
+  pub fn pending(&self) -> usize {
+    self.pending_batch.len()
+  }
+
+  /// Whether a batch is running.
+  pub fn is_running(&self) -> bool {
+    self.running.is_some()
+  }
+
+  /// The signal the batches raise.
+  pub fn signal(&self) -> &S {
+    &self.signal
+  }
+
+  fn start(&mut self) {
+    if self.pending_batch.is_empty() && self.pending_callbacks.is_empty() {
+      return;
+    }
+    let batch = mem::take(&mut self.pending_batch);
+    self.running = Some(mem::take(&mut self.pending_callbacks));
+    (self.run)(batch);
+  }
+}
+
+impl<T, S> fmt::Debug for CoreBatcher<T, S> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("CoreBatcher")
+      .field("pending", &self.pending_batch.len())
+      .field("running", &self.running.is_some())
+      .finish()
+  }
+}
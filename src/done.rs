@@ -0,0 +1,343 @@
+use batch::Meta;
+use clock::SharedClock;
+use std::any::Any;
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+use {BatchError, Entry, IdempotencyKey, Trigger};
+
+/// Implemented by batchers so `Done` does not carry their item type.
+pub(crate) trait Complete: Send + Sync {
+  fn complete(&self, id: u64, outcome: Outcome);
+
+  /// The run function reported progress, see `Done::progress`.
+  fn progress(&self, _id: u64, _committed: usize) {}
+}
+
+/// What a run function made of a batch, see `Done::finish_with`.
+pub(crate) type Value = Arc<dyn Any + Send + Sync>;
+
+/// One response per value of a batch, see `Done::respond`. Each callback
+/// takes those of its own values.
+pub(crate) struct Responses<R>(pub(crate) Mutex<Vec<Option<R>>>);
+
+/// How a batch finished.
+pub(crate) enum Outcome {
+  /// One result for the whole batch.
+  Batch(Result<(), BatchError>),
+  /// One result per value of the batch.
+  Items(Vec<Result<(), BatchError>>),
+  /// One result for the whole batch, carrying a value.
+  Value(Result<Value, BatchError>),
+}
+
+impl Outcome {
+  /// Whether any value in `range` of the batch failed.
+  pub(crate) fn failed(&self, range: &Range<usize>) -> bool {
+    match self {
+      Outcome::Batch(res) => res.is_err(),
+      Outcome::Value(res) => res.is_err(),
+      Outcome::Items(results) => range.clone().any(|i| match results.get(i) {
+        Some(res) => res.is_err(),
+        None => true,
+      }),
+    }
+  }
+
+  /// The outcome as one result for the whole batch.
+  pub(crate) fn flatten(self) -> Outcome {
+    if let Outcome::Value(_) = self {
+      return self;
+    }
+    match self.error() {
+      Some(err) => Outcome::Batch(Err(err)),
+      None => Outcome::Batch(Ok(())),
+    }
+  }
+
+  /// The outcome of a failed batch of `len` values whose first `committed`
+  /// values are done, see `Done::progress`. Those values succeed.
+  pub(crate) fn commit(self, committed: usize, len: usize) -> Outcome {
+    if committed == 0 || self.error().is_none() {
+      return self;
+    }
+    let mut results = self.results(len);
+    for res in results.iter_mut().take(committed) {
+      *res = Ok(());
+    }
+    Outcome::Items(results)
+  }
+
+  /// How many values at the start of the batch succeeded.
+  pub(crate) fn committed(&self) -> usize {
+    match self {
+      Outcome::Items(results) => {
+        results.iter().take_while(|res| res.is_ok()).count()
+      }
+      _ => 0,
+    }
+  }
+
+  /// The outcome of a batch resumed after `skipped` values which are done,
+  /// from the outcome of the `len` values it ran with.
+  pub(crate) fn resume(self, skipped: usize, len: usize) -> Outcome {
+    if skipped == 0 || self.error().is_none() {
+      return self;
+    }
+    let mut results = vec![Ok(()); skipped];
+    results.extend(self.results(len));
+    Outcome::Items(results)
+  }
+
+  /// One result for each of the `len` values of the batch.
+  fn results(self, len: usize) -> Vec<Result<(), BatchError>> {
+    let err = self.error();
+    let mut results = match self {
+      Outcome::Items(results) => results,
+      _ => vec![],
+    };
+    let missing = err.map_or(Ok(()), Err);
+    results.resize(len, missing);
+    results
+  }
+
+  /// The first error of the batch, if any.
+  pub(crate) fn error(&self) -> Option<BatchError> {
+    match self {
+      Outcome::Batch(res) => res.clone().err(),
+      Outcome::Value(res) => res.clone().err(),
+      Outcome::Items(results) => {
+        results.iter().find_map(|res| res.clone().err())
+      }
+    }
+  }
+}
+
+/// Sequence number of a batch, increasing from 1 with every batch a batcher
+/// runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BatchId(u64);
+
+impl BatchId {
+  pub(crate) fn new(id: u64) -> Self {
+    BatchId(id)
+  }
+
+  /// The sequence number.
+  pub fn get(self) -> u64 {
+    self.0
+  }
+}
+
+impl fmt::Display for BatchId {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+/// Handle passed to the run function with every batch.
+///
+/// Signal the result of the batch through it, from any thread. The callbacks
+/// of the batch get the result, and the next batch starts running. Dropping
+/// it without a result fails the batch with `BatchError::DoneDropped`.
+pub struct Done {
+  /// Taken once the result is signalled.
+  batcher: Option<Arc<dyn Complete>>,
+  id: u64,
+  meta: Meta,
+}
+
+impl Done {
+  pub(crate) fn new(batcher: Arc<dyn Complete>, id: u64, meta: Meta) -> Self {
+    Done {
+      batcher: Some(batcher),
+      id,
+      meta,
+    }
+  }
+
+  pub(crate) fn meta(&self) -> Meta {
+    self.meta
+  }
+
+  /// The id of the batch.
+  pub fn id(&self) -> BatchId {
+    BatchId(self.id)
+  }
+
+  /// When the first values of the batch were appended.
+  pub fn created_at(&self) -> Instant {
+    self.meta.created_at
+  }
+
+  /// How many appends the values of the batch came from.
+  pub fn appends(&self) -> usize {
+    self.meta.appends
+  }
+
+  /// What started the batch.
+  pub fn trigger(&self) -> Trigger {
+    self.meta.trigger
+  }
+
+  /// The key of the batch, the same every time it runs.
+  pub fn idempotency_key(&self) -> IdempotencyKey {
+    self.meta.key
+  }
+
+  /// Report that the first `committed` values of the batch are done for
+  /// good, e.g. written to a log, while the rest still runs. Counts towards
+  /// `BatcherStats::committed_items` and restarts the batch timeout. Should
+  /// the batch fail, the callbacks of those values still succeed.
+  pub fn progress(&self, committed: usize) {
+    if let Some(ref batcher) = self.batcher {
+      batcher.progress(self.id, committed)
+    }
+  }
+
+  /// The batch ran successfully.
+  pub fn ok(self) {
+    self.finish(Ok(()))
+  }
+
+  /// The batch failed.
+  pub fn err(self, err: BatchError) {
+    self.finish(Err(err))
+  }
+
+  /// The batch failed after its first `committed` values were done for
+  /// good, see `progress`. Their callbacks succeed, and a `RetryLayer` runs
+  /// only the rest again.
+  pub fn err_after(self, committed: usize, err: BatchError) {
+    self.progress(committed);
+    self.err(err)
+  }
+
+  /// The batch finished with `res`.
+  pub fn finish(self, res: Result<(), BatchError>) {
+    self.complete(Outcome::Batch(res))
+  }
+
+  /// The batch finished with `res`, handing its value to the callbacks of
+  /// `Batcher::append_with_result`, e.g. the offset a broker acknowledged
+  /// the batch with. Other callbacks get the result without the value.
+  pub fn finish_with<R>(self, res: Result<R, BatchError>)
+  where
+    R: Send + Sync + 'static,
+  {
+    self.complete(Outcome::Value(res.map(|val| Arc::new(val) as Value)))
+  }
+
+  /// The batch succeeded with one response per value, in batch order,
+  /// handing each callback of `Batcher::append_map` the responses to its
+  /// own values. Other callbacks get the result without the responses.
+  pub fn respond<R: Send + 'static>(self, responses: Vec<R>) {
+    let responses = responses.into_iter().map(Some).collect();
+    let responses = Responses(Mutex::new(responses));
+    self.complete(Outcome::Value(Ok(Arc::new(responses) as Value)))
+  }
+
+  /// The batch finished with one result per value, in batch order. Values
+  /// without a result count as failed.
+  pub fn finish_each(self, results: Vec<Result<(), BatchError>>) {
+    self.complete(Outcome::Items(results))
+  }
+
+  pub(crate) fn complete(mut self, outcome: Outcome) {
+    if let Some(batcher) = self.batcher.take() {
+      batcher.complete(self.id, outcome)
+    }
+  }
+}
+
+impl Drop for Done {
+  fn drop(&mut self) {
+    if let Some(batcher) = self.batcher.take() {
+      let err = if thread::panicking() {
+        BatchError::RunnerPanicked
+      } else {
+        BatchError::DoneDropped
+      };
+      batcher.complete(self.id, Outcome::Batch(Err(err)))
+    }
+  }
+}
+
+impl fmt::Debug for Done {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Done").field("id", &self.id).finish()
+  }
+}
+
+/// Completes a batch run after its batcher is gone, see `DropPolicy::Flush`.
+pub(crate) struct Detached {
+  callbacks: Mutex<Option<Vec<Entry>>>,
+  /// Whether the callbacks were called.
+  finished: AtomicBool,
+  /// The thread waiting for the batch, see `wait`.
+  waiter: Mutex<Option<Thread>>,
+  id: u64,
+  flatten: bool,
+}
+
+impl Detached {
+  pub(crate) fn new(callbacks: Vec<Entry>, id: u64) -> Self {
+    Detached {
+      callbacks: Mutex::new(Some(callbacks)),
+      finished: AtomicBool::new(false),
+      waiter: Mutex::new(None),
+      id,
+      flatten: false,
+    }
+  }
+
+  /// Hand the callbacks a flattened outcome, see `Outcome::flatten`.
+  pub(crate) fn flatten(mut self) -> Self {
+    self.flatten = true;
+    self
+  }
+
+  /// Block until the batch is done, failing it with `BatchError::Timeout`
+  /// once `timeout` is over by `clock`. Tells whether it was done in time.
+  pub(crate) fn wait(
+    &self,
+    timeout: Option<Duration>,
+    clock: &SharedClock,
+  ) -> bool {
+    let deadline = timeout.map(|timeout| clock.now() + timeout);
+    *self.waiter.lock().unwrap() = Some(thread::current());
+    while !self.finished.load(Ordering::SeqCst) {
+      match deadline {
+        Some(deadline) if deadline <= clock.now() => {
+          let timeout = Outcome::Batch(Err(BatchError::Timeout));
+          self.complete(self.id, timeout);
+          return false;
+        }
+        // Unparked once the batch is done, or by the clock.
+        Some(deadline) => clock.0.park_until(deadline),
+        None => thread::park(),
+      }
+    }
+    true
+  }
+}
+
+impl Complete for Detached {
+  fn complete(&self, _id: u64, mut outcome: Outcome) {
+    if self.flatten {
+      outcome = outcome.flatten();
+    }
+    let callbacks = mem::take(&mut *self.callbacks.lock().unwrap());
+    for (range, cb) in callbacks.unwrap_or_default() {
+      cb.call(range, Some(BatchId(self.id)), &outcome);
+    }
+    self.finished.store(true, Ordering::SeqCst);
+    if let Some(ref waiter) = *self.waiter.lock().unwrap() {
+      waiter.unpark();
+    }
+  }
+}
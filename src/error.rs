@@ -0,0 +1,230 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+/// Reason a batch, or an append, did not succeed.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum BatchError {
+  /// The run function reported a failure.
+  Run(Arc<dyn Error + Send + Sync>),
+  /// The batcher was shut down before the batch could run.
+  Closed,
+  /// The batcher could not take more items.
+  CapacityExceeded,
+  /// The batch did not complete in time.
+  Timeout,
+  /// The values were cancelled before they ran.
+  Cancelled,
+  /// The values expired before their batch started, see
+  /// `Batcher::append_with_ttl`.
+  Expired,
+  /// The run function panicked while running the batch.
+  RunnerPanicked,
+  /// The `Done` of the batch was dropped without signalling a result.
+  DoneDropped,
+  /// The circuit breaker is open, see `CircuitBreaker::fail_fast`.
+  CircuitOpen,
+  /// The batch succeeded without the value a callback expected, see
+  /// `Batcher::append_with_result`.
+  NoResult,
+  /// Too few sinks of a `FanoutRunner` succeeded. Holds the index and error
+  /// of each sink which failed, in the order they failed.
+  Fanout(Vec<(usize, BatchError)>),
+  /// Several chunks of the values failed, see `ChunkErrors::All`. Holds the
+  /// error of each, in value order.
+  Chunks(Vec<BatchError>),
+}
+
+impl BatchError {
+  /// Wrap a run function failure.
+  pub fn run<E>(err: E) -> Self
+  where
+    E: Into<Box<dyn Error + Send + Sync>>,
+  {
+    BatchError::Run(Arc::from(err.into()))
+  }
+
+  /// The run function failure, if it is an `E`, e.g. the `io::Error` a
+  /// runner wrapped with `run`.
+  pub fn downcast_ref<E: Error + 'static>(&self) -> Option<&E> {
+    match self {
+      BatchError::Run(err) => err.downcast_ref(),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for BatchError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      BatchError::Run(err) => write!(f, "run failed: {}", err),
+      BatchError::Closed => write!(f, "batcher is closed"),
+      BatchError::CapacityExceeded => write!(f, "batcher is at capacity"),
+      BatchError::Timeout => write!(f, "batch timed out"),
+      BatchError::Cancelled => write!(f, "values were cancelled"),
+      BatchError::Expired => write!(f, "values expired before they ran"),
+      BatchError::RunnerPanicked => write!(f, "run function panicked"),
+      BatchError::DoneDropped => write!(f, "batch was never signalled done"),
+      BatchError::CircuitOpen => write!(f, "circuit breaker is open"),
+      BatchError::NoResult => write!(f, "batch has no result of that type"),
+      BatchError::Fanout(errors) => {
+        write!(f, "{} fanout sinks failed", errors.len())?;
+        for (sink, err) in errors {
+          write!(f, "; sink {}: {}", sink, err)?;
+        }
+        Ok(())
+      }
+      BatchError::Chunks(errors) => {
+        write!(f, "{} chunks failed", errors.len())?;
+        for err in errors {
+          write!(f, "; {}", err)?;
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+impl Error for BatchError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      BatchError::Run(err) => Some(&**err),
+      _ => None,
+    }
+  }
+}
+
+/// Reason a `BatcherBuilder` configuration is invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+  /// `max_batch_size` is zero.
+  ZeroBatchSize,
+  /// `max_batch_delay` is zero.
+  ZeroBatchDelay,
+  /// `max_item_latency` is zero.
+  ZeroItemLatency,
+  /// `min_batch_size` is zero, or waits for no time.
+  ZeroMinBatch,
+  /// `window` is zero.
+  ZeroWindow,
+  /// `capacity` is zero.
+  ZeroCapacity,
+  /// `batch_timeout` is zero.
+  ZeroBatchTimeout,
+  /// `max_batches_per_second` is zero.
+  ZeroRate,
+  /// The `CircuitBreaker` opens after zero failures.
+  ZeroBreakerFailures,
+  /// `max_batch_bytes` is zero.
+  ZeroBatchBytes,
+  /// `max_items_per_dispatch` is zero.
+  ZeroItemsPerDispatch,
+  /// `pipeline` or `max_concurrent_batches` is zero.
+  ZeroPipeline,
+  /// The `AdaptivePolicy` minimum is zero or above its maximum.
+  InvalidAdaptiveRange,
+  /// `capacity` is smaller than `max_batch_size`, so a batch could never fill.
+  CapacityBelowBatchSize,
+  /// A `ShardedBatcher` has no shards.
+  ZeroShards,
+  /// `max_sync_dispatches` is zero.
+  ZeroSyncDispatches,
+  /// The named setting does not apply to a batcher made by
+  /// `BatcherBuilder::scope`.
+  NotScoped(&'static str),
+  /// The named setting does not apply to a `TokioBatcher`.
+  NotTokio(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ConfigError::ZeroBatchSize => write!(f, "max_batch_size must not be 0"),
+      ConfigError::ZeroBatchDelay => {
+        write!(f, "max_batch_delay must not be 0")
+      }
+      ConfigError::ZeroItemLatency => {
+        write!(f, "max_item_latency must not be 0")
+      }
+      ConfigError::ZeroMinBatch => {
+        write!(f, "min_batch_size and its wait must not be 0")
+      }
+      ConfigError::ZeroWindow => write!(f, "window must not be 0"),
+      ConfigError::ZeroCapacity => write!(f, "capacity must not be 0"),
+      ConfigError::ZeroBatchTimeout => {
+        write!(f, "batch_timeout must not be 0")
+      }
+      ConfigError::ZeroBatchBytes => {
+        write!(f, "max_batch_bytes must not be 0")
+      }
+      ConfigError::ZeroItemsPerDispatch => {
+        write!(f, "max_items_per_dispatch must not be 0")
+      }
+      ConfigError::ZeroPipeline => write!(f, "batches in flight must not be 0"),
+      ConfigError::ZeroRate => {
+        write!(f, "max_batches_per_second must not be 0")
+      }
+      ConfigError::ZeroBreakerFailures => {
+        write!(f, "circuit breaker failures must not be 0")
+      }
+      ConfigError::InvalidAdaptiveRange => {
+        write!(f, "adaptive batch size range must be 1 <= min <= max")
+      }
+      ConfigError::CapacityBelowBatchSize => {
+        write!(f, "capacity must not be less than max_batch_size")
+      }
+      ConfigError::ZeroShards => write!(f, "shard count must not be 0"),
+      ConfigError::NotScoped(setting) => {
+        write!(f, "{} does not apply to a scoped batcher", setting)
+      }
+      ConfigError::NotTokio(setting) => {
+        write!(f, "{} does not apply to a tokio batcher", setting)
+      }
+      ConfigError::ZeroSyncDispatches => {
+        write!(f, "max_sync_dispatches must not be 0")
+      }
+    }
+  }
+}
+
+impl Error for ConfigError {}
+
+/// Values an append could not take, handed back to the caller.
+#[non_exhaustive]
+pub enum AppendError<T> {
+  /// The batcher is at capacity.
+  Full(Vec<T>),
+  /// The batcher is closed.
+  Closed(Vec<T>),
+}
+
+impl<T> AppendError<T> {
+  /// The values which were not appended.
+  pub fn into_inner(self) -> Vec<T> {
+    match self {
+      AppendError::Full(val) | AppendError::Closed(val) => val,
+    }
+  }
+}
+
+impl<T> fmt::Debug for AppendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      AppendError::Full(val) => write!(f, "Full({} values)", val.len()),
+      AppendError::Closed(val) => write!(f, "Closed({} values)", val.len()),
+    }
+  }
+}
+
+impl<T> fmt::Display for AppendError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      AppendError::Full(_) => write!(f, "batcher is at capacity"),
+      AppendError::Closed(_) => write!(f, "batcher is closed"),
+    }
+  }
+}
+
+impl<T> Error for AppendError<T> {}
@@ -0,0 +1,47 @@
+use {BatchContainer, Batcher, BatcherHandle};
+
+/// Ends an iterator pipeline in a batcher, see `collect_into_batcher`.
+pub trait CollectIntoBatcher: Iterator + Sized {
+  /// Append every value of the iterator to `batcher` without a callback,
+  /// like `Batcher::append_iter`.
+  fn collect_into_batcher(self, batcher: &Batcher<Self::Item>);
+}
+
+impl<I> CollectIntoBatcher for I
+where
+  I: Iterator,
+  I::Item: Send + 'static,
+{
+  fn collect_into_batcher(self, batcher: &Batcher<I::Item>) {
+    batcher.append_iter(self, None)
+  }
+}
+
+/// Appends the values without a callback, see `Batcher::append_iter`.
+impl<T, C> Extend<T> for Batcher<T, C>
+where
+  T: Send + 'static,
+  C: BatchContainer<T> + Send + 'static,
+{
+  fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+    self.append_iter(iter, None)
+  }
+}
+
+/// Appends the values without a callback, see `Batcher::append_iter`.
+impl<T, C> Extend<T> for &Batcher<T, C>
+where
+  T: Send + 'static,
+  C: BatchContainer<T> + Send + 'static,
+{
+  fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+    self.append_iter(iter, None)
+  }
+}
+
+/// Appends the values without a callback, see `Batcher::append_iter`.
+impl<T: Send + 'static> Extend<T> for BatcherHandle<T> {
+  fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+    self.append_iter(iter, None)
+  }
+}
@@ -0,0 +1,116 @@
+use done::{Complete, Outcome};
+use std::sync::{Arc, Mutex};
+use {BatchError, BatchRunner, Done};
+
+type Sink<T> = Box<dyn BatchRunner<T> + Send>;
+
+/// Runner handing every batch to several runners, e.g. to write to an old
+/// and a new store during a migration.
+///
+/// The batch succeeds once the quorum of sinks, all of them by default,
+/// succeeded. It fails with `BatchError::Fanout` as soon as the quorum is out
+/// of reach, listing the sinks which failed so far. Sinks done after the
+/// batch is decided are ignored.
+pub struct FanoutRunner<T> {
+  sinks: Vec<Sink<T>>,
+  quorum: Option<usize>,
+}
+
+impl<T: Clone> FanoutRunner<T> {
+  /// Create a runner without sinks.
+  pub fn new() -> Self {
+    FanoutRunner {
+      sinks: Vec::new(),
+      quorum: None,
+    }
+  }
+
+  /// Add a runner to hand batches to. Sinks are numbered from 0 in the order
+  /// they were added, and run in that order.
+  pub fn sink<R>(mut self, runner: R) -> Self
+  where
+    R: BatchRunner<T> + Send + 'static,
+  {
+    self.sinks.push(Box::new(runner));
+    self
+  }
+
+  /// Succeed once `quorum` sinks succeeded, instead of all of them.
+  ///
+  /// # Panics
+  /// Panics if `quorum` is 0.
+  pub fn quorum(mut self, quorum: usize) -> Self {
+    assert!(quorum > 0, "fanout quorum must not be 0");
+    self.quorum = Some(quorum);
+    self
+  }
+}
+
+impl<T: Clone> Default for FanoutRunner<T> {
+  fn default() -> Self {
+    FanoutRunner::new()
+  }
+}
+
+impl<T: Clone> BatchRunner<T> for FanoutRunner<T> {
+  fn run(&mut self, batch: Vec<T>, done: Done) {
+    let sinks = self.sinks.len();
+    if sinks == 0 {
+      return done.ok();
+    }
+    let (id, meta) = (done.id().get(), done.meta());
+    let quorum = self.quorum.map_or(sinks, |quorum| quorum.min(sinks));
+    let shared = Arc::new(Mutex::new(Tally {
+      done: Some(done),
+      succeeded: 0,
+      errors: Vec::new(),
+      sinks,
+      quorum,
+    }));
+    for (sink, runner) in self.sinks.iter_mut().enumerate() {
+      let part = Arc::new(Part {
+        sink,
+        tally: shared.clone(),
+      });
+      runner.run(batch.clone(), Done::new(part, id, meta));
+    }
+  }
+}
+
+/// How the sinks of a batch did so far.
+struct Tally {
+  /// Taken once the batch is decided.
+  done: Option<Done>,
+  succeeded: usize,
+  errors: Vec<(usize, BatchError)>,
+  sinks: usize,
+  quorum: usize,
+}
+
+/// The share of one sink in a batch of a `FanoutRunner`.
+struct Part {
+  sink: usize,
+  tally: Arc<Mutex<Tally>>,
+}
+
+impl Complete for Part {
+  fn complete(&self, _id: u64, outcome: Outcome) {
+    let mut tally = self.tally.lock().unwrap();
+    match outcome.error() {
+      Some(err) => tally.errors.push((self.sink, err)),
+      None => tally.succeeded += 1,
+    }
+    let res = if tally.succeeded >= tally.quorum {
+      Ok(())
+    } else if tally.sinks - tally.errors.len() < tally.quorum {
+      Err(BatchError::Fanout(tally.errors.clone()))
+    } else {
+      return;
+    };
+    let done = tally.done.take();
+    drop(tally);
+    if let Some(done) = done {
+      done.finish(res);
+    }
+  }
+}
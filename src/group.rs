@@ -0,0 +1,144 @@
+use std::sync::{Arc, Condvar, Mutex};
+use {BatchContainer, BatchError, Batcher, BatcherMetrics, BatcherStats};
+
+/// A batcher of any value type, as held by a `BatcherGroup`.
+pub trait GroupMember: Send + Sync {
+  /// The name of the batcher, if it has one.
+  fn name(&self) -> Option<&str>;
+
+  /// Call `cb` once everything appended so far is done, see
+  /// `Batcher::flush_callback`.
+  fn flush_callback(&self, cb: Box<dyn FnOnce() + Send>);
+
+  /// Stop accepting values and run the rest, see `Batcher::close`.
+  fn close(&self) -> Result<(), BatchError>;
+
+  /// See `Batcher::stats`.
+  fn stats(&self) -> BatcherStats;
+}
+
+impl<T, C> GroupMember for Batcher<T, C>
+where
+  T: Send + 'static,
+  C: BatchContainer<T> + Send + 'static,
+{
+  fn name(&self) -> Option<&str> {
+    Batcher::name(self)
+  }
+
+  fn flush_callback(&self, cb: Box<dyn FnOnce() + Send>) {
+    Batcher::flush_callback(self, cb)
+  }
+
+  fn close(&self) -> Result<(), BatchError> {
+    Batcher::close(self)
+  }
+
+  fn stats(&self) -> BatcherStats {
+    Batcher::stats(self)
+  }
+}
+
+/// Batchers of possibly different value types which flush and close
+/// together, e.g. one per table that must all drain on shutdown.
+#[derive(Default)]
+pub struct BatcherGroup {
+  members: Mutex<Vec<Arc<dyn GroupMember>>>,
+}
+
+impl BatcherGroup {
+  /// Create an empty group.
+  pub fn new() -> Self {
+    BatcherGroup::default()
+  }
+
+  /// Add a batcher to the group.
+  pub fn add(&self, member: Arc<dyn GroupMember>) {
+    self.members.lock().unwrap().push(member);
+  }
+
+  /// How many batchers are in the group.
+  pub fn len(&self) -> usize {
+    self.members.lock().unwrap().len()
+  }
+
+  /// Whether the group holds no batchers.
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Block until every value appended so far to any batcher of the group is
+  /// done. The batchers drain at once rather than one after another.
+  pub fn flush_all(&self) {
+    let members = self.members();
+    let left = Arc::new((Mutex::new(members.len()), Condvar::new()));
+    for member in &members {
+      let left = left.clone();
+      member.flush_callback(Box::new(move || {
+        *left.0.lock().unwrap() -= 1;
+        left.1.notify_all();
+      }));
+    }
+    let mut count = left.0.lock().unwrap();
+    while *count > 0 {
+      count = left.1.wait(count).unwrap();
+    }
+  }
+
+  /// Close every batcher of the group, see `Batcher::close`. Returns the
+  /// first error of their final batches.
+  pub fn close_all(&self) -> Result<(), BatchError> {
+    self.flush_all();
+    let mut res = Ok(());
+    for member in self.members() {
+      if let Err(err) = member.close() {
+        res = res.and(Err(err));
+      }
+    }
+    res
+  }
+
+  /// The stats of every batcher of the group, with its name.
+  pub fn stats(&self) -> Vec<(Option<String>, BatcherStats)> {
+    self
+      .members()
+      .iter()
+      .map(|member| (member.name().map(String::from), member.stats()))
+      .collect()
+  }
+
+  /// The stats of the group as a whole: counters, running batches, their
+  /// values and rates add up, and the oldest pending value is the oldest of
+  /// any batcher.
+  pub fn total_stats(&self) -> BatcherStats {
+    let mut total = BatcherStats {
+      metrics: BatcherMetrics::default(),
+      running: 0,
+      in_flight_items: 0,
+      committed_items: 0,
+      items_per_second: 0.0,
+      oldest_pending: None,
+    };
+    for (_, stats) in self.stats() {
+      let (sum, add) = (&mut total.metrics, stats.metrics);
+      sum.appended += add.appended;
+      sum.dispatched += add.dispatched;
+      sum.dispatched_items += add.dispatched_items;
+      sum.completed += add.completed;
+      sum.failed += add.failed;
+      sum.callback_failures += add.callback_failures;
+      sum.pending += add.pending;
+      sum.in_flight += add.in_flight;
+      total.running += stats.running;
+      total.in_flight_items += stats.in_flight_items;
+      total.committed_items += stats.committed_items;
+      total.items_per_second += stats.items_per_second;
+      total.oldest_pending = total.oldest_pending.max(stats.oldest_pending);
+    }
+    total
+  }
+
+  fn members(&self) -> Vec<Arc<dyn GroupMember>> {
+    self.members.lock().unwrap().clone()
+  }
+}
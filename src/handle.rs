@@ -0,0 +1,203 @@
+use std::sync::{mpsc, Arc, Weak};
+use std::time::Duration;
+use {AppendError, BatchError, BatchTicket, Batcher, CbOption, IdCbOption};
+use {CancelHandle, ItemsCbOption, Priority};
+
+/// Cloneable handle for appending to a batcher from many threads.
+///
+/// Handles only produce values; the batcher alone calls the run function,
+/// from whichever thread starts a batch.
+pub struct BatcherHandle<T> {
+  batcher: Arc<Batcher<T>>,
+}
+
+impl<T: Send + 'static> BatcherHandle<T> {
+  /// Accept an array of values and a callback, see `Batcher::append`.
+  pub fn append(&self, val: Vec<T>, cb: CbOption) {
+    self.batcher.append(val, cb)
+  }
+
+  /// Accept any iterator of values, see `Batcher::append_iter`.
+  pub fn append_iter<I>(&self, val: I, cb: CbOption)
+  where
+    I: IntoIterator<Item = T>,
+  {
+    self.batcher.append_iter(val, cb)
+  }
+
+  /// Accept a single value, see `Batcher::append_one`.
+  pub fn append_one(&self, val: T, cb: CbOption) {
+    self.batcher.append_one(val, cb)
+  }
+
+  /// Accept an array of values and a callback getting their batch id, see
+  /// `Batcher::append_with_id`.
+  pub fn append_with_id(&self, val: Vec<T>, cb: IdCbOption) {
+    self.batcher.append_with_id(val, cb)
+  }
+
+  /// Accept an array of values with a priority, see
+  /// `Batcher::append_with_priority`.
+  pub fn append_with_priority(
+    &self,
+    val: Vec<T>,
+    priority: Priority,
+    cb: CbOption,
+  ) {
+    self.batcher.append_with_priority(val, priority, cb)
+  }
+
+  /// Accept an array of values if there is room, see `Batcher::try_append`.
+  pub fn try_append(
+    &self,
+    val: Vec<T>,
+    cb: CbOption,
+  ) -> Result<(), AppendError<T>> {
+    self.batcher.try_append(val, cb)
+  }
+
+  /// Accept an array of values once there is room, see
+  /// `Batcher::append_blocking`.
+  pub fn append_blocking(
+    &self,
+    val: Vec<T>,
+    cb: CbOption,
+  ) -> Result<(), AppendError<T>> {
+    self.batcher.append_blocking(val, cb)
+  }
+
+  /// Accept an array of values and a per-value callback, see
+  /// `Batcher::append_each`.
+  pub fn append_each(&self, val: Vec<T>, cb: ItemsCbOption) {
+    self.batcher.append_each(val, cb)
+  }
+
+  /// Accept an array of values, see `Batcher::append_ticket`.
+  pub fn append_ticket(&self, val: Vec<T>) -> BatchTicket {
+    self.batcher.append_ticket(val)
+  }
+
+  /// Accept an array of values and block until they ran, see
+  /// `Batcher::append_and_wait`.
+  pub fn append_and_wait(&self, val: Vec<T>) -> Result<(), BatchError> {
+    self.batcher.append_and_wait(val)
+  }
+
+  /// Accept an array of values which expire, see `Batcher::append_with_ttl`.
+  pub fn append_with_ttl(&self, val: Vec<T>, ttl: Duration, cb: CbOption) {
+    self.batcher.append_with_ttl(val, ttl, cb)
+  }
+
+  /// Accept an array of values which may be cancelled, see
+  /// `Batcher::append_cancellable`.
+  pub fn append_cancellable(&self, val: Vec<T>, cb: CbOption) -> CancelHandle {
+    self.batcher.append_cancellable(val, cb)
+  }
+
+  /// Cancel every value waiting to run, see `Batcher::cancel_pending`.
+  pub fn cancel_pending(&self) -> usize {
+    self.batcher.cancel_pending()
+  }
+
+  /// Take every value waiting to run, see `Batcher::take_pending`.
+  pub fn take_pending(&self) -> Vec<T> {
+    self.batcher.take_pending()
+  }
+
+  /// Block until every value appended so far has run, see `Batcher::flush`.
+  pub fn flush(&self) {
+    self.batcher.flush()
+  }
+
+  /// Call `cb` once every value appended so far has run, see
+  /// `Batcher::flush_callback`.
+  pub fn flush_callback(&self, cb: Box<dyn FnOnce() + Send>) {
+    self.batcher.flush_callback(cb)
+  }
+
+  /// Stop starting batches, see `Batcher::pause`.
+  pub fn pause(&self) {
+    self.batcher.pause()
+  }
+
+  /// Start batches again, see `Batcher::resume`.
+  pub fn resume(&self) {
+    self.batcher.resume()
+  }
+
+  /// Stop accepting values and drain the batcher, see `Batcher::close`.
+  pub fn close(&self) -> Result<(), BatchError> {
+    self.batcher.close()
+  }
+
+  /// Create a handle which does not keep the batcher alive.
+  pub fn downgrade(&self) -> WeakBatcherHandle<T> {
+    WeakBatcherHandle {
+      batcher: Arc::downgrade(&self.batcher),
+    }
+  }
+}
+
+impl<T> Clone for BatcherHandle<T> {
+  fn clone(&self) -> Self {
+    BatcherHandle {
+      batcher: self.batcher.clone(),
+    }
+  }
+}
+
+/// Handle for appending to a batcher without keeping it alive, see
+/// `BatcherHandle::downgrade`, e.g. for background producers which should
+/// not hold up shutdown. Once the batcher is dropped, appends fail with
+/// `BatchError::Closed`.
+pub struct WeakBatcherHandle<T> {
+  batcher: Weak<Batcher<T>>,
+}
+
+impl<T: Send + 'static> WeakBatcherHandle<T> {
+  /// Accept an array of values and a callback, see `Batcher::append`.
+  pub fn append(&self, val: Vec<T>, cb: CbOption) {
+    match self.batcher.upgrade() {
+      Some(batcher) => batcher.append(val, cb),
+      None => {
+        if let Some(cb) = cb {
+          cb(Err(BatchError::Closed))
+        }
+      }
+    }
+  }
+
+  /// Accept an array of values, see `Batcher::append_ticket`.
+  pub fn append_ticket(&self, val: Vec<T>) -> BatchTicket {
+    match self.batcher.upgrade() {
+      Some(batcher) => batcher.append_ticket(val),
+      // The sender is gone, so the ticket resolves as closed.
+      None => BatchTicket::new(mpsc::channel().1),
+    }
+  }
+
+  /// Accept an array of values and block until they ran, see
+  /// `Batcher::append_and_wait`.
+  pub fn append_and_wait(&self, val: Vec<T>) -> Result<(), BatchError> {
+    self.append_ticket(val).wait()
+  }
+
+  /// A handle keeping the batcher alive, unless it is gone already.
+  pub fn upgrade(&self) -> Option<BatcherHandle<T>> {
+    self.batcher.upgrade().map(BatcherHandle::from)
+  }
+}
+
+impl<T> Clone for WeakBatcherHandle<T> {
+  fn clone(&self) -> Self {
+    WeakBatcherHandle {
+      batcher: self.batcher.clone(),
+    }
+  }
+}
+
+impl<T> From<Arc<Batcher<T>>> for BatcherHandle<T> {
+  fn from(batcher: Arc<Batcher<T>>) -> Self {
+    BatcherHandle { batcher }
+  }
+}
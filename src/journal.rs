@@ -0,0 +1,98 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::Mutex;
+
+type Encode<T> = Box<dyn Fn(&T) -> Vec<u8> + Send + Sync>;
+
+/// Write-ahead log of appended values, see `BatcherBuilder::journal`.
+///
+/// Every appended value is written to the file before it is queued, and the
+/// file is cleared whenever the batcher has nothing left to run. Values still
+/// in the file when it is opened, e.g. after a crash, are appended again once
+/// the batcher is built, as are values a batcher dropped without running
+/// them, or whose batches timed out as it was dropped. A value may therefore
+/// run more than once, but is not lost if the process dies before its batch
+/// is done. Writes are not synced to disk.
+///
+/// A journal belongs to a single batcher.
+pub struct Journal<T> {
+  file: Mutex<File>,
+  encode: Encode<T>,
+  replay: Mutex<Vec<T>>,
+}
+
+impl<T> Journal<T> {
+  /// Open the journal at `path`, creating it if needed, and read back the
+  /// values left in it. `encode` and `decode` turn a value into bytes and
+  /// back; a value `decode` rejects is skipped.
+  pub fn open<P, E, D>(path: P, encode: E, decode: D) -> io::Result<Self>
+  where
+    P: AsRef<Path>,
+    E: Fn(&T) -> Vec<u8> + Send + Sync + 'static,
+    D: Fn(&[u8]) -> Option<T>,
+  {
+    let mut file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(path)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let mut replay = Vec::new();
+    let mut rest = &bytes[..];
+    // A record cut short by a crash ends the journal.
+    while rest.len() >= 4 {
+      let mut len = [0; 4];
+      len.copy_from_slice(&rest[..4]);
+      let len = u32::from_le_bytes(len) as usize;
+      if rest.len() < 4 + len {
+        break;
+      }
+      replay.extend(decode(&rest[4..4 + len]));
+      rest = &rest[4 + len..];
+    }
+    let end = (bytes.len() - rest.len()) as u64;
+    file.set_len(end)?;
+    file.seek(SeekFrom::Start(end))?;
+    Ok(Journal {
+      file: Mutex::new(file),
+      encode: Box::new(encode),
+      replay: Mutex::new(replay),
+    })
+  }
+
+  /// Write `values` to the file.
+  pub(crate) fn record(&self, values: &[T]) -> io::Result<()> {
+    let mut records = Vec::new();
+    for val in values {
+      let bytes = (self.encode)(val);
+      records.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+      records.extend(bytes);
+    }
+    let mut file = self.file.lock().unwrap();
+    file.write_all(&records)?;
+    file.flush()
+  }
+
+  /// Forget every value written so far.
+  pub(crate) fn clear(&self) -> io::Result<()> {
+    let mut file = self.file.lock().unwrap();
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0)).map(|_| ())
+  }
+
+  /// Take the values read back when opening the journal.
+  pub(crate) fn take_replay(&self) -> Vec<T> {
+    mem::take(&mut *self.replay.lock().unwrap())
+  }
+}
+
+impl<T> fmt::Debug for Journal<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Journal").field("file", &self.file).finish()
+  }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use {BatchError, BatchTicket, Batcher, BatcherBuilder, CbOption, ConfigError};
+use {Done, ItemsCbOption};
+
+type KeyedRun<K, T> = Arc<dyn Fn(&K, Vec<T>, Done) + Send + Sync>;
+
+/// Batcher grouping values by a key, e.g. a shard or a table.
+///
+/// Every key has its own batches, which run independently of the other
+/// keys'. The run function is shared by all keys, and may run for several
+/// keys at once.
+pub struct KeyedBatcher<K, T> {
+  batchers: Mutex<HashMap<K, Arc<Batcher<T>>>>,
+  builder: BatcherBuilder<T>,
+  run: KeyedRun<K, T>,
+  closed: AtomicBool,
+}
+
+impl<K, T> KeyedBatcher<K, T>
+where
+  K: Eq + Hash + Clone + Send + Sync + 'static,
+  T: Send + 'static,
+{
+  /// Create a new keyed batcher with a run function.
+  pub fn new<F>(run: F) -> Self
+  where
+    F: Fn(&K, Vec<T>, Done) + Send + Sync + 'static,
+  {
+    KeyedBatcher {
+      batchers: Mutex::new(HashMap::new()),
+      builder: BatcherBuilder::new().shared_timer(),
+      run: Arc::new(run),
+      closed: AtomicBool::new(false),
+    }
+  }
+
+  /// Create a new keyed batcher, configuring the batcher of every key with
+  /// `builder`. Settings kept by the timer, such as `max_batch_delay`, are
+  /// kept for all keys by one thread, which also starts the batches due, so
+  /// a run function blocking there holds up the timers of the other keys.
+  pub fn with_builder<F>(
+    builder: BatcherBuilder<T>,
+    run: F,
+  ) -> Result<Self, ConfigError>
+  where
+    F: Fn(&K, Vec<T>, Done) + Send + Sync + 'static,
+  {
+    builder.validate()?;
+    Ok(KeyedBatcher {
+      batchers: Mutex::new(HashMap::new()),
+      builder: builder.shared_timer(),
+      run: Arc::new(run),
+      closed: AtomicBool::new(false),
+    })
+  }
+
+  /// Accept an array of values for `key` and a callback, see
+  /// `Batcher::append`.
+  pub fn append(&self, key: K, val: Vec<T>, cb: CbOption) {
+    self.batcher(key).append(val, cb)
+  }
+
+  /// Accept an array of values for `key` and a per-value callback, see
+  /// `Batcher::append_each`.
+  pub fn append_each(&self, key: K, val: Vec<T>, cb: ItemsCbOption) {
+    self.batcher(key).append_each(val, cb)
+  }
+
+  /// Accept an array of values for `key`, see `Batcher::append_ticket`.
+  pub fn append_ticket(&self, key: K, val: Vec<T>) -> BatchTicket {
+    self.batcher(key).append_ticket(val)
+  }
+
+  /// Block until every value appended so far, for any key, has run.
+  pub fn flush(&self) {
+    for batcher in self.batchers() {
+      batcher.flush();
+    }
+  }
+
+  /// Close the batcher of every key, see `Batcher::close`. Returns the first
+  /// error of their final batches. Keys used later start out closed, too.
+  pub fn close(&self) -> Result<(), BatchError> {
+    self.closed.store(true, Ordering::SeqCst);
+    let mut res = Ok(());
+    for batcher in self.batchers() {
+      if let Err(err) = batcher.close() {
+        res = res.and(Err(err));
+      }
+    }
+    res
+  }
+
+  /// The keys which currently have a batcher.
+  pub fn keys(&self) -> Vec<K> {
+    self.batchers.lock().unwrap().keys().cloned().collect()
+  }
+
+  /// Forget the batchers of keys with nothing running or waiting to run.
+  /// A batcher about to get values, or in use otherwise, is kept.
+  pub fn prune(&self) {
+    self.batchers.lock().unwrap().retain(|_, batcher| {
+      // Appends hold a clone, so the key cannot get a second batcher.
+      Arc::strong_count(batcher) > 1 || !batcher.is_idle()
+    });
+  }
+
+  fn batcher(&self, key: K) -> Arc<Batcher<T>> {
+    let mut batchers = self.batchers.lock().unwrap();
+    if let Some(batcher) = batchers.get(&key) {
+      return batcher.clone();
+    }
+    let run = self.run.clone();
+    let name = key.clone();
+    let batcher = self
+      .builder
+      .clone()
+      .build(move |val: Vec<T>, done: Done| run(&name, val, done));
+    if self.closed.load(Ordering::SeqCst) {
+      let _ = batcher.close();
+    }
+    batchers.insert(key, batcher.clone());
+    batcher
+  }
+
+  fn batchers(&self) -> Vec<Arc<Batcher<T>>> {
+    self.batchers.lock().unwrap().values().cloned().collect()
+  }
+}
@@ -0,0 +1,479 @@
+use batch::Meta;
+use clock::SharedClock;
+use done::{Complete, Outcome};
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use timer::{Registration, Timed, Timer};
+use {BatchError, BatchRunner, Clock, Done, MetricsSink};
+
+/// Wraps a runner with extra behavior, e.g. `RetryLayer`.
+///
+/// Apply it with `BatchRunner::layer`. Layers stack: the last one applied
+/// sees every batch first.
+pub trait Layer<R> {
+  /// The wrapping runner.
+  type Runner;
+
+  /// Wrap `inner`.
+  fn layer(&self, inner: R) -> Self::Runner;
+}
+
+/// Runs failed batches again, up to a number of times.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryLayer {
+  retries: usize,
+}
+
+impl RetryLayer {
+  /// Run each failed batch again up to `retries` times. A batch with any
+  /// failed value counts as failed, and runs again without the values at
+  /// its start which succeeded or were committed, see `Done::progress`.
+  pub fn new(retries: usize) -> Self {
+    RetryLayer { retries }
+  }
+}
+
+impl<R> Layer<R> for RetryLayer {
+  type Runner = Retry<R>;
+
+  fn layer(&self, inner: R) -> Retry<R> {
+    Retry {
+      inner: Arc::new(Mutex::new(inner)),
+      retries: self.retries,
+    }
+  }
+}
+
+/// Runner created by `RetryLayer`.
+pub struct Retry<R> {
+  inner: Arc<Mutex<R>>,
+  retries: usize,
+}
+
+impl<T, R> BatchRunner<T> for Retry<R>
+where
+  T: Clone + Send + 'static,
+  R: BatchRunner<T> + Send + 'static,
+{
+  fn run(&mut self, batch: Vec<T>, done: Done) {
+    let attempt = Arc::new_cyclic(|this| Attempt {
+      this: this.clone(),
+      id: done.id().get(),
+      meta: done.meta(),
+      inner: self.inner.clone(),
+      batch: Mutex::new(batch),
+      state: Mutex::new(AttemptState {
+        done: Some(done),
+        retries: self.retries,
+        running: false,
+        again: false,
+        committed: 0,
+        skipped: 0,
+      }),
+    });
+    attempt.start();
+  }
+}
+
+/// One batch going through a `Retry` runner.
+struct Attempt<R, T> {
+  this: Weak<Attempt<R, T>>,
+  id: u64,
+  meta: Meta,
+  inner: Arc<Mutex<R>>,
+  batch: Mutex<Vec<T>>,
+  state: Mutex<AttemptState>,
+}
+
+struct AttemptState {
+  done: Option<Done>,
+  retries: usize,
+  /// Whether the inner runner is being called, like `Batcher::dispatch`.
+  running: bool,
+  /// Whether the batch failed while running, and runs again once it returns.
+  again: bool,
+  /// Values the current run reported done, see `Done::progress`.
+  committed: usize,
+  /// Values done by earlier runs, and left out of the batch since.
+  skipped: usize,
+}
+
+impl<R, T> Attempt<R, T>
+where
+  T: Clone + Send + 'static,
+  R: BatchRunner<T> + Send + 'static,
+{
+  fn start(self: Arc<Self>) {
+    loop {
+      self.state.lock().unwrap().running = true;
+      let done = Done::new(self.clone(), self.id, self.meta);
+      let batch = self.batch.lock().unwrap().clone();
+      self.inner.lock().unwrap().run(batch, done);
+      let mut state = self.state.lock().unwrap();
+      state.running = false;
+      if !state.again {
+        return;
+      }
+      state.again = false;
+    }
+  }
+}
+
+impl<R, T> Complete for Attempt<R, T>
+where
+  T: Clone + Send + 'static,
+  R: BatchRunner<T> + Send + 'static,
+{
+  fn complete(&self, _id: u64, outcome: Outcome) {
+    let mut state = self.state.lock().unwrap();
+    let len = self.batch.lock().unwrap().len();
+    let outcome = outcome.commit(mem::take(&mut state.committed), len);
+    if outcome.error().is_some() && state.retries > 0 {
+      state.retries -= 1;
+      let committed = outcome.committed().min(len);
+      self.batch.lock().unwrap().drain(..committed);
+      state.skipped += committed;
+      if state.running {
+        state.again = true;
+        return;
+      }
+      drop(state);
+      if let Some(this) = self.this.upgrade() {
+        this.start();
+      }
+      return;
+    }
+    if let Some(done) = state.done.take() {
+      let outcome = outcome.resume(state.skipped, len);
+      drop(state);
+      done.complete(outcome);
+    }
+  }
+
+  fn progress(&self, _id: u64, committed: usize) {
+    let mut state = self.state.lock().unwrap();
+    state.committed = state.committed.max(committed);
+    if let Some(ref done) = state.done {
+      done.progress(state.skipped + committed);
+    }
+  }
+}
+
+/// Fails batches with `BatchError::Timeout` which are not done in time.
+#[derive(Debug, Clone)]
+pub struct TimeoutLayer {
+  timeout: Duration,
+  timer: Timer,
+}
+
+impl TimeoutLayer {
+  /// Fail each batch not done within `timeout` of being run or of last
+  /// reporting progress. Signalling it later has no effect. The batches of
+  /// every runner made by the layer are watched by one thread.
+  pub fn new(timeout: Duration) -> Self {
+    TimeoutLayer {
+      timeout,
+      timer: Timer::new(String::from("batch-timeout"), SharedClock::default()),
+    }
+  }
+
+  /// Take the time from `clock` rather than the system, like
+  /// `BatcherBuilder::clock`. Layers cloned from this one afterwards share
+  /// its thread.
+  pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+    let clock = SharedClock(Arc::new(clock));
+    self.timer = Timer::new(String::from("batch-timeout"), clock);
+    self
+  }
+}
+
+impl<R> Layer<R> for TimeoutLayer {
+  type Runner = Timeout<R>;
+
+  fn layer(&self, inner: R) -> Timeout<R> {
+    Timeout {
+      inner,
+      timeout: self.timeout,
+      timer: self.timer.clone(),
+    }
+  }
+}
+
+/// Runner created by `TimeoutLayer`.
+pub struct Timeout<R> {
+  inner: R,
+  timeout: Duration,
+  timer: Timer,
+}
+
+impl<T, R: BatchRunner<T>> BatchRunner<T> for Timeout<R> {
+  fn run(&mut self, batch: Vec<T>, done: Done) {
+    let (id, meta) = (done.id().get(), done.meta());
+    let clock = self.timer.clock().clone();
+    let watched = Arc::new(Watched {
+      done: Mutex::new(Some(done)),
+      deadline: Mutex::new(clock.now() + self.timeout),
+      timeout: self.timeout,
+      clock,
+      registration: Mutex::new(None),
+    });
+    let weak: Weak<dyn Timed> = Arc::downgrade(&watched) as Weak<Watched>;
+    *watched.registration.lock().unwrap() = Some(self.timer.register(weak));
+    self.inner.run(batch, Done::new(watched, id, meta));
+  }
+}
+
+/// A batch watched by a `Timeout` runner.
+struct Watched {
+  done: Mutex<Option<Done>>,
+  deadline: Mutex<Instant>,
+  timeout: Duration,
+  clock: SharedClock,
+  /// Dropped once the batch is done, so the timer forgets it.
+  registration: Mutex<Option<Registration>>,
+}
+
+impl Complete for Watched {
+  fn complete(&self, _id: u64, outcome: Outcome) {
+    self.registration.lock().unwrap().take();
+    let done = self.done.lock().unwrap().take();
+    if let Some(done) = done {
+      done.complete(outcome);
+    }
+  }
+
+  fn progress(&self, _id: u64, committed: usize) {
+    // The timer finds the later deadline once the earlier one is due.
+    *self.deadline.lock().unwrap() = self.clock.now() + self.timeout;
+    if let Some(ref done) = *self.done.lock().unwrap() {
+      done.progress(committed);
+    }
+  }
+}
+
+impl Timed for Watched {
+  fn on_timer(&self) -> Option<Instant> {
+    let deadline = *self.deadline.lock().unwrap();
+    if deadline > self.clock.now() {
+      return Some(deadline);
+    }
+    self.registration.lock().unwrap().take();
+    let done = self.done.lock().unwrap().take();
+    if let Some(done) = done {
+      done.err(BatchError::Timeout);
+    }
+    None
+  }
+}
+
+/// Reports every batch going through a runner to a `MetricsSink`.
+#[derive(Debug)]
+pub struct MetricsLayer<S> {
+  sink: Arc<S>,
+  clock: SharedClock,
+}
+
+impl<S> Clone for MetricsLayer<S> {
+  fn clone(&self) -> Self {
+    MetricsLayer {
+      sink: self.sink.clone(),
+      clock: self.clock.clone(),
+    }
+  }
+}
+
+impl<S: MetricsSink> MetricsLayer<S> {
+  /// Report to `sink`. Batches go by the ids of the batcher, see `Done::id`.
+  /// How many values wait is not known to a runner, so `on_dispatch` always
+  /// gets a pending count of 0.
+  pub fn new(sink: S) -> Self {
+    MetricsLayer {
+      sink: Arc::new(sink),
+      clock: SharedClock::default(),
+    }
+  }
+
+  /// Time the batches by `clock` rather than the system, like
+  /// `BatcherBuilder::clock`.
+  pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+    self.clock = SharedClock(Arc::new(clock));
+    self
+  }
+}
+
+impl<R, S> Layer<R> for MetricsLayer<S> {
+  type Runner = Metrics<R, S>;
+
+  fn layer(&self, inner: R) -> Metrics<R, S> {
+    Metrics {
+      inner,
+      sink: self.sink.clone(),
+      clock: self.clock.clone(),
+    }
+  }
+}
+
+/// Runner created by `MetricsLayer`.
+pub struct Metrics<R, S> {
+  inner: R,
+  sink: Arc<S>,
+  clock: SharedClock,
+}
+
+impl<T, R, S> BatchRunner<T> for Metrics<R, S>
+where
+  R: BatchRunner<T>,
+  S: MetricsSink + 'static,
+{
+  fn run(&mut self, batch: Vec<T>, done: Done) {
+    let (id, meta) = (done.id().get(), done.meta());
+    // The pending count is unknown here, see `MetricsLayer::new`.
+    self.sink.on_dispatch(id, batch.len(), 0);
+    let measured = Arc::new(Measured {
+      sink: self.sink.clone(),
+      id,
+      size: batch.len(),
+      since: self.clock.now(),
+      clock: self.clock.clone(),
+      done: Mutex::new(Some(done)),
+    });
+    self.inner.run(batch, Done::new(measured, id, meta));
+  }
+}
+
+/// A batch measured by a `Metrics` runner.
+struct Measured<S> {
+  sink: Arc<S>,
+  id: u64,
+  size: usize,
+  since: Instant,
+  clock: SharedClock,
+  done: Mutex<Option<Done>>,
+}
+
+impl<S: MetricsSink> Complete for Measured<S> {
+  fn complete(&self, _id: u64, outcome: Outcome) {
+    let done = self.done.lock().unwrap().take();
+    if let Some(done) = done {
+      let res = match outcome.error() {
+        Some(err) => Err(err),
+        None => Ok(()),
+      };
+      let elapsed = self.clock.now().saturating_duration_since(self.since);
+      self.sink.on_complete(self.id, self.size, elapsed, &res);
+      done.complete(outcome);
+    }
+  }
+
+  fn progress(&self, _id: u64, committed: usize) {
+    if let Some(ref done) = *self.done.lock().unwrap() {
+      done.progress(committed);
+    }
+  }
+}
+
+/// Hands the values of failed batches to a function, e.g. to store them
+/// elsewhere. Applied after a `RetryLayer`, it gets the values once the
+/// retries are used up.
+pub struct DeadLetterLayer<F> {
+  dead_letter: Arc<F>,
+}
+
+impl<F> Clone for DeadLetterLayer<F> {
+  fn clone(&self) -> Self {
+    DeadLetterLayer {
+      dead_letter: self.dead_letter.clone(),
+    }
+  }
+}
+
+impl<F> DeadLetterLayer<F> {
+  /// Call `dead_letter` with the failed values of each failed batch and its
+  /// first error, before the callbacks of the batch get the error. With one
+  /// result per value only the values which failed are handed over.
+  pub fn new(dead_letter: F) -> Self {
+    DeadLetterLayer {
+      dead_letter: Arc::new(dead_letter),
+    }
+  }
+}
+
+impl<R, F> Layer<R> for DeadLetterLayer<F> {
+  type Runner = DeadLetter<R, F>;
+
+  fn layer(&self, inner: R) -> DeadLetter<R, F> {
+    DeadLetter {
+      inner,
+      dead_letter: self.dead_letter.clone(),
+    }
+  }
+}
+
+/// Runner created by `DeadLetterLayer`.
+pub struct DeadLetter<R, F> {
+  inner: R,
+  dead_letter: Arc<F>,
+}
+
+impl<T, R, F> BatchRunner<T> for DeadLetter<R, F>
+where
+  T: Clone + Send + 'static,
+  R: BatchRunner<T>,
+  F: Fn(Vec<T>, BatchError) + Send + Sync + 'static,
+{
+  fn run(&mut self, batch: Vec<T>, done: Done) {
+    let (id, meta) = (done.id().get(), done.meta());
+    let letter = Arc::new(Letter {
+      values: Mutex::new(batch.clone()),
+      dead_letter: self.dead_letter.clone(),
+      done: Mutex::new(Some(done)),
+      committed: Mutex::new(0),
+    });
+    self.inner.run(batch, Done::new(letter, id, meta));
+  }
+}
+
+/// A batch whose values go to a `DeadLetter` function if it fails.
+struct Letter<T, F> {
+  values: Mutex<Vec<T>>,
+  dead_letter: Arc<F>,
+  done: Mutex<Option<Done>>,
+  /// Values reported done, which are not dead letters if the batch fails.
+  committed: Mutex<usize>,
+}
+
+impl<T, F> Complete for Letter<T, F>
+where
+  T: Send,
+  F: Fn(Vec<T>, BatchError) + Send + Sync,
+{
+  fn complete(&self, _id: u64, outcome: Outcome) {
+    let done = match self.done.lock().unwrap().take() {
+      Some(done) => done,
+      None => return,
+    };
+    let committed = *self.committed.lock().unwrap();
+    let len = self.values.lock().unwrap().len();
+    let outcome = outcome.commit(committed, len);
+    if let Some(err) = outcome.error() {
+      let values = mem::take(&mut *self.values.lock().unwrap());
+      let failed = values
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| outcome.failed(&(*i..*i + 1)))
+        .map(|(_, val)| val)
+        .collect();
+      (self.dead_letter)(failed, err);
+    }
+    done.complete(outcome);
+  }
+
+  fn progress(&self, _id: u64, committed: usize) {
+    let mut done_so_far = self.committed.lock().unwrap();
+    *done_so_far = (*done_so_far).max(committed);
+    if let Some(ref done) = *self.done.lock().unwrap() {
+      done.progress(committed);
+    }
+  }
+}
@@ -1,26 +1,30 @@
 #![cfg_attr(test, deny(warnings))]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! ## Example
-//! ```rust
+#![cfg_attr(feature = "async", doc = "```rust")]
+#![cfg_attr(not(feature = "async"), doc = "```ignore")]
 //! extern crate atomic_batcher;
 //! extern crate tokio;
 
 //! use atomic_batcher::*;
-//! use std::sync::Arc;
+//! use std::sync::mpsc;
 //! use std::time::{Duration, Instant};
 //! use tokio::prelude::*;
 //! use tokio::timer::Delay;
 
 //! fn main() {
 //!   let when = Instant::now() + Duration::from_millis(2000);
-//!   let run = move |val: Vec<u64>, _batcher: &Batcher<u64>| -> () {
-//!     println!("{:?}", val);  
+//!   let (tx, rx) = mpsc::channel();
+//!   let run = move |val: Vec<u64>, done: Done| {
+//!     println!("{:?}", val);
+//!     tx.send(done).unwrap();
 //!   };
 //!
 //!   // Create a batcher with a run function which will be called  
 //!   // when batcher's inner state `running` is OFF and inner state `pending_batch`
 //!   // is not empty.
-//!   let batcher = Batcher::new(Box::new(run));
+//!   let batcher = Batcher::new(run);
 //!
 //!   // Before this first append, batcher's inner state `running` is initial OFF,
 //!   // so batcher will call the run function with the append value directly,
@@ -34,12 +38,13 @@
 //!   batcher.append(vec![7, 8, 9], None);
 //!
 //!   // Now `pending_batch` is vec![4, 5, 6, 7, 8, 9].
-//!   // After 2 seconds, batcher.done get called which will turn `running` to OFF,
+//!   // After 2 seconds, the first batch is signalled done which will turn `running` to OFF,
 //!   // then call run function with `pending_batch`.
 //!   // Finally turn `running` to ON again.
+//!   let done = rx.recv().unwrap();
 //!   let task = Delay::new(when)
 //!   .and_then(move |_| {
-//!     batcher.done(Ok(()));
+//!     done.ok();
 //!     Ok(())
 //!   })
 //!   .map_err(|e| panic!("delay errored; err={:?}", e));
@@ -53,66 +58,2591 @@
 //! // two seconds later
 //! [4, 5, 6, 7, 8, 9]
 //! ```
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+#[macro_use]
+extern crate alloc;
+// Under `no_std` it is in the crate root already.
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "async")]
+extern crate tokio;
 
-type Cb = Box<Fn(Result<(), &str>) -> () + Send + Sync>;
-/// Describing optional batched callback function
+#[cfg(feature = "async")]
+mod async_batcher;
+#[cfg(feature = "std")]
+mod batch;
+#[cfg(feature = "async")]
+mod batched_fn;
+#[cfg(feature = "std")]
+mod builder;
+#[cfg(feature = "std")]
+mod cancel;
+#[cfg(feature = "std")]
+mod check;
+#[cfg(feature = "std")]
+mod chunk;
+#[cfg(feature = "std")]
+mod clock;
+#[cfg(feature = "std")]
+mod compress;
+#[cfg(feature = "std")]
+mod container;
+mod core_batcher;
+#[cfg(feature = "std")]
+mod done;
+#[cfg(feature = "std")]
+mod error;
+#[cfg(feature = "std")]
+mod extend;
+#[cfg(feature = "std")]
+mod fanout;
+#[cfg(feature = "std")]
+mod group;
+#[cfg(feature = "std")]
+mod handle;
+#[cfg(feature = "std")]
+mod journal;
+#[cfg(feature = "std")]
+mod keyed;
+#[cfg(feature = "std")]
+mod layer;
+#[cfg(feature = "std")]
+mod local;
+#[cfg(feature = "std")]
+mod logging;
+#[cfg(feature = "std")]
+mod manual;
+#[cfg(feature = "std")]
+mod mapped;
+#[cfg(feature = "std")]
+mod metrics;
+#[cfg(feature = "std")]
+mod policy;
+#[cfg(feature = "std")]
+mod runner;
+#[cfg(feature = "async")]
+mod runtime;
+#[cfg(feature = "std")]
+mod scoped;
+#[cfg(feature = "std")]
+mod segmented;
+#[cfg(feature = "std")]
+mod sharded;
+#[cfg(feature = "std")]
+mod snapshot;
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "std")]
+mod ticket;
+#[cfg(feature = "std")]
+mod timer;
+#[cfg(feature = "async")]
+mod tokio_batcher;
+#[cfg(feature = "std")]
+mod watch;
+
+#[cfg(feature = "async")]
+pub use async_batcher::{Appended, AsyncBatcher};
+#[cfg(feature = "std")]
+pub use batch::{Batch, FlushOutcome, IdempotencyKey, PollOutcome, Trigger};
+#[cfg(feature = "async")]
+pub use batched_fn::{BatchedFn, Call};
+#[cfg(feature = "std")]
+pub use builder::{AdaptivePolicy, BatcherBuilder, ChunkErrors};
+#[cfg(feature = "std")]
+pub use builder::{CircuitBreaker, DropPolicy};
+#[cfg(feature = "std")]
+pub use cancel::{CancelHandle, CancellationToken};
+#[cfg(feature = "std")]
+pub use clock::{Clock, SystemClock};
+#[cfg(feature = "std")]
+pub use compress::{Codec, CompressedSize};
+#[cfg(feature = "std")]
+pub use container::BatchContainer;
+pub use core_batcher::{CoreBatcher, CoreCbOption, Signal};
+#[cfg(feature = "std")]
+pub use done::{BatchId, Done};
+#[cfg(feature = "std")]
+pub use error::{AppendError, BatchError, ConfigError};
+#[cfg(feature = "std")]
+pub use extend::CollectIntoBatcher;
+#[cfg(feature = "std")]
+pub use fanout::FanoutRunner;
+#[cfg(feature = "std")]
+pub use group::{BatcherGroup, GroupMember};
+#[cfg(feature = "std")]
+pub use handle::{BatcherHandle, WeakBatcherHandle};
+#[cfg(feature = "std")]
+pub use journal::Journal;
+#[cfg(feature = "std")]
+pub use keyed::KeyedBatcher;
+#[cfg(feature = "std")]
+pub use layer::{DeadLetter, DeadLetterLayer, Layer, Metrics, MetricsLayer};
+#[cfg(feature = "std")]
+pub use layer::{Retry, RetryLayer};
+#[cfg(feature = "std")]
+pub use layer::{Timeout, TimeoutLayer};
+#[cfg(feature = "std")]
+pub use local::{LocalBatcher, LocalCbOption, LocalDone};
+#[cfg(feature = "std")]
+pub use manual::ManualBatcher;
+#[cfg(feature = "std")]
+pub use mapped::MappedBatcher;
+#[cfg(feature = "std")]
+pub use metrics::{BatcherMetrics, BatcherStats, MetricsSink};
+#[cfg(feature = "std")]
+pub use policy::{Decision, FlushPolicy};
+#[cfg(feature = "std")]
+pub use runner::BatchRunner;
+#[cfg(feature = "async")]
+pub use runtime::{Runtime, RuntimeFuture, TokioRuntime};
+#[cfg(feature = "std")]
+pub use scoped::ScopedBatcher;
+#[cfg(feature = "std")]
+pub use segmented::SegmentedQueue;
+#[cfg(feature = "std")]
+pub use sharded::ShardedBatcher;
+#[cfg(feature = "std")]
+pub use snapshot::PendingSnapshot;
+#[cfg(feature = "async")]
+pub use stream::{BatchSink, BatchStream};
+#[cfg(feature = "std")]
+pub use ticket::BatchTicket;
+#[cfg(feature = "async")]
+pub use tokio_batcher::{TokioAppend, TokioBatcher};
+#[cfg(feature = "std")]
+pub use watch::{BatcherState, StateWatch};
+
+#[cfg(feature = "std")]
+use batch::Meta;
+#[cfg(feature = "std")]
+use builder::{Config, Hooks, Policy};
+#[cfg(feature = "std")]
+use cancel::{Cancel, Subscription};
+#[cfg(feature = "std")]
+use check::Counts;
+#[cfg(feature = "std")]
+use chunk::Joint;
+#[cfg(feature = "std")]
+use clock::SharedClock;
+#[cfg(feature = "std")]
+use done::{Complete, Detached, Outcome, Responses, Value};
+#[cfg(feature = "async")]
+use futures::task::Task;
+#[cfg(feature = "async")]
+use futures::{Async, Stream};
+#[cfg(feature = "std")]
+use metrics::Ewma;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(feature = "std")]
+use std::iter;
+#[cfg(feature = "std")]
+use std::mem;
+#[cfg(feature = "std")]
+use std::ops::Range;
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "std")]
+use std::sync::mpsc;
+#[cfg(feature = "std")]
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "std")]
+use timer::{Registration, Timed, Timer};
+#[cfg(feature = "std")]
+use watch::Watch;
+
+#[cfg(feature = "std")]
+type Cb = Box<dyn FnOnce(Result<(), BatchError>) + Send>;
+/// Describing optional batched callback function. The callback is called at
+/// most once and may capture any `Send` state, e.g. a response channel.
+#[cfg(feature = "std")]
 pub type CbOption = Option<Cb>;
+#[cfg(feature = "std")]
+type ItemsCb = Box<dyn FnOnce(Vec<Result<(), BatchError>>) + Send>;
+/// Describing optional callback function receiving one result per appended
+/// value, see `Batcher::append_each`.
+#[cfg(feature = "std")]
+pub type ItemsCbOption = Option<ItemsCb>;
+/// A callback and the range of values of its batch it was appended with.
+#[cfg(feature = "std")]
+type Entry = (Range<usize>, Callback);
+
+#[cfg(feature = "std")]
+type IdCb = Box<dyn FnOnce(Option<BatchId>, Result<(), BatchError>) + Send>;
+/// Describing optional callback function which also gets the id of the
+/// batch its values ran in, see `Batcher::append_with_id`.
+#[cfg(feature = "std")]
+pub type IdCbOption = Option<IdCb>;
+#[cfg(feature = "std")]
+type FlushCb = Box<dyn FnOnce() + Send>;
+#[cfg(feature = "std")]
+type ValueCb = Box<dyn FnOnce(Range<usize>, Result<Value, BatchError>) + Send>;
 
-/// Batching representation.
-pub struct Batcher<T> {
-  running: AtomicBool,
-  pending_batch: Mutex<Vec<T>>,
-  pending_callbacks: Mutex<Vec<Cb>>,
-  callbacks: Mutex<Vec<Cb>>,
-  run: Box<Fn(Vec<T>, &Batcher<T>) -> () + Send + Sync>,
+#[cfg(feature = "std")]
+enum Callback {
+  Batch(Cb),
+  Items(ItemsCb),
+  Tagged(IdCb),
+  /// See `Batcher::append_with_result`.
+  Value(ValueCb),
+  /// Part of a callback split across runs, with the offset of its values.
+  Part(Arc<Joint>, usize),
+  /// A callback which may be cancelled with the token, see `CancelHandle`.
+  Token(u64, Option<Box<Callback>>),
+  /// A callback whose values expire at the deadline, see
+  /// `Batcher::append_with_ttl`.
+  Expiring(Instant, Option<Box<Callback>>),
+  /// Callbacks sharing the values of a batch, see `Batcher::with_pending`.
+  Merged(Vec<Callback>),
 }
+#[cfg(feature = "std")]
+type OwnedRun<C> = Box<dyn FnMut(C, Done) + Send>;
+#[cfg(feature = "std")]
+type BorrowedRun<C> = Box<dyn FnMut(&mut C, Done) + Send>;
+/// The values, callbacks and metadata of the urgent or the pending batch.
+#[cfg(feature = "std")]
+type Lane<'a, C> = (&'a mut C, &'a mut Vec<Entry>, &'a mut Option<Meta>);
 
-impl<T> Batcher<T> {
+/// The run function, getting batches in a `C`.
+#[cfg(feature = "std")]
+enum Run<C> {
+  Owned(OwnedRun<C>),
+  /// See `Batcher::pooled`.
+  Borrowed(BorrowedRun<C>),
+}
+
+/// Priority of appended values, see `Batcher::append_with_priority`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg(feature = "std")]
+pub enum Priority {
+  /// Run in a separate batch ahead of the other waiting values.
+  High,
+  /// Wait for a batch in append order.
+  #[default]
+  Normal,
+}
+
+/// Batching representation. Values wait for their batch in a `C`, see
+/// `BatcherBuilder::build_into`.
+#[cfg(feature = "std")]
+pub struct Batcher<T, C: BatchContainer<T> = Vec<T>> {
+  state: Mutex<State<T, C>>,
+  completed: Condvar,
+  space: Condvar,
+  #[cfg(feature = "async")]
+  space_tasks: Mutex<Vec<Task>>,
+  run: Mutex<Run<C>>,
+  config: Config,
+  hooks: Hooks<T>,
+  timer: Mutex<Option<Registration>>,
+  /// See `BatcherBuilder::cancellation`.
+  cancellation: Mutex<Option<Subscription>>,
+  /// The latest token handed out, see `CancelHandle`.
+  tokens: AtomicU64,
+  /// See `Batcher::watch_state`.
+  watch: Arc<Watch>,
+  this: Weak<Batcher<T, C>>,
+}
+
+#[cfg(feature = "std")]
+struct State<T, C> {
+  dispatching: bool,
+  pending_batch: C,
+  pending_callbacks: Vec<Entry>,
+  /// When the pending batch started lingering, see `max_batch_delay` and
+  /// `max_item_latency`.
+  opened_at: Option<Instant>,
+  /// When the window of the pending batch ends, see `BatcherBuilder::window`.
+  window_end: Option<Instant>,
+  /// High priority values, which run before any other waiting batch.
+  urgent_batch: C,
+  urgent_callbacks: Vec<Entry>,
+  /// Metadata of the urgent and the pending batch, once they got values or
+  /// callbacks.
+  urgent_meta: Option<Meta>,
+  pending_meta: Option<Meta>,
+  /// Batches which reached `max_batch_size`, oldest first.
+  ready: VecDeque<(C, Vec<Entry>, Meta)>,
+  /// Batches handed to the run function whose callbacks were not called
+  /// yet, in order, how many may be at once and whether their callbacks are
+  /// called in order, see `BatcherBuilder::pipeline`.
+  in_flight: VecDeque<Flight>,
+  max_in_flight: usize,
+  unordered: bool,
+  /// See `Batcher::pause`.
+  paused: bool,
+  dispatched: u64,
+  completed: u64,
+  closed: bool,
+  /// Error of the most recently done batch, if it failed.
+  last_error: Option<BatchError>,
+  metrics: BatcherMetrics,
+  rate: Ewma,
+  /// Total weight of the pending batch, see `BatcherBuilder::max_batch_bytes`.
+  pending_weight: usize,
+  /// The current `max_batch_size`, see `BatcherBuilder::adaptive_batch_size`.
+  batch_size: Option<usize>,
+  /// See `BatcherBuilder::max_items_per_dispatch`.
+  max_items_per_dispatch: Option<usize>,
+  /// See `BatcherBuilder::chunk_errors`.
+  chunk_errors: ChunkErrors,
+  /// See `BatcherBuilder::clock`.
+  clock: SharedClock,
+  /// When the latest batch started.
+  last_dispatch: Option<Instant>,
+  /// Batches failed in a row, and until when the circuit is open, see
+  /// `BatcherBuilder::circuit_breaker`.
+  failures: usize,
+  open_until: Option<Instant>,
+  /// Cleared buffers of batches borrowed by the run function.
+  spare: Vec<C>,
+  /// Whether a dispatching thread left the next batch to the timer, see
+  /// `BatcherBuilder::max_sync_dispatches`.
+  handed_off: bool,
+  /// See `BatcherBuilder::flush_policy`.
+  policy: Option<Policy<T>>,
+  /// Callbacks with the batch count they wait for, see
+  /// `Batcher::flush_callback`.
+  flush_callbacks: Vec<(u64, FlushCb)>,
+  /// Callbacks of values which expired before their batch started, with
+  /// how many values there were, see `Batcher::append_with_ttl`.
+  expired: Vec<(usize, Callback)>,
+  /// See `Batcher::invariants`.
+  counts: Counts,
+}
+
+/// A batch handed to the run function.
+#[cfg(feature = "std")]
+struct Flight {
+  id: u64,
+  callbacks: Vec<Entry>,
+  meta: Meta,
+  len: usize,
+  since: Instant,
+  /// When the batch started or last reported progress.
+  beat: Instant,
+  /// Values the run function reported done, see `Done::progress`.
+  committed: usize,
+  /// How the batch finished and how long it ran, once it is done.
+  finished: Option<(Outcome, Duration)>,
+  /// Whether its callbacks are being called.
+  acking: bool,
+}
+
+#[cfg(feature = "std")]
+impl Flight {
+  fn is_running(&self) -> bool {
+    self.finished.is_none() && !self.acking
+  }
+}
+
+/// A done batch whose callbacks are to be called.
+#[cfg(feature = "std")]
+struct Ack {
+  id: u64,
+  callbacks: Vec<Entry>,
+  len: usize,
+  outcome: Outcome,
+  elapsed: Duration,
+}
+
+#[cfg(feature = "std")]
+impl<T: Send + 'static> Batcher<T> {
   /// Create a new batcher with a run function.
-  pub fn new(run: Box<Fn(Vec<T>, &Batcher<T>) -> () + Send + Sync>) -> Arc<Self> {
-    Arc::new(Batcher {
-      running: AtomicBool::new(false),
-      pending_batch: Mutex::new(Vec::new()),
-      pending_callbacks: Mutex::new(Vec::new()),
-      callbacks: Mutex::new(Vec::new()),
-      run,
-    })
+  ///
+  /// The run function may be any closure, including one that owns mutable
+  /// state such as a connection or a counter. It gets each batch with a
+  /// `Done` handle to signal the result through. It is never re-entered: if
+  /// it signals before returning, the next batch is run once it returns. If
+  /// it panics, the batch fails with `BatchError::RunnerPanicked`.
+  pub fn new<F>(run: F) -> Arc<Self>
+  where
+    F: FnMut(Vec<T>, Done) + Send + 'static,
+  {
+    Batcher::builder().build(run)
+  }
+  /// Create a new batcher with a run function getting each batch as a
+  /// `Batch`, which also tells its id, when it was created, how many appends
+  /// it holds and what started it. Otherwise like `new`.
+  pub fn with_batches<F>(run: F) -> Arc<Self>
+  where
+    F: FnMut(Batch<T>, Done) + Send + 'static,
+  {
+    Batcher::builder().build_batches(run)
+  }
+  /// Create a new batcher with a run function which is done with each batch
+  /// once it returns, with the result it returns. Otherwise like `new`.
+  pub fn for_each_batch<F>(run: F) -> Arc<Self>
+  where
+    F: FnMut(Vec<T>) -> Result<(), BatchError> + Send + 'static,
+  {
+    Batcher::builder().build_for_each(run)
+  }
+  /// Create a new batcher with a `BatchRunner`, like `new`.
+  pub fn with_runner<R>(runner: R) -> Arc<Self>
+  where
+    R: BatchRunner<T> + Send + 'static,
+  {
+    Batcher::builder().build_runner(runner)
+  }
+  /// Create a new batcher whose run function is called on a thread of its
+  /// own, rather than by whichever append or `Done` starts a batch. Appends
+  /// never block on the run function. The thread exits once the batcher is
+  /// dropped.
+  pub fn spawn<R>(runner: R) -> BatcherHandle<T>
+  where
+    R: BatchRunner<T> + Send + 'static,
+  {
+    Batcher::builder().spawn(runner)
+  }
+  /// Create a batcher together with the stream of its batches. See
+  /// `BatchStream` for when a batch is done.
+  #[cfg(feature = "async")]
+  pub fn batch_stream() -> (Arc<Self>, BatchStream<T>) {
+    Batcher::builder().build_stream()
+  }
+  /// Configure a batcher before creating it.
+  pub fn builder() -> BatcherBuilder<T> {
+    BatcherBuilder::new()
+  }
+
+  /// Create a new batcher whose run function borrows each batch instead of
+  /// taking it. Once it returns the batcher clears the batch and fills the
+  /// same buffer again, so batches stop allocating once the buffers have
+  /// grown. The run function must take what it needs out of the batch before
+  /// returning, e.g. with `Vec::drain`.
+  pub fn pooled<F>(run: F) -> Arc<Self>
+  where
+    F: FnMut(&mut Vec<T>, Done) + Send + 'static,
+  {
+    Batcher::builder().build_pooled(run)
+  }
+
+  /// Create a `Sink` appending to this batcher.
+  #[cfg(feature = "async")]
+  pub fn sink(&self) -> BatchSink<T> {
+    BatchSink::new(self.this())
+  }
+
+  /// Create a handle for appending from other threads.
+  pub fn handle(&self) -> BatcherHandle<T> {
+    BatcherHandle::from(self.this())
+  }
+
+  /// Create a front accepting `V` values, which `map` turns into values of
+  /// this batcher as they are appended, e.g. to batch a wire format behind
+  /// a typed API.
+  pub fn map_items<V, F>(&self, map: F) -> MappedBatcher<V, T>
+  where
+    F: Fn(V) -> T + Send + Sync + 'static,
+  {
+    MappedBatcher::new(self.this(), Arc::new(map))
+  }
+}
+
+#[cfg(feature = "std")]
+impl<T, C> Batcher<T, C>
+where
+  T: Send + 'static,
+  C: BatchContainer<T> + Send + 'static,
+{
+  pub(crate) fn with_config(
+    run: Run<C>,
+    config: Config,
+    hooks: Hooks<T>,
+  ) -> Arc<Self> {
+    let batcher = Arc::new_cyclic(|this| Batcher {
+      this: this.clone(),
+      watch: Arc::new(Watch::new(config.clock.clone())),
+      state: Mutex::new(State {
+        dispatching: false,
+        pending_batch: C::default(),
+        pending_callbacks: Vec::with_capacity(config.callback_capacity),
+        opened_at: None,
+        window_end: None,
+        urgent_batch: C::default(),
+        urgent_callbacks: Vec::new(),
+        urgent_meta: None,
+        pending_meta: None,
+        ready: VecDeque::new(),
+        in_flight: VecDeque::new(),
+        max_in_flight: config.pipeline.unwrap_or(1),
+        unordered: config.unordered,
+        paused: false,
+        dispatched: 0,
+        completed: 0,
+        closed: false,
+        last_error: None,
+        metrics: BatcherMetrics::default(),
+        rate: Ewma::new(config.clock.now()),
+        pending_weight: 0,
+        batch_size: config.initial_batch_size(),
+        max_items_per_dispatch: config.max_items_per_dispatch,
+        chunk_errors: config.chunk_errors,
+        clock: config.clock.clone(),
+        last_dispatch: None,
+        failures: 0,
+        open_until: None,
+        spare: Vec::new(),
+        handed_off: false,
+        policy: hooks.policy.as_ref().map(|policy| policy()),
+        flush_callbacks: Vec::new(),
+        expired: Vec::new(),
+        counts: Counts::default(),
+      }),
+      completed: Condvar::new(),
+      space: Condvar::new(),
+      #[cfg(feature = "async")]
+      space_tasks: Mutex::new(Vec::new()),
+      run: Mutex::new(run),
+      config,
+      hooks,
+      timer: Mutex::new(None),
+      cancellation: Mutex::new(None),
+      tokens: AtomicU64::new(0),
+    });
+    let config = &batcher.config;
+    let timed = config.lingers()
+      || config.batch_timeout.is_some()
+      || config.max_batches_per_second.is_some()
+      || config.breaker.is_some()
+      || config.max_sync_dispatches.is_some();
+    if timed && !config.without_timer {
+      let timer = match config.timer {
+        Some(ref timer) => timer.clone(),
+        None => Timer::named(config.name.as_deref(), config.clock.clone()),
+      };
+      let weak: Weak<dyn Timed> =
+        Arc::downgrade(&batcher) as Weak<Batcher<T, C>>;
+      *batcher.timer.lock().unwrap() = Some(timer.register(weak));
+    }
+    if let Some(ref journal) = batcher.hooks.journal {
+      let replay = journal.take_replay();
+      if !replay.is_empty() {
+        // Already in the journal, so queue them without writing them again.
+        batcher.enqueue(batcher.lock(), replay, None, false);
+      }
+    }
+    if let Some(ref token) = batcher.config.cancellation {
+      let weak = Arc::downgrade(&batcher);
+      let subscription = token.on_cancel(Box::new(move || {
+        if let Some(batcher) = weak.upgrade() {
+          batcher.shut_down();
+        }
+      }));
+      *batcher.cancellation.lock().unwrap() = subscription;
+    }
+    batcher
   }
   /// Accept an array of values and a callback.
   /// The accepted callback is called when the batch containing the values have been run.
-  pub fn append(&self, val: Vec<T>, cb: CbOption) -> () {
-    if self.running.load(Ordering::Relaxed) {
-      if self.pending_batch.lock().unwrap().len() == 0 {
-        *self.pending_callbacks.lock().unwrap() = Vec::new();
-      }
-      self.pending_batch.lock().unwrap().extend(val);
+  /// Without a callback nothing is recorded for the append besides its values.
+  pub fn append(&self, val: Vec<T>, cb: CbOption) {
+    self.append_callback(val, cb.map(Callback::Batch), Priority::Normal)
+  }
+  /// Accept an array of values the caller already batched, to run as a batch
+  /// of its own after the values appended before. Skips `capacity`, the
+  /// flush policy, `max_batch_bytes` and lingering, so it is cheaper than
+  /// `append` for arrays of hundreds of values or more, but nothing bounds
+  /// the values waiting. Small arrays cost more, as they never share a batch.
+  /// With a journal or a circuit breaker it is the same as `append`.
+  pub fn append_unchecked(&self, val: Vec<T>, cb: CbOption) {
+    if self.hooks.journal.is_some() || self.config.breaker.is_some() {
+      return self.append(val, cb);
+    }
+    let mut state = self.lock();
+    let cb = cb.map(Callback::Batch);
+    if state.closed {
+      drop(state);
       if let Some(cb) = cb {
-        self.callbacks.lock().unwrap().push(cb);
+        let closed = Outcome::Batch(Err(BatchError::Closed));
+        cb.call(0..val.len(), None, &closed);
+      }
+      return;
+    }
+    let count = val.len();
+    let callbacks: Vec<Entry> =
+      cb.map(|cb| (0..count, cb)).into_iter().collect();
+    state.metrics.appended += count as u64;
+    state.counts.bound += callbacks.len() as u64;
+    let mut meta = Meta::new(Trigger::Ready, self.config.clock.now());
+    meta.appends = 1;
+    let queued = state.queued() > 0
+      || !state.pending_callbacks.is_empty()
+      || !state.urgent_callbacks.is_empty();
+    let val = C::from_vec(val);
+    if state.is_free() && !queued && self.throttled_until(&state).is_none() {
+      let batch = state.start(val, callbacks, meta);
+      state.dispatching = true;
+      drop(state);
+      self.dispatch(batch);
+    } else {
+      if !state.pending_batch.is_empty() || !state.pending_callbacks.is_empty()
+      {
+        state.seal();
+        // It closes for the caller's batch, not for its size.
+        if let Some((_, _, ref mut meta)) = state.ready.back_mut() {
+          meta.trigger = Trigger::Ready;
+        }
+      }
+      state.ready.push_back((val, callbacks, meta));
+      self.kick(state, Trigger::Size);
+    }
+    self.report_append(count);
+    self.publish();
+  }
+  /// Accept any iterator of values and a callback, like `append`. Queued
+  /// values go straight into the pending batch, without collecting them
+  /// first.
+  pub fn append_iter<I>(&self, val: I, cb: CbOption)
+  where
+    I: IntoIterator<Item = T>,
+  {
+    self.append_callback(val, cb.map(Callback::Batch), Priority::Normal)
+  }
+  /// Accept every value waiting in `rx` at once, without blocking for more.
+  /// Returns how many there were.
+  pub fn drain_from(&self, rx: &mpsc::Receiver<T>) -> usize {
+    let first = match rx.try_recv() {
+      Ok(first) => first,
+      Err(_) => return 0,
+    };
+    let mut count = 0;
+    let val = iter::once(first)
+      .chain(rx.try_iter())
+      .inspect(|_| count += 1);
+    self.append_iter(val, None);
+    count
+  }
+  /// Accept every value `stream` has ready at once, like `drain_from`. Must
+  /// be called from within a task, which the stream notifies once it has
+  /// more. Values taken before the stream failed are appended all the same.
+  #[cfg(feature = "async")]
+  pub fn drain_stream<S>(&self, stream: &mut S) -> Result<usize, S::Error>
+  where
+    S: Stream<Item = T>,
+  {
+    let mut val = Vec::new();
+    let res = loop {
+      match stream.poll() {
+        Ok(Async::Ready(Some(item))) => val.push(item),
+        Ok(Async::Ready(None)) | Ok(Async::NotReady) => break Ok(val.len()),
+        Err(err) => break Err(err),
       }
+    };
+    if !val.is_empty() {
+      self.append(val, None);
+    }
+    res
+  }
+  /// Accept a single value and a callback, like `append`.
+  pub fn append_one(&self, val: T, cb: CbOption) {
+    self.append_iter(Some(val), cb)
+  }
+  /// Accept an array of values and a callback getting the value the run
+  /// function finished their batch with, see `Done::finish_with`. The
+  /// callback gets `BatchError::NoResult` if the batch succeeded without a
+  /// value of type `R`, e.g. when it was split by `max_items_per_dispatch`.
+  pub fn append_with_result<R, F>(&self, val: Vec<T>, cb: F)
+  where
+    R: Send + Sync + 'static,
+    F: FnOnce(Result<Arc<R>, BatchError>) + Send + 'static,
+  {
+    let cb = move |_, res: Result<Value, BatchError>| {
+      cb(res.and_then(|val| val.downcast().map_err(|_| BatchError::NoResult)))
+    };
+    let cb = Callback::Value(Box::new(cb));
+    self.append_callback(val, Some(cb), Priority::Normal)
+  }
+  /// Accept an array of values and a callback getting one response per
+  /// value, in order, for run functions which answer each value, see
+  /// `Done::respond`. The callback gets `BatchError::NoResult` if the batch
+  /// succeeded without a response of type `R` for each of its values.
+  pub fn append_map<R, F>(&self, val: Vec<T>, cb: F)
+  where
+    R: Send + 'static,
+    F: FnOnce(Result<Vec<R>, BatchError>) + Send + 'static,
+  {
+    let cb = move |range: Range<usize>, res: Result<Value, BatchError>| {
+      cb(res.and_then(|val| {
+        let responses = val
+          .downcast_ref::<Responses<R>>()
+          .ok_or(BatchError::NoResult)?;
+        let mut responses = responses.0.lock().unwrap();
+        let mine = responses.get_mut(range).ok_or(BatchError::NoResult)?;
+        mine
+          .iter_mut()
+          .map(|res| res.take())
+          .collect::<Option<_>>()
+          .ok_or(BatchError::NoResult)
+      }))
+    };
+    let cb = Callback::Value(Box::new(cb));
+    self.append_callback(val, Some(cb), Priority::Normal)
+  }
+  /// Accept an array of values and a callback, like `append`. The callback
+  /// also gets the id of the batch the values ran in, which is the one
+  /// `Done::id` reports to the run function. Values which never made it into
+  /// a batch, e.g. appended after `close`, get no id.
+  pub fn append_with_id(&self, val: Vec<T>, cb: IdCbOption) {
+    self.append_callback(val, cb.map(Callback::Tagged), Priority::Normal)
+  }
+  /// Accept an array of values and a callback, like `append`, with the
+  /// values expiring `ttl` from now: should their batch not start by then,
+  /// e.g. while the run function is slow, they are left out of it and the
+  /// callback gets `BatchError::Expired`.
+  pub fn append_with_ttl(&self, val: Vec<T>, ttl: Duration, cb: CbOption) {
+    let cb = cb.map(|cb| Box::new(Callback::Batch(cb)));
+    let cb = Some(Callback::Expiring(self.config.clock.now() + ttl, cb));
+    self.append_callback(val, cb, Priority::Normal)
+  }
+  /// Accept an array of values and a callback, like `append`, returning a
+  /// handle to cancel them with until their batch starts.
+  pub fn append_cancellable(&self, val: Vec<T>, cb: CbOption) -> CancelHandle {
+    let token = self.tokens.fetch_add(1, Ordering::Relaxed) + 1;
+    let cb = cb.map(|cb| Box::new(Callback::Batch(cb)));
+    let cb = Some(Callback::Token(token, cb));
+    self.append_callback(val, cb, Priority::Normal);
+    CancelHandle::new(self.this.clone(), token)
+  }
+  /// Call `f` with the values of the pending batch, e.g. to reorder, prune
+  /// or merge them before they run, and return what it returns. `f` must not
+  /// use the batcher. As values may move, the callbacks of the pending batch
+  /// then get the result of the batch as a whole, those of `append_each` one
+  /// result per value of the batch, and can no longer be cancelled. Values
+  /// appended with `append_with_ttl` then expire together, at the earliest
+  /// of their deadlines. Once `f` returns, the batch is checked against the
+  /// size limits and the flush policy afresh, as after an append. Urgent
+  /// values and batches closed already are left alone.
+  pub fn with_pending<R, F>(&self, f: F) -> R
+  where
+    F: FnOnce(&mut C) -> R,
+  {
+    let mut guard = self.lock();
+    let state = &mut *guard;
+    let before = state.pending_batch.len();
+    let res = f(&mut state.pending_batch);
+    let after = state.pending_batch.len();
+    if after < before {
+      state.counts.discarded += (before - after) as u64;
     } else {
+      state.metrics.appended += (after - before) as u64;
+    }
+    let mut callbacks: Vec<Callback> = mem::take(&mut state.pending_callbacks)
+      .into_iter()
+      .map(|(_, cb)| cb)
+      .collect();
+    let deadline = callbacks
+      .iter()
+      .filter_map(|cb| match cb {
+        Callback::Expiring(deadline, _) => Some(*deadline),
+        _ => None,
+      })
+      .min();
+    let merged = match callbacks.len() {
+      0 | 1 => callbacks.pop(),
+      n => {
+        state.counts.bound -= n as u64 - 1;
+        let merged = Callback::Merged(callbacks);
+        Some(match deadline {
+          Some(deadline) => {
+            Callback::Expiring(deadline, Some(Box::new(merged)))
+          }
+          None => merged,
+        })
+      }
+    };
+    state
+      .pending_callbacks
+      .extend(merged.map(|cb| (0..after, cb)));
+    if let Some(ref weigher) = self.hooks.weigher {
+      state.pending_weight =
+        state.pending_batch.iter().map(|val| weigher(val)).sum();
+    }
+    if after == 0 && state.pending_callbacks.is_empty() {
+      state.pending_meta = None;
+    }
+    // The policy saw other values than those pending now.
+    state.reset_policy();
+    let full = !state.pending_batch.is_empty()
+      && (state.decide() == Decision::Flush || self.is_full(state));
+    if full {
+      state.seal();
+      self.kick(guard, Trigger::Size);
+    }
+    res
+  }
+  /// Fail every value waiting for a batch to run with
+  /// `BatchError::Cancelled`, returning how many there were. The running
+  /// batch is not affected.
+  pub fn cancel_pending(&self) -> usize {
+    let batches = self.lock().take_waiting();
+    self.fail_waiting(batches)
+  }
+
+  /// Take every value waiting for a batch to run out of the batcher, in the
+  /// order they would have run, e.g. to hand them to another path during
+  /// failover. Their callbacks fail with `BatchError::Cancelled`. The running
+  /// batch is not affected.
+  pub fn take_pending(&self) -> Vec<T> {
+    let mut batches = self.lock().take_waiting();
+    let values = batches
+      .iter_mut()
+      .flat_map(|(batch, ..)| mem::take(batch))
+      .collect();
+    self.fail_waiting(batches);
+    values
+  }
+
+  /// Close the batcher and cancel every waiting value at once, once the
+  /// `CancellationToken` it was built with is cancelled.
+  fn shut_down(&self) {
+    let batches = {
+      let mut state = self.lock();
+      state.closed = true;
+      state.take_waiting()
+    };
+    self.fail_waiting(batches);
+  }
+
+  /// Fail batches taken out with `State::take_waiting` with
+  /// `BatchError::Cancelled`, returning how many values there were.
+  fn fail_waiting(&self, batches: Vec<(C, Vec<Entry>, Meta)>) -> usize {
+    self.notify_space();
+    self.completed.notify_all();
+    self.notify_flushed();
+    let cancelled = Outcome::Batch(Err(BatchError::Cancelled));
+    let mut values = 0;
+    for (batch, callbacks, _) in batches {
+      values += batch.len();
+      for (range, cb) in callbacks {
+        cb.call(range, None, &cancelled);
+      }
+    }
+    values
+  }
+  /// Accept an array of values and a callback, like `append`. With
+  /// `Priority::High` the values skip the queue: unless they run right away,
+  /// they run in their own batch before any other waiting values, without
+  /// lingering for `max_batch_delay`.
+  pub fn append_with_priority(
+    &self,
+    val: Vec<T>,
+    priority: Priority,
+    cb: CbOption,
+  ) {
+    self.append_callback(val, cb.map(Callback::Batch), priority)
+  }
+  /// Accept an array of values and a callback receiving one result per
+  /// value, in order. The run function reports them with
+  /// `Done::finish_each`; when it reports a single result instead, every
+  /// value gets that one.
+  pub fn append_each(&self, val: Vec<T>, cb: ItemsCbOption) {
+    self.append_callback(val, cb.map(Callback::Items), Priority::Normal)
+  }
+
+  /// Accept an array of values and a callback if there is room for them,
+  /// otherwise hand them back with `AppendError::Full`.
+  pub fn try_append(
+    &self,
+    val: Vec<T>,
+    cb: CbOption,
+  ) -> Result<(), AppendError<T>> {
+    let state = self.lock();
+    if state.closed {
+      return Err(AppendError::Closed(val));
+    }
+    if !self.fits(&state, val.len()) {
+      return Err(AppendError::Full(val));
+    }
+    if let Some(count) = self.push(state, val, cb.map(Callback::Batch), false) {
+      self.report_append(count);
+    }
+    Ok(())
+  }
+  /// Accept an array of values and a callback, blocking until there is room
+  /// for them. If they can never fit in the batcher's `capacity` they are
+  /// handed back with `AppendError::Full`.
+  ///
+  /// Must not be called from within the run function, as the batch it is
+  /// running would never make room.
+  pub fn append_blocking(
+    &self,
+    val: Vec<T>,
+    cb: CbOption,
+  ) -> Result<(), AppendError<T>> {
+    if let Some(capacity) = self.config.capacity {
+      if val.len() > capacity {
+        return Err(AppendError::Full(val));
+      }
+    }
+    let mut state = self.lock();
+    while !state.closed && !self.fits(&state, val.len()) {
+      state = self.space.wait(state).unwrap();
+    }
+    if state.closed {
+      return Err(AppendError::Closed(val));
+    }
+    if let Some(count) = self.push(state, val, cb.map(Callback::Batch), false) {
+      self.report_append(count);
+    }
+    Ok(())
+  }
+
+  fn append_callback<I>(&self, val: I, cb: Option<Callback>, priority: Priority)
+  where
+    I: IntoIterator<Item = T>,
+  {
+    let state = self.lock();
+    if state.closed {
+      drop(state);
+      if let Some(cb) = cb {
+        let count = val.into_iter().count();
+        cb.call(0..count, None, &Outcome::Batch(Err(BatchError::Closed)));
+      }
+      return;
+    }
+    let urgent = priority == Priority::High;
+    if let Some(count) = self.push(state, val, cb, urgent) {
+      self.report_append(count);
+    }
+    self.publish();
+  }
+
+  fn report_append(&self, count: usize) {
+    if let Some(ref sink) = self.config.metrics_sink {
+      sink.0.on_append(count);
+    }
+  }
+
+  /// Whether `len` more values fit within the configured capacity. Values
+  /// running right away do not count.
+  fn fits(&self, state: &State<T, C>, len: usize) -> bool {
+    let idle = state.is_free();
+    let queues =
+      !idle || self.config.lingers() || self.throttled_until(state).is_some();
+    match self.config.capacity {
+      Some(capacity) => !queues || state.queued() + len <= capacity,
+      None => true,
+    }
+  }
+
+  /// Add values to the batcher after writing them to the journal, if there
+  /// is one, see `enqueue`. Values which cannot be written, or which come
+  /// while a fail fast circuit breaker is open, are not added and fail their
+  /// callback.
+  fn push<I>(
+    &self,
+    state: MutexGuard<'_, State<T, C>>,
+    val: I,
+    cb: Option<Callback>,
+    urgent: bool,
+  ) -> Option<usize>
+  where
+    I: IntoIterator<Item = T>,
+  {
+    let open = self.config.breaker.is_some_and(|breaker| breaker.fail_fast)
+      && state
+        .open_until
+        .is_some_and(|until| until > self.config.clock.now());
+    if open {
+      drop(state);
       if let Some(cb) = cb {
-        *self.callbacks.lock().unwrap() = vec![cb];
+        let count = val.into_iter().count();
+        cb.call(
+          0..count,
+          None,
+          &Outcome::Batch(Err(BatchError::CircuitOpen)),
+        );
+      }
+      return None;
+    }
+    let journal = match self.hooks.journal {
+      Some(ref journal) => journal,
+      None => return self.enqueue(state, val, cb, urgent),
+    };
+    let val: Vec<T> = val.into_iter().collect();
+    if let Err(err) = journal.record(&val) {
+      drop(state);
+      if let Some(cb) = cb {
+        let failed = Outcome::Batch(Err(BatchError::run(err)));
+        cb.call(0..val.len(), None, &failed);
+      }
+      return None;
+    }
+    self.enqueue(state, val, cb, urgent)
+  }
+
+  /// Add values to the batcher, either running them right away or queueing
+  /// them, with urgent ones ahead of the rest. Returns how many there were,
+  /// or fails the callback with `BatchError::CapacityExceeded` if they do
+  /// not fit.
+  fn enqueue<I>(
+    &self,
+    mut state: MutexGuard<'_, State<T, C>>,
+    val: I,
+    cb: Option<Callback>,
+    urgent: bool,
+  ) -> Option<usize>
+  where
+    I: IntoIterator<Item = T>,
+  {
+    let idle = state.is_free();
+    let throttled = self.throttled_until(&state).is_some();
+    if idle && !self.config.lingers() && !throttled {
+      let batch = match state.spare.pop() {
+        Some(mut batch) => {
+          batch.extend(val);
+          batch
+        }
+        None => val.into_iter().collect::<C>(),
+      };
+      let count = batch.len();
+      state.metrics.appended += count as u64;
+      let callbacks: Vec<Entry> =
+        cb.map(|cb| (0..count, cb)).into_iter().collect();
+      state.counts.bound += callbacks.len() as u64;
+      let mut meta = Meta::new(Trigger::Ready, self.config.clock.now());
+      meta.appends = 1;
+      let batch = state.start(batch, callbacks, meta);
+      state.dispatching = true;
+      drop(state);
+      self.dispatch(batch);
+      return Some(count);
+    }
+    if !urgent && self.config.window.is_some() {
+      state.close_window(self.config.clock.now());
+      if state.window_end.is_none() {
+        state.window_end = self.config.window_end();
+      }
+    }
+    let had_cb = cb.is_some();
+    let count = state.extend(urgent, val, cb);
+    if let Some(capacity) = self.config.capacity {
+      if state.queued() > capacity {
+        let cb = state.retract(urgent, count, had_cb);
+        drop(state);
+        if let Some(cb) = cb {
+          let full = Outcome::Batch(Err(BatchError::CapacityExceeded));
+          cb.call(0..count, None, &full);
+        }
+        return None;
       }
-      self.running.store(true, Ordering::Relaxed);
-      (self.run)(val, self);
     }
+    state.metrics.appended += count as u64;
+    if !urgent {
+      self.weigh(&mut state, count);
+      if state.decide() == Decision::Flush {
+        state.seal();
+      }
+    }
+    if urgent || (idle && throttled) {
+      self.kick(state, Trigger::Ready);
+    } else if !idle {
+      if self.is_full(&state) {
+        state.seal();
+      }
+    } else {
+      // Linger: give other appends a chance to join the batch.
+      let enough = self
+        .config
+        .min_batch
+        .is_some_and(|(min, _)| state.pending_batch.len() >= min);
+      if !state.ready.is_empty() || self.is_full(&state) || enough {
+        self.kick(state, Trigger::Size);
+      } else if state.opened_at.is_none()
+        || state.policy.is_some()
+        || self.config.window.is_some()
+      {
+        // The policy may have moved its deadline, or a window started.
+        state
+          .opened_at
+          .get_or_insert_with(|| self.config.clock.now());
+        drop(state);
+        self.wake_timer();
+      }
+    }
+    Some(count)
+  }
+
+  /// Add the weight of the last `count` pending values. If they would take
+  /// the pending batch beyond `max_batch_bytes`, it is closed before them.
+  fn weigh(&self, state: &mut State<T, C>, count: usize) {
+    let (weigher, max) =
+      match (&self.hooks.weigher, self.config.max_batch_bytes) {
+        (Some(weigher), Some(max)) => (weigher, max),
+        _ => return,
+      };
+    let start = state.pending_batch.len() - count;
+    let weight: usize = state
+      .pending_batch
+      .iter()
+      .skip(start)
+      .map(|val| weigher(val))
+      .sum();
+    if start > 0 && state.pending_weight + weight > max {
+      state.seal_before(start);
+    }
+    state.pending_weight += weight;
+  }
+
+  /// Whether the pending batch reached `max_batch_size` or `max_batch_bytes`.
+  fn is_full(&self, state: &State<T, C>) -> bool {
+    let size = state
+      .batch_size
+      .is_some_and(|max| state.pending_batch.len() >= max);
+    let bytes = self
+      .config
+      .max_batch_bytes
+      .is_some_and(|max| state.pending_weight >= max);
+    size || bytes
+  }
+  /// Accept an array of values, returning a ticket which resolves when the
+  /// batch containing the values has been run.
+  pub fn append_ticket(&self, val: Vec<T>) -> BatchTicket {
+    let (tx, rx) = mpsc::channel();
+    self.append(
+      val,
+      Some(Box::new(move |res| {
+        let _ = tx.send(res);
+      })),
+    );
+    BatchTicket::new(rx)
+  }
+  /// Copy the values waiting for a batch to run, in the order they would
+  /// run in. The running batch is not included.
+  pub fn snapshot(&self) -> PendingSnapshot<T>
+  where
+    T: Clone,
+  {
+    let state = self.lock();
+    let mut values: Vec<T> = state.urgent_batch.iter().cloned().collect();
+    for (batch, ..) in &state.ready {
+      values.extend(batch.iter().cloned());
+    }
+    values.extend(state.pending_batch.iter().cloned());
+    PendingSnapshot::from(values)
+  }
+  /// Append the values of a snapshot taken with `snapshot`, e.g. by a
+  /// batcher before a restart.
+  pub fn restore(&self, snapshot: PendingSnapshot<T>) {
+    self.append(snapshot.into_values(), None)
+  }
+  /// Accept an array of values and block until the batch containing them
+  /// has been run, returning its result. Must not be called from within the
+  /// run function, see `flush`.
+  pub fn append_and_wait(&self, val: Vec<T>) -> Result<(), BatchError> {
+    self.append_ticket(val).wait()
   }
   /// Turn batcher's running state to off. then call the run function.
-  pub fn done(&self, err: Result<(), &str>) -> () {
-    for cb in self.callbacks.lock().unwrap().iter() {
-      cb(err)
-    }
-    self.running.store(false, Ordering::Relaxed);
-    let mut pending_callbacks = self.pending_callbacks.lock().unwrap();
-    let mut pending_batch = self.pending_batch.lock().unwrap();
-    *self.callbacks.lock().unwrap() = pending_callbacks.drain(..).collect();
-    let nextbatch: Vec<T> = pending_batch.drain(..).collect();
-    if nextbatch.is_empty() && self.callbacks.lock().unwrap().is_empty() {
+  fn done(&self, id: u64, mut outcome: Outcome) {
+    if self.hooks.coalesce.is_some() {
+      outcome = outcome.flatten();
+    }
+    {
+      let mut state = self.lock();
+      let flight = state
+        .in_flight
+        .iter_mut()
+        .find(|flight| flight.id == id && flight.is_running());
+      match flight {
+        Some(flight) => {
+          if self.hooks.coalesce.is_none() {
+            outcome = outcome.commit(flight.committed, flight.len);
+          }
+          let elapsed = self.config.clock.now().duration_since(flight.since);
+          flight.finished = Some((outcome, elapsed));
+        }
+        // Not a batch which is running.
+        None => return,
+      }
+    }
+    loop {
+      let acks = self.lock().take_acks();
+      if acks.is_empty() {
+        break;
+      }
+      for ack in acks {
+        self.ack(ack);
+      }
+    }
+    let state = self.lock();
+    self.clear_journal(&state);
+    self.kick(state, Trigger::Ready);
+    self.notify_flushed();
+  }
+
+  /// Call the callbacks of a done batch and account for it.
+  fn ack(&self, ack: Ack) {
+    let Ack {
+      id,
+      callbacks,
+      len,
+      outcome,
+      elapsed,
+    } = ack;
+    let failures = callbacks
+      .iter()
+      .filter(|(range, _)| outcome.failed(range))
+      .count();
+    for (range, cb) in callbacks {
+      cb.call(range, Some(BatchId::new(id)), &outcome);
+    }
+    if let Some(ref after_complete) = self.hooks.after_complete {
+      let res = outcome.error().map_or(Ok(()), Err);
+      after_complete(BatchId::new(id), &res);
+    }
+    match outcome.error() {
+      Some(err) => self.config.log(
+        true,
+        format_args!("batch {} failed after {:?}: {}", id, elapsed, err),
+      ),
+      None => self
+        .config
+        .log(false, format_args!("batch {} done after {:?}", id, elapsed)),
+    }
+    let mut state = self.lock();
+    state.completed += 1;
+    state.last_error = outcome.error();
+    if let Some(policy) = self.config.adaptive {
+      let size = state.batch_size.unwrap_or(len);
+      state.batch_size = Some(policy.next_size(size, elapsed));
+    }
+    state.metrics.completed += 1;
+    state.metrics.in_flight += elapsed;
+    state.rate.record(len, self.config.clock.now());
+    state.metrics.callback_failures += failures as u64;
+    if state.last_error.is_some() {
+      state.metrics.failed += 1;
+    }
+    if let Some(breaker) = self.config.breaker {
+      if state.last_error.is_some() {
+        state.failures += 1;
+        if state.failures >= breaker.failures {
+          state.open_until = Some(self.config.clock.now() + breaker.cooldown);
+        }
+      } else {
+        state.failures = 0;
+        state.open_until = None;
+      }
+    }
+    if let Some(ref sink) = self.config.metrics_sink {
+      let res = match state.last_error {
+        Some(ref err) => Err(err.clone()),
+        None => Ok(()),
+      };
+      drop(state);
+      sink.0.on_complete(id, len, elapsed, &res);
+      state = self.lock();
+    }
+    state.in_flight.retain(|flight| flight.id != id);
+    self.completed.notify_all();
+  }
+  /// Block until every value appended so far has been run and its batch is
+  /// done. Must not be called from within the run function, as the batch it
+  /// is running would never be done.
+  pub fn flush(&self) {
+    if let Some(ref sink) = self.config.metrics_sink {
+      sink.0.on_flush();
+    }
+    self.config.log(false, format_args!("flush"));
+    self.kick(self.lock(), Trigger::Flush);
+    let mut state = self.lock();
+    let target = state.flush_target();
+    // Cancelled values never run, so also stop once all is done.
+    while state.completed < target && !state.is_idle() {
+      state = self.completed.wait(state).unwrap();
+    }
+  }
+  /// Start the next batch if nothing keeps it waiting, like `flush` but
+  /// without waiting for it to be done, and tell what happened. May be
+  /// called from within the run function.
+  pub fn try_flush(&self) -> FlushOutcome {
+    if let Some(ref sink) = self.config.metrics_sink {
+      sink.0.on_flush();
+    }
+    self.config.log(false, format_args!("flush"));
+    let state = self.lock();
+    let callbacks =
+      state.urgent_callbacks.len() + state.pending_callbacks.len();
+    if state.queued() == 0 && callbacks == 0 {
+      return FlushOutcome::Empty;
+    }
+    if state.paused {
+      return FlushOutcome::Paused;
+    }
+    if !state.is_free() {
+      return FlushOutcome::Running;
+    }
+    if self.throttled_until(&state).is_some() {
+      return FlushOutcome::Throttled;
+    }
+    self.kick(state, Trigger::Flush);
+    FlushOutcome::Dispatched
+  }
+
+  /// Call `cb` once every value appended so far has been run and its batch
+  /// is done, like `flush` but without blocking. May be called from within
+  /// the run function. If the batcher is dropped first, `cb` is called from
+  /// the drop.
+  pub fn flush_callback(&self, cb: Box<dyn FnOnce() + Send>) {
+    if let Some(ref sink) = self.config.metrics_sink {
+      sink.0.on_flush();
+    }
+    self.config.log(false, format_args!("flush"));
+    let mut state = self.lock();
+    let target = state.flush_target();
+    state.flush_callbacks.push((target, cb));
+    self.kick(state, Trigger::Flush);
+    self.notify_flushed();
+  }
+  /// Stop accepting values, run everything appended so far and wait for the
+  /// final batch to be done. Returns the error of that batch, if it failed.
+  /// Later appends fail with `BatchError::Closed`.
+  ///
+  /// Must not be called from within the run function, see `flush`.
+  pub fn close(&self) -> Result<(), BatchError> {
+    self.lock().closed = true;
+    self.publish();
+    // Wake up blocked appends, so they see the batcher is closed.
+    self.notify_space();
+    self.flush();
+    match self.lock().last_error.clone() {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+
+  /// Stop starting batches, e.g. while the downstream service is down for
+  /// maintenance. Running batches go on, and appends keep queueing, up to
+  /// `capacity` if set. A `flush` waits until the batcher is resumed.
+  pub fn pause(&self) {
+    self.lock().paused = true;
+  }
+
+  /// Start batches again after `pause`, beginning with those which queued
+  /// up meanwhile.
+  pub fn resume(&self) {
+    let mut state = self.lock();
+    state.paused = false;
+    self.kick(state, Trigger::Ready);
+  }
+
+  /// Whether the batcher is paused, see `pause`.
+  pub fn is_paused(&self) -> bool {
+    self.lock().paused
+  }
+
+  /// Whether a batch was handed to the run function and is not done yet.
+  pub fn is_running(&self) -> bool {
+    self.lock().in_flight.iter().any(Flight::is_running)
+  }
+
+  /// Whether the circuit breaker is open, see
+  /// `BatcherBuilder::circuit_breaker`.
+  pub fn is_circuit_open(&self) -> bool {
+    let until = self.lock().open_until;
+    until.is_some_and(|until| until > self.config.clock.now())
+  }
+
+  /// Number of values waiting for a batch to run.
+  pub fn pending_len(&self) -> usize {
+    self.lock().queued()
+  }
+
+  /// Number of values in the running batches.
+  pub fn in_flight_len(&self) -> usize {
+    let state = self.lock();
+    state
+      .in_flight
+      .iter()
+      .filter(|flight| flight.is_running())
+      .map(|flight| flight.len)
+      .sum()
+  }
+
+  /// Number of values waiting or running.
+  pub fn len(&self) -> usize {
+    self.pending_len() + self.in_flight_len()
+  }
+
+  /// Whether no values are waiting or running.
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Number of batches handed to the run function so far.
+  pub fn total_dispatched(&self) -> u64 {
+    self.lock().dispatched
+  }
+
+  /// The size at which the pending batch is closed, if any. It only changes
+  /// with `BatcherBuilder::adaptive_batch_size`.
+  pub fn batch_size(&self) -> Option<usize> {
+    self.lock().batch_size
+  }
+
+  /// Panic if the batcher lost track of a value or callback: every
+  /// appended value is run, cancelled or still waiting, every callback is
+  /// called or still held, and every started batch is done or in flight.
+  /// Meant for fuzzing the batcher and code built on it, behind the `check`
+  /// feature.
+  #[cfg(feature = "check")]
+  pub fn invariants(&self) {
+    self.lock().check()
+  }
+
+  /// A snapshot of the batcher's counters.
+  pub fn metrics(&self) -> BatcherMetrics {
+    let state = self.lock();
+    let mut metrics = state.metrics.clone();
+    metrics.pending = state.queued();
+    metrics
+  }
+
+  /// A summary of the counters, the running batches, the rate at which
+  /// values are done and how long values have been waiting.
+  pub fn stats(&self) -> BatcherStats {
+    let state = self.lock();
+    let now = self.config.clock.now();
+    let mut metrics = state.metrics.clone();
+    metrics.pending = state.queued();
+    let running = state.in_flight.iter().filter(|flight| flight.is_running());
+    let oldest = state
+      .urgent_meta
+      .iter()
+      .chain(state.ready.iter().map(|(_, _, meta)| meta))
+      .chain(state.pending_meta.iter())
+      .map(|meta| meta.created_at)
+      .min();
+    BatcherStats {
+      metrics,
+      running: running.clone().count(),
+      in_flight_items: running.clone().map(|flight| flight.len).sum(),
+      committed_items: running.map(|flight| flight.committed).sum(),
+      items_per_second: state.rate.rate(now),
+      oldest_pending: oldest.map(|created_at| now.duration_since(created_at)),
+    }
+  }
+
+  /// The name given with `BatcherBuilder::name`.
+  pub fn name(&self) -> Option<&str> {
+    self.config.name.as_deref()
+  }
+
+  /// The `Arc` this batcher lives in, for handing out to asynchronous work.
+  pub(crate) fn this(&self) -> Arc<Self> {
+    self
+      .this
+      .upgrade()
+      .expect("batcher is always owned by an Arc")
+  }
+
+  fn lock(&self) -> MutexGuard<'_, State<T, C>> {
+    self.state.lock().unwrap()
+  }
+
+  /// Start running the next batch, unless as many as may are already
+  /// running. `trigger` is what starts the pending batch, should that be the
+  /// next one.
+  fn kick(&self, mut state: MutexGuard<'_, State<T, C>>, trigger: Trigger) {
+    if !state.is_free() {
+      // When only `dispatching` is set the run function signalled
+      // completion before returning, the dispatch loop picks the next batch
+      // up once it does.
+      return;
+    }
+    if self.throttled_until(&state).is_some()
+      || (trigger == Trigger::Ready && self.lingers(&mut state))
+    {
+      // The timer kicks once the next batch may start.
+      drop(state);
+      return self.wake_timer();
+    }
+    match state.next_batch(trigger) {
+      Some(nextbatch) => {
+        state.dispatching = true;
+        drop(state);
+        self.notify_space();
+        self.dispatch(nextbatch);
+      }
+      None => {
+        let expired = mem::take(&mut state.expired);
+        drop(state);
+        fail_expired(expired);
+      }
+    }
+  }
+
+  /// When the next batch may start, if that is in the future, see
+  /// `BatcherBuilder::max_batches_per_second` and
+  /// `BatcherBuilder::circuit_breaker`.
+  fn throttled_until(&self, state: &State<T, C>) -> Option<Instant> {
+    let rate = match (state.last_dispatch, self.config.dispatch_interval()) {
+      (Some(last), Some(interval)) => Some(last + interval),
+      _ => None,
+    };
+    let due = match (rate, state.open_until) {
+      (Some(rate), Some(open)) => rate.max(open),
+      (rate, open) => rate.or(open)?,
+    };
+    if due > self.config.clock.now() {
+      Some(due)
+    } else {
+      None
+    }
+  }
+
+  /// Do what the timer thread does once it is due, see
+  /// `BatcherBuilder::without_timer`: fail running batches which timed out,
+  /// dispatch a lingering batch whose delay is over, one held back by the
+  /// rate limit once it may start, and one left by `max_sync_dispatches`.
+  /// Tells what it did, and when to poll again.
+  pub fn poll_complete(&self) -> PollOutcome {
+    let start = self.lock().dispatched;
+    let mut outcome = PollOutcome::default();
+    loop {
+      let dispatched = self.lock().dispatched;
+      let (timed_out, next_due) = self.check_due();
+      outcome.next_due = next_due;
+      outcome.timed_out += timed_out as usize;
+      // A batch started meanwhile may have a deadline of its own.
+      if !timed_out && self.lock().dispatched == dispatched {
+        break;
+      }
+    }
+    outcome.dispatched = self.lock().dispatched - start;
+    outcome
+  }
+
+  fn on_timer(&self) -> Option<Instant> {
+    self.check_due().1
+  }
+
+  /// Fail a running batch if it timed out, dispatch a lingering batch
+  /// whose delay is over, and one held back by the rate limit once it may
+  /// start. Returns whether a batch timed out, and when the timer is due
+  /// next, if at all.
+  fn check_due(&self) -> (bool, Option<Instant>) {
+    let mut next = None;
+    if let Some(timeout) = self.config.batch_timeout {
+      let state = self.lock();
+      // The batch which reported last the longest ago is the first to time
+      // out.
+      let first = state
+        .in_flight
+        .iter()
+        .filter(|flight| flight.is_running())
+        .min_by_key(|flight| flight.beat);
+      if let Some(flight) = first {
+        let due = flight.beat + timeout;
+        if due > self.config.clock.now() {
+          next = Some(due);
+        } else {
+          let id = flight.id;
+          drop(state);
+          self.config.log(
+            true,
+            format_args!("batch {} timed out after {:?}", id, timeout),
+          );
+          self.done(id, Outcome::Batch(Err(BatchError::Timeout)));
+          // The next batch may have started, with a deadline of its own.
+          return (true, Some(self.config.clock.now()));
+        }
+      }
+    }
+    if self.config.max_sync_dispatches.is_some() {
+      let mut state = self.lock();
+      if mem::take(&mut state.handed_off) {
+        self.kick(state, Trigger::Ready);
+      }
+    }
+    let throttles = self.config.max_batches_per_second.is_some()
+      || self.config.breaker.is_some();
+    if throttles {
+      let state = self.lock();
+      let waiting = state.queued() > 0 || !state.pending_callbacks.is_empty();
+      let idle = state.is_free();
+      if let (true, true, Some(due)) =
+        (idle, waiting, self.throttled_until(&state))
+      {
+        next = Some(due);
+      } else if idle && waiting && state.opened_at.is_none() {
+        self.kick(state, Trigger::Ready);
+      }
+    }
+    if self.config.lingers() {
+      let mut state = self.lock();
+      if state.close_window(self.config.clock.now()) {
+        self.kick(state, Trigger::Time);
+        state = self.lock();
+      }
+      if let Some(due) = self.linger_due(&state) {
+        if due > self.config.clock.now() {
+          next = Some(next.map_or(due, |next: Instant| next.min(due)));
+        } else {
+          self.kick(state, Trigger::Time);
+        }
+      }
+    }
+    (false, next)
+  }
+
+  /// When the lingering pending batch is due, see `max_batch_delay`,
+  /// `max_item_latency` and `window`.
+  fn linger_due(&self, state: &State<T, C>) -> Option<Instant> {
+    let opened_at = match state.opened_at {
+      Some(opened_at) => opened_at,
+      None => return state.window_end,
+    };
+    let delay = self.config.max_batch_delay.map(|delay| opened_at + delay);
+    let latency = self.config.max_item_latency.map(|latency| {
+      let oldest = state.pending_meta.map_or(opened_at, |meta| meta.created_at);
+      oldest + latency
+    });
+    let min = self.config.min_batch.map(|(_, wait)| {
+      let oldest = state.pending_meta.map_or(opened_at, |meta| meta.created_at);
+      oldest + wait
+    });
+    let policy = state.policy.as_ref().and_then(|p| p.next_deadline());
+    let window = state.window_end;
+    delay
+      .into_iter()
+      .chain(latency)
+      .chain(min)
+      .chain(policy)
+      .chain(window)
+      .min()
+  }
+
+  /// Whether the pending batch, being next to run, is to linger because its
+  /// oldest value did not wait for `max_item_latency` yet, its window is not
+  /// over, or it is short of `min_batch_size`. If so, the timer starts it
+  /// later.
+  fn lingers(&self, state: &mut State<T, C>) -> bool {
+    let now = self.config.clock.now();
+    let young = match self.config.max_item_latency {
+      Some(latency) => state
+        .pending_meta
+        .is_some_and(|meta| meta.created_at + latency > now),
+      None => false,
+    };
+    let open = state.window_end.is_some_and(|end| end > now);
+    let short = match self.config.min_batch {
+      Some((min, wait)) => {
+        state.pending_batch.len() < min
+          && state
+            .pending_meta
+            .is_some_and(|meta| meta.created_at + wait > now)
+      }
+      None => false,
+    };
+    let urgent = !state.urgent_batch.is_empty()
+      || !state.urgent_callbacks.is_empty()
+      || !state.ready.is_empty();
+    if urgent || !(young || open || short) || self.is_full(state) {
+      return false;
+    }
+    if state.opened_at.is_none() {
+      state.opened_at = Some(now);
+    }
+    true
+  }
+
+  /// Wake up appends waiting for room, see `append_blocking` and `sink`.
+  fn notify_space(&self) {
+    self.space.notify_all();
+    #[cfg(feature = "async")]
+    for task in self.space_tasks.lock().unwrap().drain(..) {
+      task.notify();
+    }
+  }
+
+  /// Empty the journal once nothing is left to run.
+  fn clear_journal(&self, state: &State<T, C>) {
+    if let (Some(journal), true) = (&self.hooks.journal, state.is_idle()) {
+      // On failure the values are appended again on the next start.
+      let _ = journal.clear();
+    }
+  }
+
+  /// Call the flush callbacks whose batches are done.
+  fn notify_flushed(&self) {
+    let flushed = self.lock().take_flushed();
+    for cb in flushed {
+      cb();
+    }
+    self.publish();
+  }
+
+  /// Tell watchers what the batcher is doing, see `watch_state`.
+  fn publish(&self) {
+    if !self.watch.is_watched() {
       return;
     }
-    self.running.store(true, Ordering::Relaxed);
-    (self.run)(nextbatch, self);
+    let state = self.lock();
+    let observed = match (state.closed, state.is_idle()) {
+      (false, true) => BatcherState::Idle,
+      (false, false) => BatcherState::Running,
+      (true, false) => BatcherState::Draining,
+      (true, true) => BatcherState::Closed,
+    };
+    self.watch.set(observed);
+  }
+
+  /// Subscribe to what the batcher is doing, e.g. for a supervisor to await
+  /// it being fully drained, see `StateWatch`.
+  pub fn watch_state(&self) -> StateWatch {
+    let watch = Watch::subscribe(&self.watch);
+    self.publish();
+    watch
+  }
+
+  /// Whether nothing is running or waiting to run.
+  pub(crate) fn is_idle(&self) -> bool {
+    self.lock().is_idle()
+  }
+
+  #[cfg(feature = "async")]
+  pub(crate) fn wait_for_space(&self, task: Task) {
+    self.space_tasks.lock().unwrap().push(task);
+  }
+
+  fn wake_timer(&self) {
+    if let Some(ref timer) = *self.timer.lock().unwrap() {
+      timer.wake();
+    }
+  }
+
+  /// Call the run function until no more batches are ready. Only one thread
+  /// dispatches at a time, the one which flipped `dispatching` on.
+  fn dispatch(&self, batch: C) {
+    self.run_batches(batch);
+    self.publish();
+  }
+
+  fn run_batches(&self, mut batch: C) {
+    let mut runs = 0;
+    loop {
+      runs += 1;
+      if let Some(ref coalesce) = self.hooks.coalesce {
+        let mut values = batch.into_vec();
+        coalesce(&mut values);
+        batch = C::from_vec(values);
+      }
+      let (id, meta, pending, expired) = {
+        let mut state = self.lock();
+        let now = self.config.clock.now();
+        let (id, meta) = {
+          let flight = state.in_flight.back_mut().expect("a batch started");
+          flight.len = batch.len();
+          flight.since = now;
+          flight.beat = now;
+          (flight.id, flight.meta)
+        };
+        state.last_dispatch = Some(now);
+        state.metrics.dispatched += 1;
+        state.metrics.dispatched_items += batch.len() as u64;
+        let expired = mem::take(&mut state.expired);
+        (id, meta, state.queued(), expired)
+      };
+      fail_expired(expired);
+      if let Some(ref sink) = self.config.metrics_sink {
+        sink.0.on_dispatch(id, batch.len(), pending);
+      }
+      self.config.log(
+        false,
+        format_args!(
+          "batch {} started with {} values ({:?}), {} pending",
+          id,
+          batch.len(),
+          meta.trigger,
+          pending
+        ),
+      );
+      if self.config.batch_timeout.is_some() {
+        self.wake_timer();
+      }
+      let done = Done::new(self.this(), id, meta);
+      let mut run = self.run.lock().unwrap();
+      let ran = panic::catch_unwind(AssertUnwindSafe(|| {
+        if let Some(ref before_dispatch) = self.hooks.before_dispatch {
+          before_dispatch(BatchId::new(id), batch.as_mut_slice());
+        }
+        run.run(batch, done)
+      }));
+      drop(run);
+      let spare = match ran {
+        Ok(spare) => spare,
+        Err(_) => {
+          // Ignored if the run function signalled before panicking.
+          self.done(id, Outcome::Batch(Err(BatchError::RunnerPanicked)));
+          None
+        }
+      };
+      let mut state = self.lock();
+      if let Some(spare) = spare {
+        state.spare.push(spare);
+      }
+      if state.is_busy() {
+        state.dispatching = false;
+        return;
+      }
+      if self.throttled_until(&state).is_some() || self.lingers(&mut state) {
+        state.dispatching = false;
+        drop(state);
+        return self.wake_timer();
+      }
+      let waiting = state.queued() > 0 || !state.pending_callbacks.is_empty();
+      let limited = self.config.max_sync_dispatches.is_some_and(|m| runs >= m);
+      if waiting && limited {
+        state.dispatching = false;
+        state.handed_off = true;
+        drop(state);
+        return self.wake_timer();
+      }
+      match state.next_batch(Trigger::Ready) {
+        Some(nextbatch) => {
+          batch = nextbatch;
+          self.notify_space();
+        }
+        None => {
+          state.dispatching = false;
+          self.clear_journal(&state);
+          let expired = mem::take(&mut state.expired);
+          drop(state);
+          return fail_expired(expired);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl<T, C> Cancel for Batcher<T, C>
+where
+  T: Send + 'static,
+  C: BatchContainer<T> + Send + 'static,
+{
+  fn cancel(&self, token: u64) -> bool {
+    let mut state = self.lock();
+    let (removed, cb, pending) = match state.take_token(token) {
+      Some(taken) => taken,
+      None => return false,
+    };
+    state.counts.discarded += removed.len() as u64;
+    state.counts.called += 1;
+    if let (true, Some(weigher)) = (pending, &self.hooks.weigher) {
+      let weight: usize = removed.iter().map(|val| weigher(val)).sum();
+      state.pending_weight = state.pending_weight.saturating_sub(weight);
+    }
+    drop(state);
+    self.notify_space();
+    self.completed.notify_all();
+    self.notify_flushed();
+    let cancelled = Outcome::Batch(Err(BatchError::Cancelled));
+    cb.call(0..removed.len(), None, &cancelled);
+    true
+  }
+}
+
+#[cfg(feature = "std")]
+impl<T, C> Complete for Batcher<T, C>
+where
+  T: Send + 'static,
+  C: BatchContainer<T> + Send + 'static,
+{
+  fn complete(&self, id: u64, outcome: Outcome) {
+    self.done(id, outcome)
+  }
+
+  fn progress(&self, id: u64, committed: usize) {
+    let mut state = self.lock();
+    let flight = state
+      .in_flight
+      .iter_mut()
+      .find(|flight| flight.id == id && flight.is_running());
+    if let Some(flight) = flight {
+      flight.committed = flight.committed.max(committed.min(flight.len));
+      flight.beat = self.config.clock.now();
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl Callback {
+  /// Whether the callback takes one result per value.
+  fn wants_items(&self) -> bool {
+    match self {
+      Callback::Items(_) => true,
+      Callback::Token(_, Some(cb)) | Callback::Expiring(_, Some(cb)) => {
+        cb.wants_items()
+      }
+      _ => false,
+    }
+  }
+
+  /// Hand the callback the result of the values in `range` of batch `id`,
+  /// or of values which never made it into a batch if there is no `id`.
+  fn call(self, range: Range<usize>, id: Option<BatchId>, outcome: &Outcome) {
+    let res = || match outcome {
+      Outcome::Batch(res) => res.clone(),
+      Outcome::Value(res) => res.clone().map(|_| ()),
+      Outcome::Items(results) => outcome_range(results, range.clone())
+        .into_iter()
+        .find(Result::is_err)
+        .unwrap_or(Ok(())),
+    };
+    match self {
+      Callback::Token(_, cb) | Callback::Expiring(_, cb) => {
+        if let Some(cb) = cb {
+          cb.call(range, id, outcome)
+        }
+      }
+      Callback::Part(joint, offset) => {
+        let results = match outcome {
+          Outcome::Items(results) => outcome_range(results, range),
+          _ => vec![res(); range.len()],
+        };
+        joint.finish(offset, results, id)
+      }
+      Callback::Merged(callbacks) => {
+        for cb in callbacks {
+          cb.call(range.clone(), id, outcome)
+        }
+      }
+      Callback::Batch(cb) => cb(res()),
+      Callback::Tagged(cb) => cb(id, res()),
+      Callback::Value(cb) => match outcome {
+        Outcome::Value(res) => cb(range, res.clone()),
+        _ => {
+          let res = res().and(Err(BatchError::NoResult));
+          cb(range, res)
+        }
+      },
+      Callback::Items(cb) => match outcome {
+        Outcome::Items(results) => cb(outcome_range(results, range)),
+        _ => cb(vec![res(); range.len()]),
+      },
+    }
+  }
+}
+
+/// Fail the callbacks of expired values, see `Batcher::append_with_ttl`.
+#[cfg(feature = "std")]
+fn fail_expired(expired: Vec<(usize, Callback)>) {
+  let outcome = Outcome::Batch(Err(BatchError::Expired));
+  for (count, cb) in expired {
+    cb.call(0..count, None, &outcome);
+  }
+}
+
+/// Whether the values of an entry expired by `now`.
+#[cfg(feature = "std")]
+fn has_expired((_, cb): &Entry, now: Instant) -> bool {
+  match cb {
+    Callback::Expiring(deadline, _) => *deadline <= now,
+    _ => false,
+  }
+}
+
+#[cfg(feature = "std")]
+fn has_token((_, cb): &Entry, token: u64) -> bool {
+  match cb {
+    Callback::Token(t, _) => *t == token,
+    _ => false,
+  }
+}
+
+/// The metadata of a batch leaving its lane, started by `trigger`.
+#[cfg(feature = "std")]
+fn take_meta(meta: &mut Option<Meta>, trigger: Trigger, now: Instant) -> Meta {
+  let mut meta = meta.take().unwrap_or_else(|| Meta::new(trigger, now));
+  meta.trigger = trigger;
+  meta
+}
+
+/// Count one append less in a lane, forgetting its metadata once empty.
+#[cfg(feature = "std")]
+fn forget_append<T, C>(meta: &mut Option<Meta>, batch: &C, callbacks: &[Entry])
+where
+  C: BatchContainer<T>,
+{
+  if batch.is_empty() && callbacks.is_empty() {
+    *meta = None;
+  } else if let Some(ref mut meta) = *meta {
+    meta.appends = meta.appends.saturating_sub(1);
+  }
+}
+
+/// Take the entry at `at` out of a batch together with its values, moving
+/// the later values up.
+#[cfg(feature = "std")]
+fn take_entry<T, C: BatchContainer<T>>(
+  batch: &mut C,
+  callbacks: &mut Vec<Entry>,
+  at: usize,
+) -> (C, Callback) {
+  let (range, cb) = callbacks.remove(at);
+  let len = range.len();
+  let mut removed = batch.split_off(range.start);
+  batch.extend(removed.split_off(len));
+  for (later, _) in &mut callbacks[at..] {
+    *later = later.start - len..later.end - len;
+  }
+  (removed, cb)
+}
+
+/// The results for `range`, failing the values the run function reported no
+/// result for.
+#[cfg(feature = "std")]
+fn outcome_range(
+  results: &[Result<(), BatchError>],
+  range: Range<usize>,
+) -> Vec<Result<(), BatchError>> {
+  range
+    .map(|i| match results.get(i) {
+      Some(res) => res.clone(),
+      None => Err(BatchError::run("run function reported no result")),
+    })
+    .collect()
+}
+
+#[cfg(feature = "std")]
+impl<C> Run<C> {
+  /// Run a batch, handing back its buffer if the run function only borrowed
+  /// it.
+  fn run<T>(&mut self, batch: C, done: Done) -> Option<C>
+  where
+    C: BatchContainer<T>,
+  {
+    match self {
+      Run::Owned(run) => {
+        run(batch, done);
+        None
+      }
+      Run::Borrowed(run) => {
+        let mut batch = batch;
+        run(&mut batch, done);
+        batch.clear();
+        Some(batch)
+      }
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl<T, C> fmt::Debug for Batcher<T, C>
+where
+  T: Send + 'static,
+  C: BatchContainer<T> + Send + 'static,
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Batcher")
+      .field("name", &self.config.name)
+      .field("stats", &self.stats())
+      .finish()
+  }
+}
+
+#[cfg(feature = "std")]
+impl<T, C: BatchContainer<T>> Drop for Batcher<T, C> {
+  fn drop(&mut self) {
+    let state = match self.state.get_mut() {
+      Ok(state) => state,
+      Err(poisoned) => poisoned.into_inner(),
+    };
+    // A running batch keeps the batcher alive through its `Done`, so if
+    // there is one its `Done` was forgotten and it can never finish.
+    let closed = Outcome::Batch(Err(BatchError::Closed));
+    // Whether every value ran, so the journal may forget them.
+    let mut finished = state.in_flight.is_empty();
+    for flight in mem::take(&mut state.in_flight) {
+      for (range, cb) in flight.callbacks {
+        cb.call(range, Some(BatchId::new(flight.id)), &closed);
+      }
+    }
+    let mut batches = state.take_waiting();
+    let values: usize = batches.iter().map(|(batch, ..)| batch.len()).sum();
+    if values > 0 {
+      let policy = self.config.drop_policy;
+      self.config.log(
+        false,
+        format_args!("dropped with {} values left, {:?}", values, policy),
+      );
+    }
+    match self.config.drop_policy {
+      DropPolicy::Flush => {
+        let run = self.run.get_mut().unwrap();
+        if let Some(max) = self.config.max_items_per_dispatch {
+          batches = batches
+            .into_iter()
+            .flat_map(|(batch, callbacks, meta)| {
+              let errors = state.chunk_errors;
+              let chunks = chunk::split(batch, callbacks, max, errors);
+              let chunks = chunks.into_iter();
+              chunks.map(move |(batch, callbacks)| (batch, callbacks, meta))
+            })
+            .collect();
+        }
+        let flushed = mem::take(&mut state.flush_callbacks);
+        for (mut batch, callbacks, mut meta) in batches {
+          meta.trigger = Trigger::Drop;
+          state.dispatched += 1;
+          let id = state.dispatched;
+          let mut detached = Detached::new(callbacks, id);
+          if let Some(ref coalesce) = self.hooks.coalesce {
+            let mut values = batch.into_vec();
+            coalesce(&mut values);
+            batch = C::from_vec(values);
+            detached = detached.flatten();
+          }
+          let detached = Arc::new(detached);
+          let done = Done::new(detached.clone(), id, meta);
+          let ran =
+            panic::catch_unwind(AssertUnwindSafe(|| run.run(batch, done)));
+          if ran.is_err() {
+            let panicked = Outcome::Batch(Err(BatchError::RunnerPanicked));
+            detached.complete(id, panicked);
+          }
+          let clock = &self.config.clock;
+          finished &= detached.wait(self.config.batch_timeout, clock);
+        }
+        if let (Some(journal), true) = (&self.hooks.journal, finished) {
+          let _ = journal.clear();
+        }
+        for (_, cb) in flushed {
+          cb();
+        }
+        self.watch.set(BatcherState::Closed);
+        return;
+      }
+      DropPolicy::Discard | DropPolicy::PanicIfPending => {}
+    }
+    for (_, callbacks, _) in batches {
+      for (range, cb) in callbacks {
+        cb.call(range, None, &closed);
+      }
+    }
+    for (_, cb) in mem::take(&mut state.flush_callbacks) {
+      cb();
+    }
+    self.watch.set(BatcherState::Closed);
+    let panics = self.config.drop_policy == DropPolicy::PanicIfPending;
+    if panics && values > 0 && !thread::panicking() {
+      panic!("batcher dropped with {} values pending", values);
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl<T, C: BatchContainer<T>> State<T, C> {
+  /// The batch count at which everything appended so far is done.
+  fn flush_target(&self) -> u64 {
+    let mut target = self.dispatched;
+    for (batch, _, _) in &self.ready {
+      target += self.runs(batch.len());
+    }
+    if !self.urgent_batch.is_empty() || !self.urgent_callbacks.is_empty() {
+      target += self.runs(self.urgent_batch.len());
+    }
+    if !self.pending_batch.is_empty() || !self.pending_callbacks.is_empty() {
+      target += self.runs(self.pending_batch.len());
+    }
+    target
+  }
+
+  /// Take the flush callbacks whose batches are done, see `flush_target`.
+  fn take_flushed(&mut self) -> Vec<FlushCb> {
+    let idle = self.is_idle();
+    let completed = self.completed;
+    let (done, waiting) = mem::take(&mut self.flush_callbacks)
+      .into_iter()
+      .partition(|(target, _)| idle || *target <= completed);
+    self.flush_callbacks = waiting;
+    done.into_iter().map(|(_, cb)| cb).collect()
+  }
+
+  /// Whether nothing is running or waiting to run.
+  fn is_idle(&self) -> bool {
+    let callbacks = self.urgent_callbacks.len() + self.pending_callbacks.len();
+    self.in_flight.is_empty()
+      && !self.dispatching
+      && self.queued() == 0
+      && callbacks == 0
+  }
+
+  /// Whether as many batches as may are handed to the run function, or
+  /// the batcher is paused.
+  fn is_busy(&self) -> bool {
+    self.in_flight.len() >= self.max_in_flight || self.paused
+  }
+
+  /// Whether the next batch may start right away.
+  fn is_free(&self) -> bool {
+    !self.is_busy() && !self.dispatching
+  }
+
+  /// Take the done batches whose callbacks may be called. Unless unordered,
+  /// those are the ones before every batch still running or being
+  /// acknowledged by another thread.
+  fn take_acks(&mut self) -> Vec<Ack> {
+    let unordered = self.unordered;
+    let mut acks = Vec::new();
+    for flight in self.in_flight.iter_mut() {
+      if flight.acking && unordered {
+        continue;
+      } else if flight.acking {
+        break;
+      }
+      match flight.finished.take() {
+        Some((outcome, elapsed)) => {
+          flight.acking = true;
+          self.counts.called += flight.callbacks.len() as u64;
+          acks.push(Ack {
+            id: flight.id,
+            callbacks: mem::take(&mut flight.callbacks),
+            len: flight.len,
+            outcome,
+            elapsed,
+          });
+        }
+        None if unordered => {}
+        None => break,
+      }
+    }
+    acks
+  }
+
+  /// Take every batch which did not start running yet, in order.
+  fn take_waiting(&mut self) -> Vec<(C, Vec<Entry>, Meta)> {
+    self.reset_policy();
+    self.opened_at = None;
+    self.pending_weight = 0;
+    let mut batches = vec![(
+      mem::take(&mut self.urgent_batch),
+      mem::take(&mut self.urgent_callbacks),
+      take_meta(&mut self.urgent_meta, Trigger::Ready, self.clock.now()),
+    )];
+    batches.extend(self.ready.drain(..));
+    batches.push((
+      mem::take(&mut self.pending_batch),
+      mem::take(&mut self.pending_callbacks),
+      take_meta(&mut self.pending_meta, Trigger::Ready, self.clock.now()),
+    ));
+    batches.retain(|(batch, callbacks, _)| {
+      !batch.is_empty() || !callbacks.is_empty()
+    });
+    for (batch, callbacks, _) in &batches {
+      self.counts.discarded += batch.len() as u64;
+      self.counts.called += callbacks.len() as u64;
+    }
+    batches
+  }
+
+  /// Take the values and callback appended with `token` out of the batch
+  /// they wait in, telling whether that is the pending batch.
+  fn take_token(&mut self, token: u64) -> Option<(C, Callback, bool)> {
+    let found = self
+      .urgent_callbacks
+      .iter()
+      .position(|e| has_token(e, token));
+    if let Some(at) = found {
+      let (batch, callbacks, meta) = self.lane(true);
+      let (removed, cb) = take_entry(batch, callbacks, at);
+      forget_append(meta, batch, callbacks);
+      return Some((removed, cb, false));
+    }
+    for i in 0..self.ready.len() {
+      let (batch, callbacks, meta) = &mut self.ready[i];
+      if let Some(at) = callbacks.iter().position(|e| has_token(e, token)) {
+        let (removed, cb) = take_entry(batch, callbacks, at);
+        meta.appends = meta.appends.saturating_sub(1);
+        if batch.is_empty() && callbacks.is_empty() {
+          self.ready.remove(i);
+        }
+        return Some((removed, cb, false));
+      }
+    }
+    let at = self
+      .pending_callbacks
+      .iter()
+      .position(|e| has_token(e, token))?;
+    let (batch, callbacks, meta) = self.lane(false);
+    let (removed, cb) = take_entry(batch, callbacks, at);
+    forget_append(meta, batch, callbacks);
+    Some((removed, cb, true))
+  }
+
+  /// Number of values waiting for a batch to run.
+  fn queued(&self) -> usize {
+    let ready: usize = self.ready.iter().map(|(batch, ..)| batch.len()).sum();
+    ready + self.urgent_batch.len() + self.pending_batch.len()
+  }
+
+  /// Add values to the urgent or the pending batch, binding the callback to
+  /// their range. Returns how many values there were.
+  fn extend<I>(&mut self, urgent: bool, val: I, cb: Option<Callback>) -> usize
+  where
+    I: IntoIterator<Item = T>,
+  {
+    let now = self.clock.now();
+    let (batch, callbacks, meta) = self.lane(urgent);
+    meta
+      .get_or_insert_with(|| Meta::new(Trigger::Ready, now))
+      .appends += 1;
+    let start = batch.len();
+    batch.extend(val);
+    let end = batch.len();
+    if let Some(cb) = cb {
+      callbacks.push((start..end, cb));
+      self.counts.bound += 1;
+    }
+    end - start
+  }
+
+  /// Take back the last `count` values added with `extend`, and their
+  /// callback if they had one.
+  fn retract(
+    &mut self,
+    urgent: bool,
+    count: usize,
+    had_cb: bool,
+  ) -> Option<Callback> {
+    let (batch, callbacks, meta) = self.lane(urgent);
+    let len = batch.len();
+    // The values split off are dropped.
+    batch.split_off(len - count);
+    let cb = if had_cb {
+      callbacks.pop().map(|(_, cb)| cb)
+    } else {
+      None
+    };
+    forget_append(meta, batch, callbacks);
+    self.counts.called += cb.is_some() as u64;
+    cb
+  }
+
+  fn lane(&mut self, urgent: bool) -> Lane<'_, C> {
+    if urgent {
+      (
+        &mut self.urgent_batch,
+        &mut self.urgent_callbacks,
+        &mut self.urgent_meta,
+      )
+    } else {
+      (
+        &mut self.pending_batch,
+        &mut self.pending_callbacks,
+        &mut self.pending_meta,
+      )
+    }
+  }
+
+  /// How many runs a batch of `len` values takes.
+  fn runs(&self, len: usize) -> u64 {
+    match self.max_items_per_dispatch {
+      Some(max) if len > max => len.div_ceil(max) as u64,
+      _ => 1,
+    }
+  }
+
+  /// An empty buffer for a new batch.
+  fn fresh(&mut self) -> C {
+    self.spare.pop().unwrap_or_default()
+  }
+
+  /// Close the pending batch before its value at `start`, which starts the
+  /// next one. The values from `start` on are those of the latest append.
+  fn seal_before(&mut self, start: usize) {
+    let rest = self.pending_batch.split_off(start);
+    let at = self
+      .pending_callbacks
+      .iter()
+      .position(|(range, _)| range.start >= start)
+      .unwrap_or(self.pending_callbacks.len());
+    let callbacks = self
+      .pending_callbacks
+      .split_off(at)
+      .into_iter()
+      .map(|(range, cb)| (range.start - start..range.end - start, cb))
+      .collect();
+    if let Some(ref mut meta) = self.pending_meta {
+      meta.appends -= 1;
+    }
+    self.seal();
+    self.pending_batch = rest;
+    self.pending_callbacks = callbacks;
+    let mut meta = Meta::new(Trigger::Ready, self.clock.now());
+    meta.appends = 1;
+    self.pending_meta = Some(meta);
+  }
+
+  /// What the flush policy makes of the pending batch.
+  fn decide(&mut self) -> Decision {
+    match self.policy {
+      Some(ref mut policy) => {
+        policy.on_append(self.pending_batch.as_mut_slice())
+      }
+      None => Decision::Wait,
+    }
+  }
+
+  /// Tell the flush policy the pending batch closed.
+  fn reset_policy(&mut self) {
+    if let Some(ref mut policy) = self.policy {
+      policy.reset();
+    }
+  }
+
+  /// Close the pending batch if its window is over, telling whether there
+  /// was one, see `BatcherBuilder::window`.
+  fn close_window(&mut self, now: Instant) -> bool {
+    if self.window_end.is_none_or(|end| end > now) {
+      return false;
+    }
+    self.window_end = None;
+    if self.pending_batch.is_empty() && self.pending_callbacks.is_empty() {
+      return false;
+    }
+    self.seal();
+    if let Some((_, _, ref mut meta)) = self.ready.back_mut() {
+      meta.trigger = Trigger::Time;
+    }
+    true
+  }
+
+  /// Close the pending batch, so later appends start a new one.
+  fn seal(&mut self) {
+    let sealed = self.take_pending(Trigger::Size);
+    self.ready.push_back(sealed);
+  }
+
+  /// Take the pending batch out of its lane, started by `trigger`, with the
+  /// idempotency key its flush policy supplies, if any.
+  fn take_pending(&mut self, trigger: Trigger) -> (C, Vec<Entry>, Meta) {
+    let key = match self.policy {
+      Some(ref mut policy) => {
+        policy.idempotency_key(self.pending_batch.as_mut_slice())
+      }
+      None => None,
+    };
+    self.reset_policy();
+    self.pending_weight = 0;
+    let fresh = self.fresh();
+    let batch = mem::replace(&mut self.pending_batch, fresh);
+    let callbacks = mem::take(&mut self.pending_callbacks);
+    let mut meta = take_meta(&mut self.pending_meta, trigger, self.clock.now());
+    if let Some(key) = key {
+      meta.key = key;
+    }
+    (batch, callbacks, meta)
+  }
+
+  /// Move the urgent batch, or else the oldest ready batch, or else the
+  /// pending batch, into the running slot, if there is anything to run.
+  /// `trigger` is what starts the pending batch.
+  fn next_batch(&mut self, trigger: Trigger) -> Option<C> {
+    let urgent = !self.urgent_batch.is_empty();
+    let (mut batch, mut callbacks, mut meta) =
+      if urgent || !self.urgent_callbacks.is_empty() {
+        let fresh = self.fresh();
+        let batch = mem::replace(&mut self.urgent_batch, fresh);
+        let meta =
+          take_meta(&mut self.urgent_meta, Trigger::Ready, self.clock.now());
+        (batch, mem::take(&mut self.urgent_callbacks), meta)
+      } else if let Some(ready) = self.ready.pop_front() {
+        ready
+      } else {
+        self.opened_at = None;
+        if self.pending_batch.is_empty() && self.pending_callbacks.is_empty() {
+          return None;
+        }
+        self.take_pending(trigger)
+      };
+    if self.expire(&mut batch, &mut callbacks, &mut meta) {
+      // Everything expired, so there is nothing to run.
+      return self.next_batch(trigger);
+    }
+    Some(self.start(batch, callbacks, meta))
+  }
+
+  /// Take the expired values out of a batch about to start, keeping their
+  /// callbacks for `expired`. Tells whether nothing is left of the batch.
+  fn expire(
+    &mut self,
+    batch: &mut C,
+    callbacks: &mut Vec<Entry>,
+    meta: &mut Meta,
+  ) -> bool {
+    let now = self.clock.now();
+    let mut at = 0;
+    let mut expired = false;
+    while at < callbacks.len() {
+      if !has_expired(&callbacks[at], now) {
+        at += 1;
+        continue;
+      }
+      let (removed, cb) = take_entry(batch, callbacks, at);
+      self.counts.discarded += removed.len() as u64;
+      self.counts.called += 1;
+      meta.appends = meta.appends.saturating_sub(1);
+      self.expired.push((removed.len(), cb));
+      expired = true;
+    }
+    expired && batch.is_empty() && callbacks.is_empty()
+  }
+
+  /// Move a batch into the running slot. What is beyond
+  /// `max_items_per_dispatch` is split off to run next.
+  fn start(&mut self, batch: C, callbacks: Vec<Entry>, meta: Meta) -> C {
+    let now = self.clock.now();
+    let (batch, callbacks) = match self.max_items_per_dispatch {
+      Some(max) if batch.len() > max => {
+        let bound = callbacks.len() as u64;
+        let chunks = chunk::split(batch, callbacks, max, self.chunk_errors);
+        let parts: usize = chunks.iter().map(|(_, cbs)| cbs.len()).sum();
+        self.counts.bound += parts as u64 - bound;
+        let mut chunks = chunks.into_iter();
+        let first = chunks.next().expect("a split batch is never empty");
+        for (batch, callbacks) in chunks.rev() {
+          // Every run is a batch of its own to the backend.
+          let mut meta = meta;
+          meta.key = IdempotencyKey::generate();
+          self.ready.push_front((batch, callbacks, meta));
+        }
+        first
+      }
+      _ => (batch, callbacks),
+    };
+    self.counts.started += batch.len() as u64;
+    self.dispatched += 1;
+    self.in_flight.push_back(Flight {
+      id: self.dispatched,
+      callbacks,
+      meta,
+      len: batch.len(),
+      since: now,
+      beat: now,
+      committed: 0,
+      finished: None,
+      acking: false,
+    });
+    batch
   }
 }
@@ -0,0 +1,180 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::{Rc, Weak};
+use BatchError;
+
+type LocalCb = Box<dyn FnOnce(Result<(), BatchError>)>;
+/// Describing optional callback function of a `LocalBatcher`, which need not
+/// be `Send`.
+pub type LocalCbOption = Option<LocalCb>;
+type LocalRun<T> = Box<dyn FnMut(Vec<T>, LocalDone)>;
+
+/// Batcher for values which are not `Send`, e.g. holding an `Rc`, used on a
+/// single thread.
+///
+/// Like `Batcher` without its settings: the first append runs right away,
+/// and later ones collect in the pending batch until the run function
+/// signals the running batch done through its `LocalDone`, which runs the
+/// next one. Neither the batcher nor the `LocalDone` leave their thread.
+pub struct LocalBatcher<T> {
+  inner: Rc<Inner<T>>,
+}
+
+struct Inner<T> {
+  run: RefCell<LocalRun<T>>,
+  state: RefCell<LocalState<T>>,
+}
+
+struct LocalState<T> {
+  pending_batch: Vec<T>,
+  pending_callbacks: Vec<LocalCb>,
+  /// The callbacks of the running batch, if there is one.
+  running: Option<Vec<LocalCb>>,
+  /// Whether the run function is being called, see `Batcher::dispatch`.
+  dispatching: bool,
+}
+
+impl<T: 'static> LocalBatcher<T> {
+  /// Create a new local batcher with a run function.
+  pub fn new<F>(run: F) -> Self
+  where
+    F: FnMut(Vec<T>, LocalDone) + 'static,
+  {
+    LocalBatcher {
+      inner: Rc::new(Inner {
+        run: RefCell::new(Box::new(run)),
+        state: RefCell::new(LocalState {
+          pending_batch: Vec::new(),
+          pending_callbacks: Vec::new(),
+          running: None,
+          dispatching: false,
+        }),
+      }),
+    }
+  }
+
+  /// Accept an array of values and a callback, called once the batch
+  /// containing the values has been run.
+  pub fn append(&self, val: Vec<T>, cb: LocalCbOption) {
+    let mut state = self.inner.state.borrow_mut();
+    state.pending_batch.extend(val);
+    state.pending_callbacks.extend(cb);
+    let idle = state.running.is_none() && !state.dispatching;
+    drop(state);
+    if idle {
+      dispatch(&self.inner);
+    }
+  }
+
+  /// Accept a single value and a callback, see `append`.
+  pub fn append_one(&self, val: T, cb: LocalCbOption) {
+    self.append(vec![val], cb)
+  }
+
+  /// How many values wait for a batch.
+  pub fn pending(&self) -> usize {
+    self.inner.state.borrow().pending_batch.len()
+  }
+
+  /// Whether a batch is running.
+  pub fn is_running(&self) -> bool {
+    self.inner.state.borrow().running.is_some()
+  }
+}
+
+/// Run the pending batch, and the ones after it while the run function
+/// signals them done before returning.
+fn dispatch<T: 'static>(inner: &Rc<Inner<T>>) {
+  loop {
+    let batch = {
+      let mut state = inner.state.borrow_mut();
+      let empty =
+        state.pending_batch.is_empty() && state.pending_callbacks.is_empty();
+      if state.running.is_some() || empty {
+        state.dispatching = false;
+        return;
+      }
+      state.dispatching = true;
+      state.running = Some(mem::take(&mut state.pending_callbacks));
+      mem::take(&mut state.pending_batch)
+    };
+    let weak: Weak<dyn Complete> = Rc::downgrade(inner) as Weak<Inner<T>>;
+    let done = LocalDone {
+      batcher: Some(weak),
+    };
+    (inner.run.borrow_mut())(batch, done);
+  }
+}
+
+/// Implemented by local batchers so `LocalDone` does not carry their item
+/// type.
+trait Complete {
+  fn complete(self: Rc<Self>, res: Result<(), BatchError>);
+}
+
+impl<T: 'static> Complete for Inner<T> {
+  fn complete(self: Rc<Self>, res: Result<(), BatchError>) {
+    let callbacks = self.state.borrow_mut().running.take();
+    for cb in callbacks.into_iter().flatten() {
+      cb(res.clone());
+    }
+    if !self.state.borrow().dispatching {
+      dispatch(&self);
+    }
+  }
+}
+
+impl<T> Drop for Inner<T> {
+  fn drop(&mut self) {
+    let state = self.state.get_mut();
+    let running = state.running.take().into_iter().flatten();
+    for cb in running.chain(state.pending_callbacks.drain(..)) {
+      cb(Err(BatchError::Closed));
+    }
+  }
+}
+
+/// Handle passed to the run function of a `LocalBatcher` with every batch,
+/// see `Done`. Dropping it without a result fails the batch with
+/// `BatchError::DoneDropped`.
+pub struct LocalDone {
+  /// Taken once the result is signalled.
+  batcher: Option<Weak<dyn Complete>>,
+}
+
+impl LocalDone {
+  /// The batch ran successfully.
+  pub fn ok(self) {
+    self.finish(Ok(()))
+  }
+
+  /// The batch failed.
+  pub fn err(self, err: BatchError) {
+    self.finish(Err(err))
+  }
+
+  /// The batch finished with `res`.
+  pub fn finish(mut self, res: Result<(), BatchError>) {
+    self.complete(res)
+  }
+
+  fn complete(&mut self, res: Result<(), BatchError>) {
+    let batcher = self.batcher.take().and_then(|batcher| batcher.upgrade());
+    if let Some(batcher) = batcher {
+      batcher.complete(res)
+    }
+  }
+}
+
+impl Drop for LocalDone {
+  fn drop(&mut self) {
+    self.complete(Err(BatchError::DoneDropped))
+  }
+}
+
+impl fmt::Debug for LocalDone {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("LocalDone").finish()
+  }
+}
@@ -0,0 +1,20 @@
+use builder::Config;
+use std::fmt;
+
+impl Config {
+  /// Log an event of the batcher, at the level for failures if it is one,
+  /// see `BatcherBuilder::log_levels`. Does nothing without the `log`
+  /// feature.
+  #[cfg_attr(not(feature = "log"), allow(unused_variables))]
+  pub(crate) fn log(&self, failure: bool, args: fmt::Arguments) {
+    #[cfg(feature = "log")]
+    {
+      let (event, failed) = self.log_levels();
+      let level = if failure { failed } else { event };
+      if log_enabled!(target: "atomic_batcher", level) {
+        let name = self.name.as_deref().unwrap_or("batcher");
+        log!(target: "atomic_batcher", level, "{}: {}", name, args);
+      }
+    }
+  }
+}
@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+use {BatchError, Batcher, BatcherBuilder, BatcherHandle, CbOption};
+use {Done, FlushOutcome};
+
+type Slot<T> = Arc<Mutex<Option<(Vec<T>, Done)>>>;
+
+/// Batcher driven by hand, for deterministic tests of code built on a
+/// batcher.
+///
+/// Batches are not run anywhere: the running batch waits in the manual
+/// batcher until `complete_current` finishes it, which starts the next one
+/// right away on the calling thread. Hand the code under test `batcher` or
+/// `handle`.
+pub struct ManualBatcher<T> {
+  batcher: Arc<Batcher<T>>,
+  current: Slot<T>,
+}
+
+impl<T: Send + 'static> ManualBatcher<T> {
+  /// Create a manual batcher with the default configuration.
+  pub fn new() -> Self {
+    ManualBatcher::with_builder(BatcherBuilder::new())
+  }
+
+  /// Create a manual batcher configured with `builder`. Settings kept by
+  /// the timer, such as `max_batch_delay`, still follow the clock; use
+  /// `tick` rather than waiting for them.
+  ///
+  /// # Panics
+  /// Panics if the configuration is invalid, see `BatcherBuilder::try_build`.
+  pub fn with_builder(builder: BatcherBuilder<T>) -> Self {
+    let current: Slot<T> = Arc::new(Mutex::new(None));
+    let slot = current.clone();
+    let batcher = builder.build(move |val: Vec<T>, done: Done| {
+      *slot.lock().unwrap() = Some((val, done));
+    });
+    ManualBatcher { batcher, current }
+  }
+
+  /// Accept an array of values and a callback, see `Batcher::append`.
+  pub fn append(&self, val: Vec<T>, cb: CbOption) {
+    self.batcher.append(val, cb)
+  }
+
+  /// Start the next batch now if nothing keeps it waiting, see
+  /// `Batcher::try_flush`.
+  pub fn tick(&self) -> FlushOutcome {
+    self.batcher.try_flush()
+  }
+
+  /// Copy the values of the running batch, if there is one.
+  pub fn current(&self) -> Option<Vec<T>>
+  where
+    T: Clone,
+  {
+    let current = self.current.lock().unwrap();
+    current.as_ref().map(|(val, _)| val.clone())
+  }
+
+  /// Finish the running batch with `res`. Returns whether there was one.
+  pub fn complete_current(&self, res: Result<(), BatchError>) -> bool {
+    // Finishing may start the next batch, which fills the slot again.
+    let current = self.current.lock().unwrap().take();
+    match current {
+      Some((_, done)) => {
+        done.finish(res);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// The batcher, for the code under test.
+  pub fn batcher(&self) -> &Arc<Batcher<T>> {
+    &self.batcher
+  }
+
+  /// A handle to the batcher, for the code under test.
+  pub fn handle(&self) -> BatcherHandle<T> {
+    self.batcher.handle()
+  }
+}
+
+impl<T: Send + 'static> Default for ManualBatcher<T> {
+  fn default() -> Self {
+    ManualBatcher::new()
+  }
+}
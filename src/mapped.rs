@@ -0,0 +1,70 @@
+use std::sync::Arc;
+use {BatchError, BatchTicket, Batcher, CbOption};
+
+type Map<T, U> = Arc<dyn Fn(T) -> U + Send + Sync>;
+
+/// Front of a batcher of `U` values accepting `T` values, converting each
+/// as it is appended, see `Batcher::map_items`.
+///
+/// Cloning it is cheap, and every clone appends to the same batcher.
+pub struct MappedBatcher<T, U> {
+  batcher: Arc<Batcher<U>>,
+  map: Map<T, U>,
+}
+
+impl<T, U: Send + 'static> MappedBatcher<T, U> {
+  pub(crate) fn new(batcher: Arc<Batcher<U>>, map: Map<T, U>) -> Self {
+    MappedBatcher { batcher, map }
+  }
+
+  /// Accept an array of values and a callback, see `Batcher::append`.
+  pub fn append(&self, val: Vec<T>, cb: CbOption) {
+    self.append_iter(val, cb)
+  }
+
+  /// Accept any iterator of values, see `Batcher::append_iter`.
+  pub fn append_iter<I>(&self, val: I, cb: CbOption)
+  where
+    I: IntoIterator<Item = T>,
+  {
+    let map = &*self.map;
+    self.batcher.append_iter(val.into_iter().map(map), cb)
+  }
+
+  /// Accept a single value, see `Batcher::append_one`.
+  pub fn append_one(&self, val: T, cb: CbOption) {
+    self.batcher.append_one((self.map)(val), cb)
+  }
+
+  /// Accept an array of values, returning a ticket which resolves once
+  /// their batch ran, see `Batcher::append_ticket`.
+  pub fn append_ticket(&self, val: Vec<T>) -> BatchTicket {
+    let val = val.into_iter().map(&*self.map).collect();
+    self.batcher.append_ticket(val)
+  }
+
+  /// Accept an array of values and block until their batch ran, see
+  /// `Batcher::append_and_wait`.
+  pub fn append_and_wait(&self, val: Vec<T>) -> Result<(), BatchError> {
+    self.append_ticket(val).wait()
+  }
+
+  /// Block until every value appended so far has run, see `Batcher::flush`.
+  pub fn flush(&self) {
+    self.batcher.flush()
+  }
+
+  /// The batcher the values go to.
+  pub fn batcher(&self) -> &Arc<Batcher<U>> {
+    &self.batcher
+  }
+}
+
+impl<T, U> Clone for MappedBatcher<T, U> {
+  fn clone(&self) -> Self {
+    MappedBatcher {
+      batcher: self.batcher.clone(),
+      map: self.map.clone(),
+    }
+  }
+}
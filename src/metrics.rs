@@ -0,0 +1,165 @@
+use std::fmt;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use BatchError;
+
+/// How quickly the rate in `BatcherStats` follows changes.
+const RATE_WINDOW: f64 = 5.0;
+
+/// Counters describing how a batcher has been doing, see
+/// `Batcher::metrics`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BatcherMetrics {
+  /// Values accepted by appends.
+  pub appended: u64,
+  /// Batches handed to the run function.
+  pub dispatched: u64,
+  /// Values handed to the run function.
+  pub dispatched_items: u64,
+  /// Batches which are done.
+  pub completed: u64,
+  /// Batches which are done with at least one error.
+  pub failed: u64,
+  /// Callbacks which got an error.
+  pub callback_failures: u64,
+  /// Values waiting for a batch to run.
+  pub pending: usize,
+  /// Total time from handing batches to the run function until they were
+  /// done.
+  pub in_flight: Duration,
+}
+
+impl BatcherMetrics {
+  /// The average number of values per dispatched batch.
+  pub fn average_batch_size(&self) -> f64 {
+    if self.dispatched == 0 {
+      return 0.0;
+    }
+    self.dispatched_items as f64 / self.dispatched as f64
+  }
+
+  /// The average time from dispatching a batch until it was done.
+  pub fn average_in_flight(&self) -> Option<Duration> {
+    if self.completed == 0 {
+      return None;
+    }
+    Some(self.in_flight / self.completed as u32)
+  }
+}
+
+/// A summary of how a batcher is doing, see `Batcher::stats`. Its
+/// `Display` fits on one line, e.g. for periodic logging.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct BatcherStats {
+  /// The counters of `Batcher::metrics`.
+  pub metrics: BatcherMetrics,
+  /// Batches handed to the run function which are not done yet.
+  pub running: usize,
+  /// Values in those batches.
+  pub in_flight_items: usize,
+  /// Values of those batches reported done, see `Done::progress`.
+  pub committed_items: usize,
+  /// Values done per second, as a moving average over a few seconds.
+  pub items_per_second: f64,
+  /// How long the oldest value waiting for a batch has been waiting.
+  pub oldest_pending: Option<Duration>,
+}
+
+impl fmt::Display for BatcherStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} pending", self.metrics.pending)?;
+    if let Some(age) = self.oldest_pending {
+      write!(f, " (oldest {:?})", age)?;
+    }
+    write!(
+      f,
+      ", {} running ({} values), {} dispatched, {} done, {} failed, \
+       {:.1} values/s",
+      self.running,
+      self.in_flight_items,
+      self.metrics.dispatched,
+      self.metrics.completed,
+      self.metrics.failed,
+      self.items_per_second,
+    )
+  }
+}
+
+/// Exponentially weighted moving average of values done per second.
+#[derive(Debug)]
+pub(crate) struct Ewma {
+  rate: f64,
+  since: Instant,
+}
+
+impl Ewma {
+  pub(crate) fn new(now: Instant) -> Self {
+    Ewma {
+      rate: 0.0,
+      since: now,
+    }
+  }
+
+  /// Account for `count` values done at `now`.
+  pub(crate) fn record(&mut self, count: usize, now: Instant) {
+    let elapsed = now.duration_since(self.since).as_secs_f64();
+    self.since = now;
+    if elapsed > 0.0 {
+      let weight = 1.0 - (-elapsed / RATE_WINDOW).exp();
+      self.rate += weight * (count as f64 / elapsed - self.rate);
+    }
+  }
+
+  /// The rate at `now`, decayed for the time since anything was last done.
+  pub(crate) fn rate(&self, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(self.since).as_secs_f64();
+    self.rate * (-elapsed / RATE_WINDOW).exp()
+  }
+}
+
+/// Receives an event for every append, flush and batch, e.g. to feed a
+/// metrics library or to open a `tracing` span per batch id.
+///
+/// It is called from whichever thread appends, dispatches or completes, so
+/// it should not block.
+pub trait MetricsSink: Send + Sync {
+  /// `count` values were accepted. Reported once the append is done, which
+  /// may be after their batch ran.
+  fn on_append(&self, _count: usize) {}
+
+  /// A flush started waiting for the values appended so far.
+  fn on_flush(&self) {}
+
+  /// Batch number `id`, of `size` values, is handed to the run function,
+  /// with `pending` values left waiting. Batches are numbered from 1.
+  fn on_dispatch(&self, _id: u64, _size: usize, _pending: usize) {}
+
+  /// Batch number `id`, of `size` values, is done after `elapsed`, with its
+  /// first error.
+  fn on_complete(
+    &self,
+    _id: u64,
+    _size: usize,
+    _elapsed: Duration,
+    _res: &Result<(), BatchError>,
+  ) {
+  }
+}
+
+/// A `MetricsSink` shared by the batcher's configuration.
+#[derive(Clone)]
+pub(crate) struct SharedSink(pub(crate) Arc<dyn MetricsSink>);
+
+impl fmt::Debug for SharedSink {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("MetricsSink")
+  }
+}
+
+// The batcher keeps no state of its own in the sink, so a panic inside it
+// cannot leave the batcher broken.
+impl UnwindSafe for SharedSink {}
+impl RefUnwindSafe for SharedSink {}
@@ -0,0 +1,38 @@
+use std::time::Instant;
+use IdempotencyKey;
+
+/// What a `FlushPolicy` makes of the pending batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Decision {
+  /// Hold the batch back for more values.
+  Wait,
+  /// Close the batch, so it runs next.
+  Flush,
+}
+
+/// Custom rule for when the pending batch closes, see
+/// `BatcherBuilder::flush_policy`, e.g. once a sentinel value arrives.
+///
+/// It works alongside `max_batch_size`, `max_batch_delay` and the other
+/// limits: the batch closes once any of them says so. Each batcher gets a
+/// clone of its own, which is called with the batcher locked, so it should
+/// be quick and must not use the batcher.
+pub trait FlushPolicy<T> {
+  /// Decide on the pending `batch`, which just got values.
+  fn on_append(&mut self, batch: &[T]) -> Decision;
+
+  /// When the pending batch should run at the latest, if ever.
+  fn next_deadline(&self) -> Option<Instant> {
+    None
+  }
+
+  /// The key of the pending `batch`, which is closing, if it is not to get
+  /// a generated one, see `Done::idempotency_key`. Called before `reset`.
+  fn idempotency_key(&mut self, _batch: &[T]) -> Option<IdempotencyKey> {
+    None
+  }
+
+  /// The pending batch closed, and the next values start a new one.
+  fn reset(&mut self) {}
+}
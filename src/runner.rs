@@ -0,0 +1,70 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
+use {Done, Layer};
+
+/// Runs the batches of a batcher, see `Batcher::with_runner`.
+///
+/// Every `FnMut(Vec<T>, Done)` closure is a runner; implement the trait
+/// directly for runners with their own state or for wrapping other runners.
+pub trait BatchRunner<T> {
+  /// Run a batch, signalling its result through `done`. Never re-entered,
+  /// see `Batcher::new`.
+  fn run(&mut self, batch: Vec<T>, done: Done);
+
+  /// Wrap this runner with `layer`.
+  fn layer<L: Layer<Self>>(self, layer: L) -> L::Runner
+  where
+    Self: Sized,
+  {
+    layer.layer(self)
+  }
+}
+
+impl<T, F> BatchRunner<T> for F
+where
+  F: FnMut(Vec<T>, Done),
+{
+  fn run(&mut self, batch: Vec<T>, done: Done) {
+    self(batch, done)
+  }
+}
+
+/// Runner handing batches to a thread of their own, see `Batcher::spawn`.
+pub(crate) struct Dispatcher<T> {
+  batches: mpsc::Sender<(Vec<T>, Done)>,
+}
+
+impl<T: Send + 'static> Dispatcher<T> {
+  /// Spawn the thread calling `runner`. It exits once the dispatcher is
+  /// dropped.
+  pub(crate) fn spawn<R>(name: Option<&str>, mut runner: R) -> Self
+  where
+    R: BatchRunner<T> + Send + 'static,
+  {
+    let name = match name {
+      Some(name) => format!("{}-dispatcher", name),
+      None => String::from("atomic-batcher-dispatcher"),
+    };
+    let (batches, rx) = mpsc::channel::<(Vec<T>, Done)>();
+    thread::Builder::new()
+      .name(name)
+      .spawn(move || {
+        for (batch, done) in rx {
+          // Keep running later batches if the runner panics.
+          let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            runner.run(batch, done);
+          }));
+        }
+      })
+      .expect("failed to spawn the batcher dispatcher thread");
+    Dispatcher { batches }
+  }
+}
+
+impl<T> BatchRunner<T> for Dispatcher<T> {
+  fn run(&mut self, batch: Vec<T>, done: Done) {
+    // The thread only exits once the dispatcher is dropped.
+    let _ = self.batches.send((batch, done));
+  }
+}
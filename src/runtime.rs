@@ -0,0 +1,35 @@
+use futures::Future;
+use std::time::Instant;
+use tokio;
+use tokio::timer::Delay;
+
+/// Future spawned or returned by a `Runtime`.
+pub type RuntimeFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// The executor and timer a `TokioBatcher` runs on, see
+/// `BatcherBuilder::build_on`.
+///
+/// Implement it to run the batcher on an executor other than tokio's, e.g.
+/// through a futures compatibility layer.
+pub trait Runtime: Send + Sync + 'static {
+  /// Run `future` to completion in the background.
+  fn spawn(&self, future: RuntimeFuture);
+
+  /// A future resolving at `at`.
+  fn delay(&self, at: Instant) -> RuntimeFuture;
+}
+
+/// The tokio executor and timer. Spawning needs a running tokio runtime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+impl Runtime for TokioRuntime {
+  fn spawn(&self, future: RuntimeFuture) {
+    tokio::spawn(future);
+  }
+
+  fn delay(&self, at: Instant) -> RuntimeFuture {
+    // A timer error means the runtime shuts down, so don't wait.
+    Box::new(Delay::new(at).then(|_| Ok(())))
+  }
+}
@@ -0,0 +1,102 @@
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, TryLockError};
+use BatchError;
+
+type ScopedRun<'a, T> =
+  Box<dyn FnMut(Vec<T>) -> Result<(), BatchError> + Send + 'a>;
+
+/// Batcher for values borrowed from the enclosing scope, see
+/// `BatcherBuilder::scope`.
+///
+/// Batches run on an appending thread, one at a time: an append which finds
+/// a batch running leaves its values to the thread running it.
+pub struct ScopedBatcher<'a, T> {
+  run: Mutex<ScopedRun<'a, T>>,
+  pending: Mutex<Vec<T>>,
+  max_batch_size: Option<usize>,
+  /// The first error of a batch.
+  error: Mutex<Option<BatchError>>,
+  /// Whether appends or flushes wait for the pending batch to be checked.
+  missed: AtomicBool,
+  /// Whether a flush waits for every pending value to run.
+  flushing: AtomicBool,
+}
+
+impl<'a, T: Send> ScopedBatcher<'a, T> {
+  pub(crate) fn new(
+    run: ScopedRun<'a, T>,
+    max_batch_size: Option<usize>,
+  ) -> Self {
+    ScopedBatcher {
+      run: Mutex::new(run),
+      pending: Mutex::new(Vec::new()),
+      max_batch_size,
+      error: Mutex::new(None),
+      missed: AtomicBool::new(false),
+      flushing: AtomicBool::new(false),
+    }
+  }
+
+  /// Accept an array of values, running the pending batch if it is full.
+  pub fn append(&self, val: Vec<T>) {
+    self.pending.lock().unwrap().extend(val);
+    self.drain(false);
+  }
+
+  /// Run every value appended so far, unless another thread is running a
+  /// batch, which then runs them.
+  pub fn flush(&self) {
+    self.drain(true);
+  }
+
+  /// Run the pending batch while it is full, or while it has values if
+  /// `all`, and nothing else is running.
+  fn drain(&self, all: bool) {
+    if all {
+      self.flushing.store(true, Ordering::SeqCst);
+    }
+    // Set before trying the lock, so the thread holding it sees the flags
+    // once it lets go, even if it found nothing to run.
+    self.missed.store(true, Ordering::SeqCst);
+    while self.missed.load(Ordering::SeqCst) {
+      let mut run = match self.run.try_lock() {
+        Ok(run) => run,
+        Err(TryLockError::WouldBlock) => return,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+      };
+      self.missed.store(false, Ordering::SeqCst);
+      let all = self.flushing.swap(false, Ordering::SeqCst);
+      while let Some(batch) = self.take(all) {
+        if let Err(err) = run(batch) {
+          self.error.lock().unwrap().get_or_insert(err);
+        }
+      }
+      drop(run);
+    }
+  }
+
+  fn take(&self, all: bool) -> Option<Vec<T>> {
+    let mut pending = self.pending.lock().unwrap();
+    let full = self.max_batch_size.is_some_and(|max| pending.len() >= max);
+    if pending.is_empty() || !(all || full) {
+      return None;
+    }
+    let len = match self.max_batch_size {
+      Some(max) => max.min(pending.len()),
+      None => pending.len(),
+    };
+    let rest = pending.split_off(len);
+    Some(mem::replace(&mut *pending, rest))
+  }
+
+  /// Run what is left and return the first error of a batch.
+  pub(crate) fn finish(self) -> Result<(), BatchError> {
+    // No other thread borrows the batcher any more.
+    self.drain(true);
+    match self.error.into_inner().unwrap() {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+}
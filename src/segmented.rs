@@ -0,0 +1,169 @@
+use std::collections::{vec_deque, VecDeque};
+use std::iter::{self, FromIterator};
+use std::mem;
+use BatchContainer;
+
+/// How many values a segment holds.
+const SEGMENT_LEN: usize = 4096;
+
+/// Container keeping values in segments of a few thousand, for batchers
+/// gathering tens of millions of values, see `BatcherBuilder::build_into`.
+/// It grows without moving the values it holds, so it never needs twice
+/// their memory, and splitting it, e.g. to close a batch or for
+/// `max_items_per_dispatch`, moves at most one segment. Cleared segments are
+/// filled again, see `BatcherBuilder::build_pooled_into`.
+///
+/// Flush policies and `before_dispatch` need the values in one slice, which
+/// merges the segments, as does `coalesce`.
+#[derive(Debug, Clone)]
+pub struct SegmentedQueue<T> {
+  segments: VecDeque<Vec<T>>,
+  len: usize,
+  /// Cleared segments, to fill before allocating new ones.
+  free: Vec<Vec<T>>,
+}
+
+impl<T> SegmentedQueue<T> {
+  /// Create an empty queue.
+  pub fn new() -> Self {
+    SegmentedQueue {
+      segments: VecDeque::new(),
+      len: 0,
+      free: Vec::new(),
+    }
+  }
+
+  /// The values in order, one segment at a time, e.g. for vectored writes.
+  pub fn segments(&self) -> impl Iterator<Item = &[T]> + '_ {
+    self.segments.iter().map(|segment| &segment[..])
+  }
+
+  fn push(&mut self, val: T) {
+    let full = self
+      .segments
+      .back()
+      .is_none_or(|segment| segment.len() == segment.capacity());
+    if full {
+      let segment = self
+        .free
+        .pop()
+        .unwrap_or_else(|| Vec::with_capacity(SEGMENT_LEN));
+      self.segments.push_back(segment);
+    }
+    self
+      .segments
+      .back_mut()
+      .expect("a segment with room")
+      .push(val);
+    self.len += 1;
+  }
+}
+
+impl<T> BatchContainer<T> for SegmentedQueue<T> {
+  type Iter<'a>
+    = iter::Flatten<vec_deque::Iter<'a, Vec<T>>>
+  where
+    T: 'a;
+
+  fn len(&self) -> usize {
+    self.len
+  }
+
+  fn iter(&self) -> Self::Iter<'_> {
+    self.segments.iter().flatten()
+  }
+
+  fn split_off(&mut self, at: usize) -> Self {
+    assert!(at <= self.len, "split index out of bounds");
+    let mut start = 0;
+    let mut index = 0;
+    while index < self.segments.len() {
+      let len = self.segments[index].len();
+      if start + len > at {
+        break;
+      }
+      start += len;
+      index += 1;
+    }
+    let mut rest = self.segments.split_off(index);
+    if at > start {
+      // Only the segment holding `at` is split, the later ones move whole.
+      let tail = rest[0].split_off(at - start);
+      self.segments.push_back(mem::replace(&mut rest[0], tail));
+    }
+    let len = self.len - at;
+    self.len = at;
+    SegmentedQueue {
+      segments: rest,
+      len,
+      free: Vec::new(),
+    }
+  }
+
+  fn clear(&mut self) {
+    for mut segment in self.segments.drain(..) {
+      segment.clear();
+      self.free.push(segment);
+    }
+    self.len = 0;
+  }
+
+  fn as_mut_slice(&mut self) -> &mut [T] {
+    if self.segments.len() > 1 {
+      let mut values = Vec::with_capacity(self.len);
+      for segment in self.segments.drain(..) {
+        values.extend(segment);
+      }
+      self.segments.push_back(values);
+    }
+    match self.segments.front_mut() {
+      Some(segment) => segment,
+      None => &mut [],
+    }
+  }
+
+  fn from_vec(values: Vec<T>) -> Self {
+    let mut queue = SegmentedQueue::new();
+    if !values.is_empty() {
+      queue.len = values.len();
+      queue.segments.push_back(values);
+    }
+    queue
+  }
+
+  fn into_vec(mut self) -> Vec<T> {
+    self.as_mut_slice();
+    self.segments.pop_front().unwrap_or_default()
+  }
+}
+
+impl<T> Default for SegmentedQueue<T> {
+  fn default() -> Self {
+    SegmentedQueue::new()
+  }
+}
+
+impl<T> Extend<T> for SegmentedQueue<T> {
+  fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+    for val in iter {
+      self.push(val);
+    }
+  }
+}
+
+impl<T> FromIterator<T> for SegmentedQueue<T> {
+  fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+    let mut queue = SegmentedQueue::new();
+    queue.extend(iter);
+    queue
+  }
+}
+
+impl<T> IntoIterator for SegmentedQueue<T> {
+  type Item = T;
+  type IntoIter = iter::Flatten<vec_deque::IntoIter<Vec<T>>>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.segments.into_iter().flatten()
+  }
+}
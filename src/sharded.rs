@@ -0,0 +1,195 @@
+use std::mem;
+use std::sync::{mpsc, Arc, Mutex};
+use {BatchError, BatchRunner, BatchTicket, Batcher, BatcherBuilder};
+use {CbOption, ChunkErrors, ConfigError, Done};
+
+type Hasher<T> = Box<dyn Fn(&T) -> u64 + Send + Sync>;
+
+/// Batcher spreading values over a fixed number of shards by a hash of
+/// each value.
+///
+/// Every shard is a batcher with a runner of its own, so the shards run
+/// their batches at the same time, while the values of a shard keep their
+/// order. Unlike `KeyedBatcher`, the shards exist from the start.
+pub struct ShardedBatcher<T> {
+  shards: Vec<Arc<Batcher<T>>>,
+  hasher: Hasher<T>,
+  errors: ChunkErrors,
+}
+
+impl<T: Send + 'static> ShardedBatcher<T> {
+  /// Create a sharded batcher with `shards` shards and the default
+  /// configuration, sharing a run function which gets the shard number.
+  ///
+  /// # Panics
+  /// Panics if `shards` is 0.
+  pub fn new<H, F>(shards: usize, hasher: H, run: F) -> Self
+  where
+    H: Fn(&T) -> u64 + Send + Sync + 'static,
+    F: Fn(usize, Vec<T>, Done) + Send + Sync + 'static,
+  {
+    let run = Arc::new(run);
+    let runners = (0..shards)
+      .map(|shard| {
+        let run = run.clone();
+        move |val: Vec<T>, done: Done| run(shard, val, done)
+      })
+      .collect();
+    match ShardedBatcher::with_runners(BatcherBuilder::new(), hasher, runners) {
+      Ok(batcher) => batcher,
+      Err(err) => panic!("invalid sharded batcher: {}", err),
+    }
+  }
+
+  /// Create a sharded batcher with one shard per runner, configuring every
+  /// shard with `builder`. Its `chunk_errors` also picks the error of values
+  /// which failed on several shards.
+  pub fn with_runners<H, R>(
+    builder: BatcherBuilder<T>,
+    hasher: H,
+    runners: Vec<R>,
+  ) -> Result<Self, ConfigError>
+  where
+    H: Fn(&T) -> u64 + Send + Sync + 'static,
+    R: BatchRunner<T> + Send + 'static,
+  {
+    if runners.is_empty() {
+      return Err(ConfigError::ZeroShards);
+    }
+    let errors = builder.chunk_errors_policy();
+    let mut shards = Vec::with_capacity(runners.len());
+    for runner in runners {
+      shards.push(builder.clone().try_build_runner(runner)?);
+    }
+    Ok(ShardedBatcher {
+      shards,
+      hasher: Box::new(hasher),
+      errors,
+    })
+  }
+
+  /// The number of shards.
+  pub fn shards(&self) -> usize {
+    self.shards.len()
+  }
+
+  /// The shard `val` goes to.
+  pub fn shard_of(&self, val: &T) -> usize {
+    ((self.hasher)(val) % self.shards.len() as u64) as usize
+  }
+
+  /// The batcher of shard `shard`.
+  ///
+  /// # Panics
+  /// Panics if there is no such shard.
+  pub fn shard(&self, shard: usize) -> &Arc<Batcher<T>> {
+    &self.shards[shard]
+  }
+
+  /// Accept an array of values and a callback, see `Batcher::append`. The
+  /// callback is called once the values of every shard ran, with the error
+  /// of the first shard which failed by default.
+  pub fn append(&self, val: Vec<T>, cb: CbOption) {
+    let mut parts: Vec<Vec<T>> = self.shards.iter().map(|_| vec![]).collect();
+    for val in val {
+      parts[self.shard_of(&val)].push(val);
+    }
+    let used = parts.iter().filter(|part| !part.is_empty()).count();
+    let joint = cb.map(|cb| {
+      Arc::new(Mutex::new(Joint {
+        left: used.max(1),
+        failures: Vec::new(),
+        cb: Some(cb),
+        errors: self.errors,
+      }))
+    });
+    if used == 0 {
+      let cb = joint.map(|joint| part_cb(joint, 0));
+      return self.shards[0].append(vec![], cb);
+    }
+    for (shard, part) in parts.into_iter().enumerate() {
+      if !part.is_empty() {
+        let cb = joint.clone().map(|joint| part_cb(joint, shard));
+        self.shards[shard].append(part, cb);
+      }
+    }
+  }
+
+  /// Accept a single value, see `Batcher::append_one`.
+  pub fn append_one(&self, val: T, cb: CbOption) {
+    let shard = self.shard_of(&val);
+    self.shards[shard].append_one(val, cb)
+  }
+
+  /// Accept an array of values, returning a ticket which resolves once the
+  /// values of every shard ran.
+  pub fn append_ticket(&self, val: Vec<T>) -> BatchTicket {
+    let (tx, rx) = mpsc::channel();
+    self.append(
+      val,
+      Some(Box::new(move |res| {
+        let _ = tx.send(res);
+      })),
+    );
+    BatchTicket::new(rx)
+  }
+
+  /// Accept an array of values and block until they ran, see
+  /// `Batcher::append_and_wait`.
+  pub fn append_and_wait(&self, val: Vec<T>) -> Result<(), BatchError> {
+    self.append_ticket(val).wait()
+  }
+
+  /// Block until every value appended so far, to any shard, has run.
+  pub fn flush(&self) {
+    for shard in &self.shards {
+      shard.flush();
+    }
+  }
+
+  /// Close every shard, see `Batcher::close`. Returns the first error of
+  /// their final batches.
+  pub fn close(&self) -> Result<(), BatchError> {
+    let mut res = Ok(());
+    for shard in &self.shards {
+      if let Err(err) = shard.close() {
+        res = res.and(Err(err));
+      }
+    }
+    res
+  }
+}
+
+/// A callback of `ShardedBatcher::append` and the shards it waits for.
+struct Joint {
+  left: usize,
+  /// The error of each shard which failed, with the shard.
+  failures: Vec<(usize, BatchError)>,
+  cb: CbOption,
+  errors: ChunkErrors,
+}
+
+/// The callback of the values of one shard.
+fn part_cb(
+  joint: Arc<Mutex<Joint>>,
+  shard: usize,
+) -> Box<dyn FnOnce(Result<(), BatchError>) + Send> {
+  Box::new(move |res| {
+    let mut joint = joint.lock().unwrap();
+    if let Err(err) = res {
+      joint.failures.push((shard, err));
+    }
+    joint.left -= 1;
+    if joint.left > 0 {
+      return;
+    }
+    let mut failures = mem::take(&mut joint.failures);
+    let cb = joint.cb.take();
+    let errors = joint.errors;
+    drop(joint);
+    failures.sort_by_key(|(shard, _)| *shard);
+    if let Some(cb) = cb {
+      cb(errors.pick(failures.into_iter().map(|(_, err)| err).collect()));
+    }
+  })
+}
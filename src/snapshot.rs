@@ -0,0 +1,26 @@
+/// Copy of the values waiting in a batcher, see `Batcher::snapshot`.
+///
+/// Persist the values in any format, and hand them to a new batcher with
+/// `Batcher::restore` after a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PendingSnapshot<T> {
+  values: Vec<T>,
+}
+
+impl<T> PendingSnapshot<T> {
+  /// The values, in the order they would run in.
+  pub fn values(&self) -> &[T] {
+    &self.values
+  }
+
+  /// Take the values out of the snapshot.
+  pub fn into_values(self) -> Vec<T> {
+    self.values
+  }
+}
+
+impl<T> From<Vec<T>> for PendingSnapshot<T> {
+  fn from(values: Vec<T>) -> Self {
+    PendingSnapshot { values }
+  }
+}
@@ -0,0 +1,135 @@
+use futures::sync::mpsc;
+use futures::task::{self, Task};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use std::sync::{Arc, Mutex};
+use {AppendError, BatchError, Batcher, Done};
+
+/// `Sink` appending each item to a batcher, see `Batcher::sink`.
+///
+/// `poll_complete` resolves once the batches of every item sent so far are
+/// done, in whichever order they finish, failing with the first error one of
+/// them had.
+pub struct BatchSink<T> {
+  batcher: Arc<Batcher<T>>,
+  sent: Arc<Mutex<Sent>>,
+}
+
+/// The items of a `BatchSink` whose batches are not done yet.
+#[derive(Default)]
+struct Sent {
+  outstanding: usize,
+  error: Option<BatchError>,
+  /// The task waiting in `poll_complete`.
+  task: Option<Task>,
+}
+
+impl<T: Send + 'static> BatchSink<T> {
+  pub(crate) fn new(batcher: Arc<Batcher<T>>) -> Self {
+    BatchSink {
+      batcher,
+      sent: Arc::new(Mutex::new(Sent::default())),
+    }
+  }
+
+  fn try_send(&mut self, item: T) -> Result<(), AppendError<T>> {
+    let sent = self.sent.clone();
+    // Counted first, as the callback may run before `try_append` returns.
+    self.sent.lock().unwrap().outstanding += 1;
+    let res = self.batcher.try_append(
+      vec![item],
+      Some(Box::new(move |res| {
+        let mut sent = sent.lock().unwrap();
+        if let Err(err) = res {
+          sent.error.get_or_insert(err);
+        }
+        sent.outstanding -= 1;
+        if sent.outstanding == 0 {
+          if let Some(task) = sent.task.take() {
+            task.notify();
+          }
+        }
+      })),
+    );
+    if res.is_err() {
+      self.sent.lock().unwrap().outstanding -= 1;
+    }
+    res
+  }
+}
+
+impl<T: Send + 'static> Sink for BatchSink<T> {
+  type SinkItem = T;
+  type SinkError = BatchError;
+
+  fn start_send(&mut self, item: T) -> StartSend<T, BatchError> {
+    let item = match self.try_send(item) {
+      Ok(()) => return Ok(AsyncSink::Ready),
+      Err(AppendError::Full(mut val)) => val.pop().unwrap(),
+      Err(AppendError::Closed(_)) => return Err(BatchError::Closed),
+    };
+    // Register for a wake up before retrying, so room made in between is
+    // not missed.
+    self.batcher.wait_for_space(task::current());
+    match self.try_send(item) {
+      Ok(()) => Ok(AsyncSink::Ready),
+      Err(AppendError::Full(mut val)) => {
+        Ok(AsyncSink::NotReady(val.pop().unwrap()))
+      }
+      Err(AppendError::Closed(_)) => Err(BatchError::Closed),
+    }
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), BatchError> {
+    let mut sent = self.sent.lock().unwrap();
+    if sent.outstanding > 0 {
+      sent.task = Some(task::current());
+      return Ok(Async::NotReady);
+    }
+    match sent.error.take() {
+      Some(err) => Err(err),
+      None => Ok(Async::Ready(())),
+    }
+  }
+}
+
+/// `Stream` of the batches of a batcher, see `Batcher::batch_stream`.
+///
+/// A batch is done once the next one is polled for, so the batcher gathers
+/// the next batch while the current one is processed.
+pub struct BatchStream<T> {
+  rx: mpsc::UnboundedReceiver<(Vec<T>, Done)>,
+  current: Option<Done>,
+}
+
+impl<T: Send + 'static> BatchStream<T> {
+  pub(crate) fn channel() -> (impl FnMut(Vec<T>, Done) + Send, Self) {
+    let (tx, rx) = mpsc::unbounded();
+    let run = move |val: Vec<T>, done: Done| {
+      if let Err(err) = tx.unbounded_send((val, done)) {
+        // Nobody is listening anymore.
+        let (_, done) = err.into_inner();
+        done.err(BatchError::Closed);
+      }
+    };
+    (run, BatchStream { rx, current: None })
+  }
+}
+
+impl<T> Stream for BatchStream<T> {
+  type Item = Vec<T>;
+  type Error = BatchError;
+
+  fn poll(&mut self) -> Poll<Option<Vec<T>>, BatchError> {
+    if let Some(done) = self.current.take() {
+      done.ok();
+    }
+    match self.rx.poll() {
+      Ok(Async::Ready(Some((val, done)))) => {
+        self.current = Some(done);
+        Ok(Async::Ready(Some(val)))
+      }
+      Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+    }
+  }
+}
@@ -0,0 +1,222 @@
+//! Runners and a clock for testing code built on a batcher, behind the
+//! `testing` feature.
+
+use clock::SharedClock;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant, SystemTime};
+use {BatchError, BatchRunner, Clock, Done};
+
+type Records<T> = Arc<Mutex<Vec<(Instant, Vec<T>)>>>;
+type Held<T> = Arc<Mutex<VecDeque<(Vec<T>, Done)>>>;
+
+/// Runner recording every batch with when it was run, finishing each one
+/// successfully. Clones share the record, so keep one to inspect it.
+pub struct RecordingRunner<T> {
+  batches: Records<T>,
+  clock: SharedClock,
+}
+
+impl<T: Clone> RecordingRunner<T> {
+  /// Create a runner with an empty record.
+  pub fn new() -> Self {
+    RecordingRunner {
+      batches: Arc::new(Mutex::new(Vec::new())),
+      clock: SharedClock::default(),
+    }
+  }
+
+  /// Record when batches run by `clock` rather than the system, e.g. the
+  /// `MockClock` of the batcher.
+  pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+    self.clock = SharedClock(Arc::new(clock));
+    self
+  }
+
+  /// The batches run so far, in order.
+  pub fn batches(&self) -> Vec<Vec<T>> {
+    let batches = self.batches.lock().unwrap();
+    batches.iter().map(|(_, batch)| batch.clone()).collect()
+  }
+
+  /// The batches run so far with when each one was run.
+  pub fn records(&self) -> Vec<(Instant, Vec<T>)> {
+    self.batches.lock().unwrap().clone()
+  }
+
+  /// Assert the batches run so far are `expected`.
+  ///
+  /// # Panics
+  /// Panics, showing both, if they are not.
+  pub fn assert_batches(&self, expected: &[&[T]])
+  where
+    T: PartialEq + Debug,
+  {
+    let expected: Vec<Vec<T>> = expected.iter().map(|b| b.to_vec()).collect();
+    assert_eq!(self.batches(), expected, "unexpected batches");
+  }
+}
+
+impl<T> BatchRunner<T> for RecordingRunner<T> {
+  fn run(&mut self, batch: Vec<T>, done: Done) {
+    let now = self.clock.now();
+    self.batches.lock().unwrap().push((now, batch));
+    done.ok();
+  }
+}
+
+impl<T> Clone for RecordingRunner<T> {
+  fn clone(&self) -> Self {
+    RecordingRunner {
+      batches: self.batches.clone(),
+      clock: self.clock.clone(),
+    }
+  }
+}
+
+impl<T: Clone> Default for RecordingRunner<T> {
+  fn default() -> Self {
+    RecordingRunner::new()
+  }
+}
+
+/// Runner holding every batch until it is released by hand. Clones share
+/// the held batches, so keep one to release them.
+pub struct GatedRunner<T> {
+  held: Held<T>,
+}
+
+impl<T> GatedRunner<T> {
+  /// Create a runner holding no batches.
+  pub fn new() -> Self {
+    GatedRunner {
+      held: Arc::new(Mutex::new(VecDeque::new())),
+    }
+  }
+
+  /// How many batches are held.
+  pub fn held(&self) -> usize {
+    self.held.lock().unwrap().len()
+  }
+
+  /// Finish the oldest held batch with `res`, returning its values, if a
+  /// batch is held.
+  pub fn release(&self, res: Result<(), BatchError>) -> Option<Vec<T>> {
+    // Finishing may run the next batch, which takes the lock again.
+    let held = self.held.lock().unwrap().pop_front();
+    held.map(|(batch, done)| {
+      done.finish(res);
+      batch
+    })
+  }
+
+  /// Finish every held batch successfully, also those run meanwhile.
+  /// Returns how many there were.
+  pub fn release_all(&self) -> usize {
+    let mut count = 0;
+    while self.release(Ok(())).is_some() {
+      count += 1;
+    }
+    count
+  }
+}
+
+impl<T> BatchRunner<T> for GatedRunner<T> {
+  fn run(&mut self, batch: Vec<T>, done: Done) {
+    self.held.lock().unwrap().push_back((batch, done));
+  }
+}
+
+impl<T> Clone for GatedRunner<T> {
+  fn clone(&self) -> Self {
+    GatedRunner {
+      held: self.held.clone(),
+    }
+  }
+}
+
+impl<T> Default for GatedRunner<T> {
+  fn default() -> Self {
+    GatedRunner::new()
+  }
+}
+
+/// Clock which only moves when told to, see `BatcherBuilder::clock`. Clones
+/// share the time, so keep one to move it.
+///
+/// Moving it wakes the timer threads of the batchers using it, which then
+/// start the batches due by the new time. That happens on those threads, so
+/// wait for the batches, e.g. on a channel, rather than expect them to have
+/// run once `advance` returns.
+#[derive(Clone)]
+pub struct MockClock {
+  inner: Arc<Mutex<Mock>>,
+}
+
+struct Mock {
+  now: Instant,
+  /// The wall-clock time at `now`.
+  wall: SystemTime,
+  /// The timer threads waiting for the time to move.
+  parked: Vec<Thread>,
+}
+
+impl MockClock {
+  /// Create a clock standing at the current time.
+  pub fn new() -> Self {
+    MockClock {
+      inner: Arc::new(Mutex::new(Mock {
+        now: Instant::now(),
+        wall: SystemTime::now(),
+        parked: Vec::new(),
+      })),
+    }
+  }
+
+  /// Move the time forward by `by`.
+  pub fn advance(&self, by: Duration) {
+    let parked = {
+      let mut mock = self.inner.lock().unwrap();
+      mock.now += by;
+      mock.wall += by;
+      mem::take(&mut mock.parked)
+    };
+    for thread in parked {
+      thread.unpark();
+    }
+  }
+}
+
+impl Clock for MockClock {
+  fn now(&self) -> Instant {
+    self.inner.lock().unwrap().now
+  }
+
+  fn system_time(&self) -> SystemTime {
+    self.inner.lock().unwrap().wall
+  }
+
+  fn park_until(&self, due: Instant) {
+    {
+      let mut mock = self.inner.lock().unwrap();
+      if due <= mock.now {
+        return;
+      }
+      let current = thread::current();
+      if mock.parked.iter().all(|thread| thread.id() != current.id()) {
+        mock.parked.push(current);
+      }
+    }
+    // An `advance` since the lock was released leaves the token to unpark.
+    thread::park();
+  }
+}
+
+impl Default for MockClock {
+  fn default() -> Self {
+    MockClock::new()
+  }
+}
@@ -0,0 +1,46 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::time::Duration;
+use BatchError;
+
+/// Receiver for the result of the batch an append ended up in, see
+/// `Batcher::append_ticket`.
+///
+/// If the batcher goes away before running the batch, the ticket resolves
+/// with `BatchError::Closed`.
+#[derive(Debug)]
+pub struct BatchTicket {
+  rx: Receiver<Result<(), BatchError>>,
+}
+
+impl BatchTicket {
+  pub(crate) fn new(rx: Receiver<Result<(), BatchError>>) -> Self {
+    BatchTicket { rx }
+  }
+
+  /// Block until the batch is done.
+  pub fn wait(self) -> Result<(), BatchError> {
+    self.rx.recv().unwrap_or(Err(BatchError::Closed))
+  }
+
+  /// Block until the batch is done, or `timeout` passed. Returns `None` on a
+  /// timeout.
+  pub fn wait_timeout(
+    &self,
+    timeout: Duration,
+  ) -> Option<Result<(), BatchError>> {
+    match self.rx.recv_timeout(timeout) {
+      Ok(res) => Some(res),
+      Err(RecvTimeoutError::Timeout) => None,
+      Err(RecvTimeoutError::Disconnected) => Some(Err(BatchError::Closed)),
+    }
+  }
+
+  /// Return the result if the batch is done already.
+  pub fn try_wait(&self) -> Option<Result<(), BatchError>> {
+    match self.rx.try_recv() {
+      Ok(res) => Some(res),
+      Err(TryRecvError::Empty) => None,
+      Err(TryRecvError::Disconnected) => Some(Err(BatchError::Closed)),
+    }
+  }
+}
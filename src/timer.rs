@@ -0,0 +1,192 @@
+use clock::SharedClock;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, Thread};
+use std::time::Instant;
+use {BatchContainer, Batcher};
+
+/// Something the timer thread calls once it is due, see `Batcher::on_timer`.
+pub(crate) trait Timed: Send + Sync {
+  /// Do what is due now. Returns when to be called next, if at all.
+  fn on_timer(&self) -> Option<Instant>;
+}
+
+impl<T, C> Timed for Batcher<T, C>
+where
+  T: Send + 'static,
+  C: BatchContainer<T> + Send + 'static,
+{
+  fn on_timer(&self) -> Option<Instant> {
+    Batcher::on_timer(self)
+  }
+}
+
+/// A thread calling batchers, or anything else `Timed`, once they are due.
+/// One thread serves every batcher registered with it, e.g. all keys of a
+/// `KeyedBatcher`. It is spawned with the first registration, and exits
+/// once the timer and the registrations are gone.
+#[derive(Clone)]
+pub(crate) struct Timer {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  name: String,
+  clock: SharedClock,
+  slots: Mutex<Vec<Arc<Slot>>>,
+  thread: Mutex<Option<Thread>>,
+}
+
+struct Slot {
+  target: Weak<dyn Timed>,
+  /// Whether to call the target regardless of when it is due.
+  woken: AtomicBool,
+  /// Only touched by the timer thread.
+  due: Mutex<Option<Instant>>,
+}
+
+/// The place of one target at a timer. Dropping it has the timer forget the
+/// target once it is gone.
+pub(crate) struct Registration {
+  inner: Arc<Inner>,
+  slot: Arc<Slot>,
+}
+
+impl Timer {
+  /// Create the timer of a batcher, whose thread is named after it.
+  pub(crate) fn named(name: Option<&str>, clock: SharedClock) -> Self {
+    let name = match name {
+      Some(name) => format!("{}-timer", name),
+      None => String::from("atomic-batcher-timer"),
+    };
+    Timer::new(name, clock)
+  }
+
+  /// Create a timer whose thread is called `name`.
+  pub(crate) fn new(name: String, clock: SharedClock) -> Self {
+    Timer {
+      inner: Arc::new(Inner {
+        name,
+        clock,
+        slots: Mutex::new(Vec::new()),
+        thread: Mutex::new(None),
+      }),
+    }
+  }
+
+  /// The clock the timer goes by.
+  pub(crate) fn clock(&self) -> &SharedClock {
+    &self.inner.clock
+  }
+
+  /// Call `target` whenever it is due, until it is dropped. It is called
+  /// right away first.
+  pub(crate) fn register(&self, target: Weak<dyn Timed>) -> Registration {
+    let slot = Arc::new(Slot {
+      target,
+      woken: AtomicBool::new(true),
+      due: Mutex::new(None),
+    });
+    self.inner.slots.lock().unwrap().push(slot.clone());
+    let mut thread = self.inner.thread.lock().unwrap();
+    match *thread {
+      Some(ref thread) => thread.unpark(),
+      None => *thread = Some(spawn(Arc::downgrade(&self.inner))),
+    }
+    Registration {
+      inner: self.inner.clone(),
+      slot,
+    }
+  }
+}
+
+impl fmt::Debug for Timer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Timer")
+      .field("name", &self.inner.name)
+      .finish()
+  }
+}
+
+impl Registration {
+  /// Have the timer call the target as soon as it can.
+  pub(crate) fn wake(&self) {
+    self.slot.woken.store(true, Ordering::SeqCst);
+    self.inner.unpark();
+  }
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    self.inner.unpark();
+  }
+}
+
+impl Inner {
+  fn unpark(&self) {
+    if let Some(ref thread) = *self.thread.lock().unwrap() {
+      thread.unpark();
+    }
+  }
+
+  /// Call the targets which are due or woken, and forget those which are
+  /// gone. Returns when the next one is due, if at all.
+  fn poll(&self) -> Option<Instant> {
+    let slots = {
+      let mut slots = self.slots.lock().unwrap();
+      slots.retain(|slot| slot.target.strong_count() > 0);
+      slots.clone()
+    };
+    let mut next = None;
+    for slot in slots {
+      let due = *slot.due.lock().unwrap();
+      let now = self.clock.now();
+      if slot.woken.swap(false, Ordering::SeqCst)
+        || due.is_some_and(|due| due <= now)
+      {
+        // Upgraded only while called, so the timer never keeps it alive.
+        let due = match slot.target.upgrade() {
+          Some(target) => target.on_timer(),
+          None => continue,
+        };
+        *slot.due.lock().unwrap() = due;
+      }
+      let due = *slot.due.lock().unwrap();
+      next = next.into_iter().chain(due).min();
+    }
+    next
+  }
+}
+
+impl Drop for Inner {
+  fn drop(&mut self) {
+    // Let the thread notice the timer is gone.
+    if let Some(ref thread) = *self.thread.get_mut().unwrap() {
+      thread.unpark();
+    }
+  }
+}
+
+/// Spawn the thread of a timer. It only holds a weak reference in between
+/// rounds.
+fn spawn(inner: Weak<Inner>) -> Thread {
+  let name = match inner.upgrade() {
+    Some(inner) => inner.name.clone(),
+    None => String::from("atomic-batcher-timer"),
+  };
+  let handle = thread::Builder::new()
+    .name(name)
+    .spawn(move || loop {
+      let (due, clock) = match inner.upgrade() {
+        Some(inner) => (inner.poll(), inner.clock.clone()),
+        None => return,
+      };
+      match due {
+        Some(due) => clock.0.park_until(due),
+        None => thread::park(),
+      }
+    })
+    .expect("failed to spawn the batcher timer thread");
+  handle.thread().clone()
+}
@@ -0,0 +1,272 @@
+use builder::Config;
+use futures::sink::Send as SendMsg;
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Poll, Sink, Stream};
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use {BatchError, BatcherBuilder, Runtime, RuntimeFuture};
+
+type Reply = oneshot::Sender<Result<(), BatchError>>;
+type Msg<T> = (Vec<T>, Reply);
+type Running = Box<dyn Future<Item = (), Error = BatchError> + Send>;
+
+/// Batcher living in a task on the tokio executor.
+///
+/// Unlike `AsyncBatcher`, which runs batches from whichever thread appends,
+/// values are gathered by a task of its own, lingering with a timer of the
+/// executor.
+/// Create it with `BatcherBuilder::build_tokio` from within a tokio runtime,
+/// or on another executor with `BatcherBuilder::build_on`.
+/// It honours `max_batch_size`, `max_batch_delay`, `capacity`, which
+/// bounds the appends waiting to be gathered rather than their values, and
+/// `batch_timeout`, kept with a timer of the executor, see
+/// `BatcherBuilder::abort_timed_out`. Other settings are rejected, see
+/// `BatcherBuilder::try_build_on`. The task runs what is left and exits once
+/// every clone is dropped.
+pub struct TokioBatcher<T> {
+  tx: Tx<T>,
+}
+
+enum Tx<T> {
+  Bounded(mpsc::Sender<Msg<T>>),
+  Unbounded(mpsc::UnboundedSender<Msg<T>>),
+}
+
+impl<T: Send + 'static> TokioBatcher<T> {
+  /// Create a tokio batcher with the default configuration, see
+  /// `BatcherBuilder::build_tokio`.
+  pub fn new<F, R>(run: F) -> Self
+  where
+    F: FnMut(Vec<T>) -> R + Send + 'static,
+    R: Future<Item = (), Error = BatchError> + Send + 'static,
+  {
+    BatcherBuilder::new().build_tokio(run)
+  }
+
+  pub(crate) fn spawn<F, R>(
+    runtime: Arc<dyn Runtime>,
+    config: &Config,
+    mut run: F,
+  ) -> Self
+  where
+    F: FnMut(Vec<T>) -> R + Send + 'static,
+    R: Future<Item = (), Error = BatchError> + Send + 'static,
+  {
+    let (tx, rx): (_, Box<dyn Stream<Item = Msg<T>, Error = ()> + Send>) =
+      match config.capacity {
+        Some(capacity) => {
+          let (tx, rx) = mpsc::channel(capacity);
+          (Tx::Bounded(tx), Box::new(rx))
+        }
+        None => {
+          let (tx, rx) = mpsc::unbounded();
+          (Tx::Unbounded(tx), Box::new(rx))
+        }
+      };
+    runtime.clone().spawn(Box::new(Gather {
+      runtime,
+      rx,
+      closed: false,
+      run: Box::new(move |batch| Box::new(run(batch)) as Running),
+      max_batch_size: config.max_batch_size,
+      max_batch_delay: config.max_batch_delay,
+      batch_timeout: config.batch_timeout,
+      abort_timed_out: config.abort_timed_out,
+      pending: Vec::new(),
+      replies: Vec::new(),
+      delay: None,
+      running: None,
+      deadline: None,
+    }));
+    TokioBatcher { tx }
+  }
+
+  /// Accept an array of values. The returned future waits for room if the
+  /// batcher is at capacity, and resolves when the batch containing the
+  /// values has been run.
+  pub fn append(&self, val: Vec<T>) -> TokioAppend<T> {
+    let (reply, rx) = oneshot::channel();
+    let send = match self.tx {
+      Tx::Bounded(ref tx) => Some(tx.clone().send((val, reply))),
+      Tx::Unbounded(ref tx) => {
+        // A failed send drops the reply, failing the append.
+        let _ = tx.unbounded_send((val, reply));
+        None
+      }
+    };
+    TokioAppend { send, rx }
+  }
+}
+
+impl<T> Clone for TokioBatcher<T> {
+  fn clone(&self) -> Self {
+    let tx = match self.tx {
+      Tx::Bounded(ref tx) => Tx::Bounded(tx.clone()),
+      Tx::Unbounded(ref tx) => Tx::Unbounded(tx.clone()),
+    };
+    TokioBatcher { tx }
+  }
+}
+
+/// Future returned by `TokioBatcher::append`.
+pub struct TokioAppend<T> {
+  send: Option<SendMsg<mpsc::Sender<Msg<T>>>>,
+  rx: oneshot::Receiver<Result<(), BatchError>>,
+}
+
+impl<T> Future for TokioAppend<T> {
+  type Item = ();
+  type Error = BatchError;
+
+  fn poll(&mut self) -> Poll<(), BatchError> {
+    if let Some(ref mut send) = self.send {
+      match send.poll() {
+        Ok(Async::Ready(_)) => {}
+        Ok(Async::NotReady) => return Ok(Async::NotReady),
+        Err(_) => return Err(BatchError::Closed),
+      }
+    }
+    self.send = None;
+    match self.rx.poll() {
+      Ok(Async::Ready(Ok(()))) => Ok(Async::Ready(())),
+      Ok(Async::Ready(Err(err))) => Err(err),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(_) => Err(BatchError::Closed),
+    }
+  }
+}
+
+/// The task of a `TokioBatcher`, gathering values and running one batch at
+/// a time.
+struct Gather<T> {
+  runtime: Arc<dyn Runtime>,
+  rx: Box<dyn Stream<Item = Msg<T>, Error = ()> + Send>,
+  /// Whether every `TokioBatcher` is gone.
+  closed: bool,
+  run: Box<dyn FnMut(Vec<T>) -> Running + Send>,
+  max_batch_size: Option<usize>,
+  max_batch_delay: Option<Duration>,
+  batch_timeout: Option<Duration>,
+  abort_timed_out: bool,
+  pending: Vec<T>,
+  replies: Vec<Reply>,
+  /// When the pending batch is due, if it lingers.
+  delay: Option<RuntimeFuture>,
+  /// The running batch and the replies for its appends.
+  running: Option<(Running, Vec<Reply>)>,
+  /// When the running batch times out, see `batch_timeout`.
+  deadline: Option<RuntimeFuture>,
+}
+
+impl<T> Gather<T> {
+  /// Reply to the appends of the running batch once it is done, or has
+  /// timed out.
+  fn poll_running(&mut self) {
+    let mut timed_out = false;
+    let res = match self.running {
+      Some((ref mut running, _)) => match running.poll() {
+        Ok(Async::NotReady) => {
+          if !is_over(&mut self.deadline) {
+            return;
+          }
+          timed_out = true;
+          Err(BatchError::Timeout)
+        }
+        Ok(Async::Ready(())) => Ok(()),
+        Err(err) => Err(err),
+      },
+      None => return,
+    };
+    self.deadline = None;
+    if let Some((running, replies)) = self.running.take() {
+      if timed_out && !self.abort_timed_out {
+        // Leave it running, without anyone waiting for it.
+        self.runtime.spawn(Box::new(running.then(|_| Ok(()))));
+      }
+      for reply in replies {
+        let _ = reply.send(res.clone());
+      }
+    }
+  }
+
+  /// Take values in until the pending batch is full, leaving the rest
+  /// waiting for room.
+  fn gather(&mut self) {
+    while !self.closed && !self.is_full() {
+      match self.rx.poll() {
+        Ok(Async::Ready(Some((val, reply)))) => {
+          if self.replies.is_empty() {
+            let runtime = &self.runtime;
+            self.delay = self
+              .max_batch_delay
+              .map(|delay| runtime.delay(Instant::now() + delay));
+          }
+          self.pending.extend(val);
+          self.replies.push(reply);
+        }
+        Ok(Async::Ready(None)) | Err(()) => self.closed = true,
+        Ok(Async::NotReady) => break,
+      }
+    }
+  }
+
+  fn is_full(&self) -> bool {
+    self
+      .max_batch_size
+      .is_some_and(|max| self.pending.len() >= max)
+  }
+
+  /// Whether the pending batch may start, polling its delay if not.
+  fn is_due(&mut self) -> bool {
+    if self.closed || self.is_full() {
+      return true;
+    }
+    match self.delay {
+      Some(ref mut delay) => match delay.poll() {
+        Ok(Async::NotReady) => false,
+        Ok(Async::Ready(())) | Err(()) => true,
+      },
+      None => true,
+    }
+  }
+}
+
+impl<T> Future for Gather<T> {
+  type Item = ();
+  type Error = ();
+
+  fn poll(&mut self) -> Poll<(), ()> {
+    loop {
+      self.poll_running();
+      self.gather();
+      let waiting = !self.replies.is_empty();
+      if self.running.is_none() && waiting && self.is_due() {
+        self.delay = None;
+        let batch = mem::take(&mut self.pending);
+        let replies = mem::take(&mut self.replies);
+        self.running = Some(((self.run)(batch), replies));
+        let runtime = &self.runtime;
+        self.deadline = self
+          .batch_timeout
+          .map(|timeout| runtime.delay(Instant::now() + timeout));
+        continue;
+      }
+      if self.closed && self.running.is_none() && !waiting {
+        return Ok(Async::Ready(()));
+      }
+      return Ok(Async::NotReady);
+    }
+  }
+}
+
+/// Whether the time of a deadline, if there is one, has come.
+fn is_over(deadline: &mut Option<RuntimeFuture>) -> bool {
+  match *deadline {
+    Some(ref mut deadline) => match deadline.poll() {
+      Ok(Async::NotReady) => false,
+      Ok(Async::Ready(())) | Err(()) => true,
+    },
+    None => false,
+  }
+}
@@ -0,0 +1,142 @@
+use clock::SharedClock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// What a batcher is doing, see `Batcher::watch_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatcherState {
+  /// Nothing is running or waiting to run.
+  Idle,
+  /// Batches are running, or values wait to run.
+  Running,
+  /// Closed, with batches left to run.
+  Draining,
+  /// Closed, and everything appended ran.
+  Closed,
+}
+
+/// The latest state of a batcher, shared with its watchers.
+pub(crate) struct Watch {
+  seen: Mutex<Seen>,
+  changed: Condvar,
+  /// Whether anyone subscribed, so unwatched batchers skip publishing.
+  watched: AtomicBool,
+  /// See `BatcherBuilder::clock`.
+  clock: SharedClock,
+}
+
+struct Seen {
+  state: BatcherState,
+  /// Counts the changes, so watchers tell which ones they saw.
+  version: u64,
+  since: Instant,
+}
+
+impl Watch {
+  pub(crate) fn new(clock: SharedClock) -> Self {
+    Watch {
+      seen: Mutex::new(Seen {
+        state: BatcherState::Idle,
+        version: 0,
+        since: clock.now(),
+      }),
+      changed: Condvar::new(),
+      watched: AtomicBool::new(false),
+      clock,
+    }
+  }
+
+  pub(crate) fn is_watched(&self) -> bool {
+    self.watched.load(Ordering::Relaxed)
+  }
+
+  pub(crate) fn set(&self, state: BatcherState) {
+    let mut seen = self.seen.lock().unwrap();
+    if seen.state != state {
+      seen.state = state;
+      seen.version += 1;
+      seen.since = self.clock.now();
+      self.changed.notify_all();
+    }
+  }
+
+  pub(crate) fn subscribe(watch: &Arc<Watch>) -> StateWatch {
+    watch.watched.store(true, Ordering::Relaxed);
+    let version = watch.seen.lock().unwrap().version;
+    StateWatch {
+      watch: watch.clone(),
+      version,
+    }
+  }
+}
+
+/// Receiver of the state changes of a batcher, see `Batcher::watch_state`.
+///
+/// Like a watch channel it only keeps the latest state: a watcher which
+/// looks too late misses states the batcher passed through meanwhile. It
+/// outlives the batcher, which is `Closed` once dropped.
+#[derive(Clone)]
+pub struct StateWatch {
+  watch: Arc<Watch>,
+  /// The version of the state last returned.
+  version: u64,
+}
+
+impl StateWatch {
+  /// The current state.
+  pub fn get(&self) -> BatcherState {
+    self.watch.seen.lock().unwrap().state
+  }
+
+  /// How long the batcher has been in its current state, e.g. to alert
+  /// when it has been `Running` for too long, by the clock of the batcher.
+  pub fn elapsed(&self) -> Duration {
+    let since = self.watch.seen.lock().unwrap().since;
+    self.watch.clock.now().saturating_duration_since(since)
+  }
+
+  /// Block until the state changed since it was last returned, and return
+  /// the new one.
+  pub fn changed(&mut self) -> BatcherState {
+    let mut seen = self.watch.seen.lock().unwrap();
+    while seen.version == self.version {
+      seen = self.watch.changed.wait(seen).unwrap();
+    }
+    self.version = seen.version;
+    seen.state
+  }
+
+  /// Block until `accept` holds for the state, and return it, or `None`
+  /// once `timeout` is over, e.g. to await `BatcherState::Closed`.
+  pub fn wait_for<F>(
+    &mut self,
+    accept: F,
+    timeout: Option<Duration>,
+  ) -> Option<BatcherState>
+  where
+    F: Fn(BatcherState) -> bool,
+  {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut seen = self.watch.seen.lock().unwrap();
+    while !accept(seen.state) {
+      seen = match deadline {
+        None => self.watch.changed.wait(seen).unwrap(),
+        Some(deadline) => {
+          let now = Instant::now();
+          if deadline <= now {
+            return None;
+          }
+          self
+            .watch
+            .changed
+            .wait_timeout(seen, deadline - now)
+            .unwrap()
+            .0
+        }
+      };
+    }
+    self.version = seen.version;
+    Some(seen.state)
+  }
+}
@@ -1,39 +1,63 @@
 extern crate atomic_batcher;
+extern crate futures;
+#[cfg(feature = "log")]
+extern crate log;
 extern crate tokio;
 
 use atomic_batcher::*;
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 use tokio::prelude::*;
 use tokio::timer::Delay;
 
+/// How many threads of the test process are called `name`.
+#[cfg(target_os = "linux")]
+fn threads(name: &str) -> usize {
+  let tasks = std::fs::read_dir("/proc/self/task").unwrap();
+  tasks
+    .filter_map(|task| {
+      std::fs::read_to_string(task.ok()?.path().join("comm")).ok()
+    })
+    .filter(|comm| comm.trim_end() == name)
+    .count()
+}
+
 #[test]
 fn run_once() {
-  fn run(val: Vec<u64>, _batcher: &Batcher<u64>) -> () {
+  fn run(val: Vec<u64>, _done: Done) {
     assert_eq!(val, vec![1, 2, 3]);
-  };
+  }
   let batcher = Batcher::new(Box::new(run));
   batcher.append(vec![1, 2, 3], None);
 }
 
 #[test]
 fn run_with_done() {
-  let run = |val: Vec<u64>, batcher: &Batcher<u64>| -> () {
-    if val == vec![1, 2, 3] {
-      batcher.append(vec![4, 5, 6], None);
-      batcher.done(Ok(()));
-    } else {
-      assert_eq!(val, vec![4, 5, 6]);
-    }
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap();
   };
   let batcher = Batcher::new(Box::new(run));
   batcher.append(vec![1, 2, 3], None);
+  batcher.append(vec![4, 5, 6], None);
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![1, 2, 3]);
+  assert!(rx.try_recv().is_err());
+  done.ok();
+  let (val, _done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![4, 5, 6]);
 }
 
 #[test]
 fn run_with_callback() {
-  let run = |val: Vec<u64>, batcher: &Batcher<u64>| -> () {
+  let run = |val: Vec<u64>, done: Done| {
     if val == vec![1, 2, 3] {
-      batcher.done(Err("some wrong"));
+      done.err(BatchError::run("some wrong"));
     } else {
       assert_eq!(val, vec![]);
     }
@@ -42,31 +66,2708 @@ fn run_with_callback() {
   batcher.append(
     vec![1, 2, 3],
     Some(Box::new(move |err| {
-      if let Err(s) = err {
-        assert_eq!(s, "some wrong");
+      if let Err(e) = err {
+        assert_eq!(e.to_string(), "run failed: some wrong");
       }
     })),
   );
 }
 
+#[test]
+fn run_with_closure_callback() {
+  let run = |_val: Vec<u64>, done: Done| {
+    done.err(BatchError::run("some wrong"));
+  };
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(run);
+  batcher.append(
+    vec![1, 2, 3],
+    Some(Box::new(move |err: Result<(), BatchError>| {
+      tx.send(err.map_err(|e| e.to_string())).unwrap();
+    })),
+  );
+  assert_eq!(
+    rx.try_recv().unwrap(),
+    Err(String::from("run failed: some wrong"))
+  );
+}
+
+#[test]
+fn run_with_state() {
+  let (tx, rx) = mpsc::channel();
+  let mut batches = 0;
+  let run = move |val: Vec<u64>, done: Done| {
+    batches += 1;
+    tx.send((batches, val)).unwrap();
+    done.ok();
+  };
+  let batcher = Batcher::new(run);
+  batcher.append(vec![1, 2, 3], None);
+  batcher.append(vec![4, 5, 6], None);
+  assert_eq!(rx.try_recv().unwrap(), (1, vec![1, 2, 3]));
+  assert_eq!(rx.try_recv().unwrap(), (2, vec![4, 5, 6]));
+}
+
 #[test]
 fn run_async() {
   let when = Instant::now() + Duration::from_millis(1000);
-  let run = move |val: Vec<u64>, _batcher: &Batcher<u64>| -> () {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
     if val != vec![1, 2, 3] {
       assert_eq!(val, vec![4, 5, 6, 7, 8, 9]);
     }
+    tx.send(done).unwrap();
   };
   let batcher = Batcher::new(Box::new(run));
   batcher.append(vec![1, 2, 3], None);
   batcher.append(vec![4, 5, 6], None);
   batcher.append(vec![7, 8, 9], None);
-  
+
+  let done = rx.try_recv().unwrap();
   let task = Delay::new(when)
     .and_then(move |_| {
-      batcher.done(Ok(()));
+      done.ok();
       Ok(())
     })
     .map_err(|e| panic!("delay errored; err={:?}", e));
   tokio::run(task);
+  assert!(rx.try_recv().is_ok());
+}
+
+#[test]
+fn run_async_batcher() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>| {
+    tx.send(val).unwrap();
+    let when = Instant::now() + Duration::from_millis(100);
+    Delay::new(when).map_err(BatchError::run)
+  };
+  let batcher = AsyncBatcher::new(run);
+  tokio::run(future::lazy(move || {
+    let first = batcher.append(vec![1, 2, 3]);
+    let second = batcher.append(vec![4, 5, 6]);
+    first
+      .join(second)
+      .map(|_| ())
+      .map_err(|e| panic!("batch errored; err={:?}", e))
+  }));
+  assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3]);
+  assert_eq!(rx.try_recv().unwrap(), vec![4, 5, 6]);
+}
+
+#[test]
+fn batch_error_keeps_source() {
+  use std::error::Error;
+  use std::io;
+
+  let err = BatchError::run(io::Error::other("disk full"));
+  assert_eq!(err.to_string(), "run failed: disk full");
+  assert_eq!(err.source().unwrap().to_string(), "disk full");
+  assert!(BatchError::Timeout.source().is_none());
+}
+
+#[test]
+fn flush_waits_for_pending() {
+  let (batch_tx, batch_rx) = mpsc::channel();
+  let (ran_tx, ran_rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    batch_tx.send((val, done)).unwrap();
+  };
+  let batcher = Batcher::new(run);
+  thread::spawn(move || {
+    for (val, done) in batch_rx {
+      thread::sleep(Duration::from_millis(50));
+      ran_tx.send(val).unwrap();
+      done.ok();
+    }
+  });
+  batcher.append(vec![1, 2, 3], None);
+  batcher.append(vec![4, 5, 6], None);
+  batcher.append(vec![7, 8, 9], None);
+  batcher.flush();
+  assert_eq!(ran_rx.try_recv().unwrap(), vec![1, 2, 3]);
+  assert_eq!(ran_rx.try_recv().unwrap(), vec![4, 5, 6, 7, 8, 9]);
+  batcher.flush();
+}
+
+#[test]
+fn max_batch_size() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap();
+  };
+  let batcher = Batcher::builder().max_batch_size(4).build(run);
+  batcher.append(vec![1], None);
+  batcher.append(vec![2, 3], None);
+  batcher.append(vec![4, 5], None);
+  batcher.append(vec![6], None);
+  batcher.append(vec![7, 8, 9, 10], None);
+  batcher.append(vec![11], None);
+  for expected in [vec![1], vec![2, 3, 4, 5], vec![6, 7, 8, 9, 10]] {
+    let (val, done) = rx.try_recv().unwrap();
+    assert_eq!(val, expected);
+    done.ok();
+  }
+  assert_eq!(rx.try_recv().unwrap().0, vec![11]);
+}
+
+#[test]
+fn max_batch_delay() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    tx.send(val).unwrap();
+    done.ok();
+  };
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_millis(50))
+    .max_batch_size(6)
+    .build(run);
+  batcher.append(vec![1, 2, 3], None);
+  batcher.append(vec![4, 5, 6], None);
+  assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3, 4, 5, 6]);
+  batcher.append(vec![7, 8], None);
+  batcher.append(vec![9], None);
+  assert!(rx.try_recv().is_err());
+  assert_eq!(
+    rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+    vec![7, 8, 9]
+  );
+}
+
+#[test]
+fn append_from_handles() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    tx.send(val).unwrap();
+    done.ok();
+  };
+  let batcher = Batcher::new(run);
+  let producers: Vec<_> = (0..4)
+    .map(|i| {
+      let handle = batcher.handle();
+      thread::spawn(move || {
+        for j in 0..100 {
+          handle.append(vec![i * 100 + j], None);
+        }
+      })
+    })
+    .collect();
+  for producer in producers {
+    producer.join().unwrap();
+  }
+  batcher.flush();
+  let mut values: Vec<u64> = rx.try_iter().flatten().collect();
+  values.sort();
+  assert_eq!(values, (0..400).collect::<Vec<u64>>());
+}
+
+#[test]
+fn append_ticket() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    if val == vec![1, 2, 3] {
+      done.err(BatchError::run("some wrong"));
+    } else {
+      tx.send(done).unwrap();
+    }
+  };
+  let batcher = Batcher::new(run);
+  let ticket = batcher.append_ticket(vec![1, 2, 3]);
+  match ticket.wait() {
+    Err(BatchError::Run(err)) => assert_eq!(err.to_string(), "some wrong"),
+    res => panic!("unexpected result {:?}", res),
+  }
+  let ticket = batcher.append_ticket(vec![4, 5, 6]);
+  assert!(ticket.try_wait().is_none());
+  rx.try_recv().unwrap().ok();
+  assert!(ticket.try_wait().unwrap().is_ok());
+}
+
+#[test]
+fn builder_validates_config() {
+  fn run(_val: Vec<u64>, _done: Done) {}
+  let err = Batcher::builder().max_batch_size(0).try_build(run).err();
+  assert_eq!(err, Some(ConfigError::ZeroBatchSize));
+  let err = Batcher::builder()
+    .max_batch_size(8)
+    .capacity(4)
+    .try_build(run)
+    .err();
+  assert_eq!(err, Some(ConfigError::CapacityBelowBatchSize));
+  let batcher = Batcher::builder()
+    .name("rows")
+    .callback_capacity(16)
+    .try_build(run)
+    .unwrap();
+  assert_eq!(batcher.name(), Some("rows"));
+}
+
+#[test]
+fn capacity_exceeded() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap();
+  };
+  let batcher = Batcher::builder().capacity(4).build(run);
+  batcher.append(vec![1, 2, 3], None);
+  let ticket = batcher.append_ticket(vec![4, 5, 6]);
+  match batcher.append_ticket(vec![7, 8]).wait() {
+    Err(BatchError::CapacityExceeded) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  rx.try_recv().unwrap().1.ok();
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![4, 5, 6]);
+  done.ok();
+  assert!(ticket.wait().is_ok());
+}
+
+#[test]
+fn callbacks_run_with_their_batch() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap();
+  };
+  let batcher = Batcher::new(run);
+  let first = batcher.append_ticket(vec![1, 2, 3]);
+  let second = batcher.append_ticket(vec![4, 5, 6]);
+  let third = batcher.append_ticket(vec![]);
+  rx.try_recv().unwrap().1.err(BatchError::Timeout);
+  assert!(first.try_wait().unwrap().is_err());
+  assert!(second.try_wait().is_none());
+  assert!(third.try_wait().is_none());
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![4, 5, 6]);
+  done.ok();
+  assert!(second.try_wait().unwrap().is_ok());
+  assert!(third.try_wait().unwrap().is_ok());
+}
+
+#[test]
+fn per_item_results() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap();
+  };
+  let batcher = Batcher::new(run);
+  batcher.append(vec![0], None);
+  let (items_tx, items_rx) = mpsc::channel();
+  batcher.append_each(
+    vec![1, 2, 3],
+    Some(Box::new(move |results| {
+      let ok: Vec<bool> = results.iter().map(Result::is_ok).collect();
+      items_tx.send(ok).unwrap();
+    })),
+  );
+  let whole = batcher.append_ticket(vec![4, 5]);
+  rx.try_recv().unwrap().1.ok();
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![1, 2, 3, 4, 5]);
+  done.finish_each(
+    val
+      .iter()
+      .map(|v| match v % 2 {
+        0 => Err(BatchError::run("even")),
+        _ => Ok(()),
+      })
+      .collect(),
+  );
+  assert_eq!(items_rx.try_recv().unwrap(), vec![true, false, true]);
+  assert!(whole.wait().is_err());
+}
+
+#[test]
+fn backpressure() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap();
+  };
+  let batcher = Batcher::builder().capacity(3).build(run);
+  batcher.try_append(vec![1, 2, 3], None).unwrap();
+  batcher.try_append(vec![4, 5], None).unwrap();
+  match batcher.try_append(vec![6, 7], None) {
+    Err(AppendError::Full(val)) => assert_eq!(val, vec![6, 7]),
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert!(batcher.append_blocking(vec![1, 2, 3, 4], None).is_err());
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![1, 2, 3]);
+  let producer = batcher.handle();
+  let blocked = thread::spawn(move || {
+    producer.append_blocking(vec![6, 7], None).unwrap();
+  });
+  thread::sleep(Duration::from_millis(50));
+  done.ok();
+  blocked.join().unwrap();
+  assert_eq!(rx.try_recv().unwrap().0, vec![4, 5]);
+}
+
+#[test]
+fn close() {
+  let (batch_tx, batch_rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    batch_tx.send((val, done)).unwrap();
+  };
+  let batcher = Batcher::new(run);
+  thread::spawn(move || {
+    for (val, done) in batch_rx {
+      thread::sleep(Duration::from_millis(20));
+      match val[0] {
+        4 => done.err(BatchError::run("some wrong")),
+        _ => done.ok(),
+      }
+    }
+  });
+  let first = batcher.append_ticket(vec![1, 2, 3]);
+  let second = batcher.append_ticket(vec![4, 5, 6]);
+  match batcher.close() {
+    Err(BatchError::Run(err)) => assert_eq!(err.to_string(), "some wrong"),
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert!(first.try_wait().unwrap().is_ok());
+  assert!(second.try_wait().unwrap().is_err());
+  match batcher.append_ticket(vec![7]).wait() {
+    Err(BatchError::Closed) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  match batcher.try_append(vec![7], None) {
+    Err(AppendError::Closed(val)) => assert_eq!(val, vec![7]),
+    res => panic!("unexpected result {:?}", res),
+  }
+}
+
+#[test]
+fn drop_policy() {
+  let (tx, rx) = mpsc::channel();
+  let run = move |val: Vec<u64>, done: Done| {
+    tx.send(val).unwrap();
+    done.ok();
+  };
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(60))
+    .drop_policy(DropPolicy::Flush)
+    .build(run);
+  let ticket = batcher.append_ticket(vec![1, 2, 3]);
+  drop(batcher);
+  assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3]);
+  assert!(ticket.wait().is_ok());
+
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(60))
+    .build(|_val: Vec<u64>, _done: Done| panic!("should not run"));
+  let ticket = batcher.append_ticket(vec![1, 2, 3]);
+  drop(batcher);
+  match ticket.wait() {
+    Err(BatchError::Closed) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(60))
+    .drop_policy(DropPolicy::PanicIfPending)
+    .build(|_val: Vec<u64>, _done: Done| {});
+  batcher.append(vec![1, 2, 3], None);
+  assert!(std::panic::catch_unwind(move || drop(batcher)).is_err());
+}
+
+#[test]
+fn sink_and_stream() {
+  let (batcher, batches) = Batcher::batch_stream();
+  let (tx, rx) = mpsc::channel();
+  let consumer = thread::spawn(move || {
+    for val in batches.wait() {
+      tx.send(val.unwrap()).unwrap();
+    }
+  });
+  let items = stream::iter_ok::<_, BatchError>(0..10u64);
+  let (_, sink) = items.forward(batcher.sink()).wait().unwrap();
+  drop(sink);
+  drop(batcher);
+  consumer.join().unwrap();
+  let values: Vec<u64> = rx.try_iter().flatten().collect();
+  assert_eq!(values, (0..10).collect::<Vec<u64>>());
+
+  // With batches done out of order, flushing waits for every one of them.
+  use std::sync::atomic::{AtomicBool, Ordering};
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_size(1)
+    .max_concurrent_batches(2)
+    .build(move |_val: Vec<u64>, done: Done| tx.send(done).unwrap());
+  let mut sink = batcher.sink();
+  assert!(sink.start_send(1).unwrap().is_ready());
+  assert!(sink.start_send(2).unwrap().is_ready());
+  let (first, second) = (rx.recv().unwrap(), rx.recv().unwrap());
+  let finished = Arc::new(AtomicBool::new(false));
+  let finishing = finished.clone();
+  let dones = thread::spawn(move || {
+    second.ok();
+    thread::sleep(Duration::from_millis(20));
+    finishing.store(true, Ordering::SeqCst);
+    first.ok();
+  });
+  future::poll_fn(|| sink.poll_complete()).wait().unwrap();
+  assert!(finished.load(Ordering::SeqCst));
+  dones.join().unwrap();
+}
+
+#[test]
+fn keyed_batcher() {
+  let (tx, rx) = mpsc::channel();
+  let tx = std::sync::Mutex::new(tx);
+  let batcher =
+    KeyedBatcher::new(move |key: &&str, val: Vec<u64>, done: Done| {
+      tx.lock().unwrap().send((*key, val)).unwrap();
+      done.ok();
+    });
+  batcher.append("a", vec![1, 2], None);
+  batcher.append("b", vec![3], None);
+  batcher.append("a", vec![4], None);
+  batcher.flush();
+  let mut batches: Vec<_> = rx.try_iter().collect();
+  batches.sort();
+  assert_eq!(
+    batches,
+    vec![("a", vec![1, 2]), ("a", vec![4]), ("b", vec![3])]
+  );
+  let mut keys = batcher.keys();
+  keys.sort();
+  assert_eq!(keys, vec!["a", "b"]);
+  batcher.prune();
+  assert!(batcher.keys().is_empty());
+  assert!(batcher.close().is_ok());
+  match batcher.append_ticket("c", vec![5]).wait() {
+    Err(BatchError::Closed) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn keyed_batcher_shares_timer() {
+  let (tx, rx) = mpsc::channel();
+  let tx = Mutex::new(tx);
+  let builder = Batcher::builder()
+    .name("keyed")
+    .max_batch_delay(Duration::from_millis(20));
+  let batcher = KeyedBatcher::with_builder(
+    builder,
+    move |key: &u64, val: Vec<u64>, done: Done| {
+      tx.lock().unwrap().send((*key, val)).unwrap();
+      done.ok();
+    },
+  )
+  .unwrap();
+  for key in 0..20 {
+    batcher.append(key, vec![key], None);
+  }
+  let mut batches: Vec<_> = rx.iter().take(20).collect();
+  batches.sort();
+  assert_eq!(
+    batches,
+    (0..20).map(|key| (key, vec![key])).collect::<Vec<_>>()
+  );
+  assert_eq!(threads("keyed-timer"), 1);
+  batcher.prune();
+  drop(batcher);
+  let start = Instant::now();
+  while threads("keyed-timer") > 0 {
+    assert!(
+      start.elapsed() < Duration::from_secs(1),
+      "timer kept running"
+    );
+    thread::sleep(Duration::from_millis(5));
+  }
+}
+
+#[test]
+fn priority() {
+  let (tx, rx) = mpsc::channel();
+  let dones = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+  let pending = dones.clone();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send(val).unwrap();
+    pending.lock().unwrap().push(done);
+  });
+  batcher.append(vec![1], None);
+  batcher.append(vec![2, 3], None);
+  batcher.append_with_priority(vec![4], Priority::High, None);
+  batcher.append_with_priority(vec![5], Priority::High, None);
+  for _ in 0..3 {
+    let done = dones.lock().unwrap().pop().unwrap();
+    done.ok();
+  }
+  let batches: Vec<_> = rx.try_iter().collect();
+  assert_eq!(batches, vec![vec![1], vec![4, 5], vec![2, 3]]);
+}
+
+#[test]
+fn metrics() {
+  struct Sink(std::sync::Mutex<mpsc::Sender<(&'static str, usize)>>);
+  impl MetricsSink for Sink {
+    fn on_append(&self, count: usize) {
+      self.0.lock().unwrap().send(("append", count)).unwrap();
+    }
+    fn on_dispatch(&self, _id: u64, size: usize, _pending: usize) {
+      self.0.lock().unwrap().send(("dispatch", size)).unwrap();
+    }
+    fn on_complete(
+      &self,
+      _id: u64,
+      size: usize,
+      _elapsed: Duration,
+      _res: &Result<(), BatchError>,
+    ) {
+      self.0.lock().unwrap().send(("complete", size)).unwrap();
+    }
+  }
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .metrics_sink(Sink(std::sync::Mutex::new(tx)))
+    .build(|val: Vec<u64>, done: Done| {
+      if val.len() > 1 {
+        done.err(BatchError::run("too many"));
+      } else {
+        done.ok();
+      }
+    });
+  batcher.append(vec![1], None);
+  batcher.append(vec![2, 3], Some(Box::new(|_| {})));
+  let metrics = batcher.metrics();
+  assert_eq!(metrics.appended, 3);
+  assert_eq!(metrics.dispatched, 2);
+  assert_eq!(metrics.completed, 2);
+  assert_eq!(metrics.failed, 1);
+  assert_eq!(metrics.callback_failures, 1);
+  assert_eq!(metrics.pending, 0);
+  assert_eq!(metrics.average_batch_size(), 1.5);
+  let events: Vec<_> = rx.try_iter().collect();
+  assert_eq!(
+    events,
+    vec![
+      ("dispatch", 1),
+      ("complete", 1),
+      ("append", 1),
+      ("dispatch", 2),
+      ("complete", 2),
+      ("append", 2),
+    ]
+  );
+}
+
+#[test]
+fn batch_runner() {
+  struct Collect(mpsc::Sender<Vec<u64>>);
+  impl BatchRunner<u64> for Collect {
+    fn run(&mut self, batch: Vec<u64>, done: Done) {
+      self.0.send(batch).unwrap();
+      done.ok();
+    }
+  }
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::with_runner(Collect(tx));
+  batcher.append(vec![1, 2], None);
+  batcher.append(vec![3], None);
+  let batches: Vec<_> = rx.try_iter().collect();
+  assert_eq!(batches, vec![vec![1, 2], vec![3]]);
+}
+
+#[test]
+fn layers() {
+  let (tx, rx) = mpsc::channel();
+  let mut failures = 2;
+  let runner = move |val: Vec<u64>, done: Done| {
+    tx.send(val).unwrap();
+    if failures > 0 {
+      failures -= 1;
+      done.err(BatchError::run("flaky"));
+    } else {
+      done.ok();
+    }
+  };
+  let batcher = Batcher::with_runner(runner.layer(RetryLayer::new(2)));
+  let ticket = batcher.append_ticket(vec![1, 2]);
+  assert!(ticket.wait().is_ok());
+  assert_eq!(rx.try_iter().count(), 3);
+
+  let (tx, rx) = mpsc::channel();
+  let runner = move |_val: Vec<u64>, done: Done| tx.send(done).unwrap();
+  let layer = TimeoutLayer::new(Duration::from_millis(10));
+  let batcher = Batcher::with_runner(runner.layer(layer.clone()));
+  let ticket = batcher.append_ticket(vec![1]);
+  match ticket.wait() {
+    Err(BatchError::Timeout) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  rx.recv().unwrap().ok();
+
+  // The batches of every runner of the layer share one watchdog thread.
+  let (tx, rx) = mpsc::channel();
+  let runner = move |_val: Vec<u64>, done: Done| tx.send(done).unwrap();
+  let batcher = Batcher::builder()
+    .pipeline(4)
+    .build_runner(runner.layer(layer));
+  let tickets: Vec<_> =
+    (0..4).map(|i| batcher.append_ticket(vec![i])).collect();
+  assert_eq!(batcher.in_flight_len(), 4);
+  #[cfg(target_os = "linux")]
+  assert_eq!(threads("batch-timeout"), 1);
+  for ticket in tickets {
+    assert!(matches!(ticket.wait(), Err(BatchError::Timeout)));
+  }
+  drop(rx);
+
+  // The metrics of a layer go by the ids of the batcher.
+  struct Ids(Mutex<mpsc::Sender<(&'static str, u64)>>, &'static str);
+  impl MetricsSink for Ids {
+    fn on_dispatch(&self, id: u64, _size: usize, _pending: usize) {
+      self.0.lock().unwrap().send((self.1, id)).unwrap();
+    }
+  }
+  let (tx, rx) = mpsc::channel();
+  let layer = MetricsLayer::new(Ids(Mutex::new(tx.clone()), "layer"));
+  let runner = |_val: Vec<u64>, done: Done| done.ok();
+  let batcher = Batcher::builder()
+    .metrics_sink(Ids(Mutex::new(tx), "batcher"))
+    .build_runner(runner.layer(layer));
+  batcher.append(vec![1], None);
+  batcher.append(vec![2], None);
+  let ids: Vec<_> = rx.try_iter().collect();
+  assert_eq!(
+    ids,
+    vec![("batcher", 1), ("layer", 1), ("batcher", 2), ("layer", 2)]
+  );
+}
+
+#[test]
+fn batch_timeout() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .batch_timeout(Duration::from_millis(20))
+    .build(move |val: Vec<u64>, done: Done| {
+      if val == vec![1] {
+        tx.send(done).unwrap();
+      } else {
+        done.ok();
+      }
+    });
+  let first = batcher.append_ticket(vec![1]);
+  let second = batcher.append_ticket(vec![2]);
+  match first.wait() {
+    Err(BatchError::Timeout) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert!(second.wait().is_ok());
+  // Signalling the timed out batch late has no effect.
+  rx.recv().unwrap().ok();
+  assert_eq!(batcher.metrics().failed, 1);
+}
+
+#[test]
+fn spawn_dispatcher() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder().name("spawned").spawn(
+    move |val: Vec<u64>, done: Done| {
+      let name = thread::current().name().map(String::from);
+      tx.send((name, val)).unwrap();
+      done.ok();
+    },
+  );
+  batcher.append(vec![1, 2], None);
+  batcher.flush();
+  let (name, val) = rx.recv().unwrap();
+  assert_eq!(name.as_deref(), Some("spawned-dispatcher"));
+  assert_eq!(val, vec![1, 2]);
+}
+
+#[test]
+fn done_starts_next_batch() {
+  let (tx, rx) = mpsc::channel();
+  let (ran_tx, ran) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    ran_tx.send(val).unwrap();
+    tx.send(done).unwrap();
+  });
+  batcher.append(vec![1], None);
+  batcher.append(vec![2], None);
+  let done = rx.recv().unwrap();
+  // No append follows: signalling from another thread alone runs the
+  // pending batch.
+  thread::spawn(move || done.ok()).join().unwrap();
+  assert_eq!(ran.recv().unwrap(), vec![1]);
+  assert_eq!(ran.recv_timeout(Duration::from_secs(1)).unwrap(), vec![2]);
+}
+
+#[test]
+fn batch_ids() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |_val: Vec<u64>, done: Done| {
+    tx.send(done.id()).unwrap();
+    done.ok();
+  });
+  let (ids_tx, ids) = mpsc::channel();
+  for i in 0..3 {
+    let ids_tx = ids_tx.clone();
+    batcher.append_with_id(
+      vec![i],
+      Some(Box::new(move |id, res| {
+        assert!(res.is_ok());
+        ids_tx.send(id.unwrap()).unwrap();
+      })),
+    );
+  }
+  let run: Vec<_> = rx.try_iter().collect();
+  let acked: Vec<_> = ids.try_iter().collect();
+  assert_eq!(run, acked);
+  assert_eq!(run.iter().map(|id| id.get()).collect::<Vec<_>>(), [1, 2, 3]);
+  batcher.close().unwrap();
+  batcher.append_with_id(
+    vec![4],
+    Some(Box::new(|id, res| {
+      assert_eq!(id, None);
+      assert!(res.is_err());
+    })),
+  );
+}
+
+#[test]
+fn append_iter() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap();
+  });
+  batcher.append_one(1, None);
+  batcher.append_iter((2..5).map(|i| i * 10), None);
+  batcher.append_one(5, None);
+  let (val, done) = rx.recv().unwrap();
+  assert_eq!(val, vec![1]);
+  done.ok();
+  let (val, done) = rx.recv().unwrap();
+  assert_eq!(val, vec![20, 30, 40, 5]);
+  done.ok();
+
+  let batcher = Batcher::builder()
+    .capacity(2)
+    .max_batch_delay(Duration::from_secs(60))
+    .build(|_val: Vec<u64>, done: Done| done.ok());
+  let (tx, rx) = mpsc::channel();
+  batcher.append_iter(0..3, Some(Box::new(move |res| tx.send(res).unwrap())));
+  match rx.recv().unwrap() {
+    Err(BatchError::CapacityExceeded) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(batcher.metrics().pending, 0);
+}
+
+#[test]
+fn pooled_buffers() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::pooled(move |val: &mut Vec<u64>, done: Done| {
+    let ptr = val.as_ptr() as usize;
+    tx.send((val.iter().sum::<u64>(), ptr)).unwrap();
+    done.ok();
+  });
+  batcher.append(vec![1, 2, 3], None);
+  batcher.append(vec![4, 5, 6], None);
+  let (first, first_ptr) = rx.recv().unwrap();
+  let (second, second_ptr) = rx.recv().unwrap();
+  assert_eq!(first, 6);
+  assert_eq!(second, 15);
+  assert_eq!(first_ptr, second_ptr);
+}
+
+#[test]
+fn adaptive_batch_size() {
+  let policy = AdaptivePolicy::new(1, 8, Duration::from_millis(10));
+  assert_eq!(policy.next_size(2, Duration::from_millis(20)), 4);
+  assert_eq!(policy.next_size(8, Duration::from_millis(20)), 8);
+  assert_eq!(policy.next_size(4, Duration::from_millis(1)), 2);
+  assert_eq!(policy.next_size(4, Duration::from_millis(7)), 4);
+
+  let batcher = Batcher::builder()
+    .max_batch_size(2)
+    .adaptive_batch_size(policy)
+    .build(|val: Vec<u64>, done: Done| {
+      if val[0] == 0 {
+        thread::sleep(Duration::from_millis(20));
+      }
+      done.ok();
+    });
+  assert_eq!(batcher.batch_size(), Some(2));
+  batcher.append(vec![0], None);
+  assert_eq!(batcher.batch_size(), Some(4));
+  batcher.append(vec![1], None);
+  assert_eq!(batcher.batch_size(), Some(2));
+
+  let invalid = AdaptivePolicy::new(4, 2, Duration::from_millis(10));
+  let res = Batcher::builder()
+    .adaptive_batch_size(invalid)
+    .try_build(|_val: Vec<u64>, done: Done| done.ok());
+  assert_eq!(res.err(), Some(ConfigError::InvalidAdaptiveRange));
+}
+
+#[test]
+fn max_batches_per_second() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder().max_batches_per_second(20).build(
+    move |val: Vec<u64>, done: Done| {
+      tx.send((Instant::now(), val)).unwrap();
+      done.ok();
+    },
+  );
+  batcher.append(vec![1], None);
+  batcher.append(vec![2], None);
+  batcher.append(vec![3], None);
+  batcher.flush();
+  let batches: Vec<_> = rx.try_iter().collect();
+  assert_eq!(batches.len(), 2);
+  assert_eq!(batches[0].1, vec![1]);
+  assert_eq!(batches[1].1, vec![2, 3]);
+  assert!(batches[1].0 - batches[0].0 >= Duration::from_millis(50));
+}
+
+#[test]
+fn coalesce() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(60))
+    .coalesce(|val: &mut Vec<u64>| {
+      val.sort();
+      val.dedup();
+    })
+    .build(move |val: Vec<u64>, done: Done| {
+      tx.send(val).unwrap();
+      done.finish_each(vec![Ok(()), Err(BatchError::run("second failed"))]);
+    });
+  let (res_tx, res) = mpsc::channel();
+  batcher.append_each(
+    vec![3, 1, 3],
+    Some(Box::new(move |results| res_tx.send(results).unwrap())),
+  );
+  batcher.append(vec![1], None);
+  batcher.flush();
+  assert_eq!(rx.recv().unwrap(), vec![1, 3]);
+  let results = res.recv().unwrap();
+  assert_eq!(results.len(), 3);
+  assert!(results.iter().all(Result::is_err));
+}
+
+#[test]
+fn max_items_per_dispatch() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder().max_items_per_dispatch(2).build(
+    move |val: Vec<u64>, done: Done| {
+      tx.send(val).unwrap();
+      done.ok();
+    },
+  );
+  let (res_tx, res) = mpsc::channel();
+  batcher.append_each(
+    vec![1, 2, 3],
+    Some(Box::new(move |results| res_tx.send(results.len()).unwrap())),
+  );
+  batcher.append(vec![4, 5, 6, 7, 8], None);
+  batcher.flush();
+  let batches: Vec<_> = rx.try_iter().collect();
+  assert_eq!(
+    batches,
+    vec![vec![1, 2], vec![3], vec![4, 5], vec![6, 7], vec![8]]
+  );
+  assert_eq!(res.try_iter().collect::<Vec<_>>(), vec![3]);
+}
+
+#[test]
+fn max_batch_bytes() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_bytes(10, |val: &String| val.len())
+    .build(move |val: Vec<String>, done: Done| {
+      tx.send((val, done)).unwrap();
+    });
+  for val in ["x", "aaaa", "bbbb", "ccc", "dddddddddddd"] {
+    batcher.append(vec![val.to_string()], None);
+  }
+  let mut batches = Vec::new();
+  for _ in 0..4 {
+    let (val, done) = rx.recv().unwrap();
+    batches.push(val.join(","));
+    done.ok();
+  }
+  assert_eq!(batches, vec!["x", "aaaa,bbbb", "ccc", "dddddddddddd"]);
+}
+
+#[test]
+fn cancel() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap();
+  });
+  batcher.append(vec![1], None);
+  let (res_tx, res) = mpsc::channel();
+  let handle = batcher.append_cancellable(
+    vec![2, 3],
+    Some(Box::new(move |res| res_tx.send(res).unwrap())),
+  );
+  batcher.append(vec![4], None);
+  assert!(handle.cancel());
+  match res.recv().unwrap() {
+    Err(BatchError::Cancelled) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  let (val, done) = rx.recv().unwrap();
+  assert_eq!(val, vec![1]);
+  done.ok();
+  let (val, done) = rx.recv().unwrap();
+  assert_eq!(val, vec![4]);
+
+  let ticket = batcher.append_ticket(vec![5, 6]);
+  assert_eq!(batcher.cancel_pending(), 2);
+  match ticket.wait() {
+    Err(BatchError::Cancelled) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  done.ok();
+  batcher.flush();
+  assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn inspect_state() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |_val: Vec<u64>, done: Done| {
+    tx.send(done).unwrap();
+  });
+  assert!(!batcher.is_running());
+  assert!(batcher.is_empty());
+  batcher.append(vec![1, 2], None);
+  batcher.append(vec![3], None);
+  assert!(batcher.is_running());
+  assert_eq!(batcher.in_flight_len(), 2);
+  assert_eq!(batcher.pending_len(), 1);
+  assert_eq!(batcher.len(), 3);
+  assert_eq!(batcher.total_dispatched(), 1);
+  rx.recv().unwrap().ok();
+  assert_eq!(batcher.in_flight_len(), 1);
+  assert_eq!(batcher.pending_len(), 0);
+  rx.recv().unwrap().ok();
+  assert!(batcher.is_empty());
+  assert_eq!(batcher.total_dispatched(), 2);
+}
+
+#[test]
+fn flush_callback() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |_val: Vec<u64>, done: Done| {
+    tx.send(done).unwrap();
+  });
+  let (flushed_tx, flushed) = mpsc::channel();
+  let flushed_now = flushed_tx.clone();
+  batcher.flush_callback(Box::new(move || flushed_now.send(0).unwrap()));
+  assert_eq!(flushed.try_recv(), Ok(0));
+
+  batcher.append(vec![1], None);
+  batcher.append(vec![2], None);
+  batcher.flush_callback(Box::new(move || flushed_tx.send(1).unwrap()));
+  rx.recv().unwrap().ok();
+  assert!(flushed.try_recv().is_err());
+  rx.recv().unwrap().ok();
+  assert_eq!(flushed.try_recv(), Ok(1));
+}
+
+#[test]
+fn runner_panicked() {
+  let batcher = Batcher::new(|val: Vec<u64>, done: Done| {
+    if val == vec![1] {
+      panic!("run failed");
+    }
+    done.ok();
+  });
+  let first = batcher.append_ticket(vec![1]);
+  match first.wait() {
+    Err(BatchError::RunnerPanicked) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert!(batcher.append_ticket(vec![2]).wait().is_ok());
+}
+
+#[test]
+fn done_dropped() {
+  let batcher = Batcher::new(|_val: Vec<u64>, done: Done| drop(done));
+  match batcher.append_ticket(vec![1]).wait() {
+    Err(BatchError::DoneDropped) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert!(!batcher.is_running());
+}
+
+#[test]
+fn batch_metadata() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder().max_batch_size(2).build_batches(
+    move |batch: Batch<u64>, done: Done| {
+      tx.send((batch, done)).unwrap();
+    },
+  );
+  batcher.append(vec![1], None);
+  batcher.append(vec![2], None);
+  batcher.append(vec![3], None);
+  batcher.append(vec![4], None);
+  let (batch, done) = rx.recv().unwrap();
+  assert_eq!(batch.items, vec![1]);
+  assert_eq!(batch.id.get(), 1);
+  assert_eq!((batch.appends, batch.trigger), (1, Trigger::Ready));
+  done.ok();
+  let (batch, done) = rx.recv().unwrap();
+  assert_eq!(batch.items, vec![2, 3]);
+  assert_eq!((batch.appends, batch.trigger), (2, Trigger::Size));
+  assert!(batch.created_at <= Instant::now());
+  done.ok();
+  let (batch, done) = rx.recv().unwrap();
+  assert_eq!(batch.items, vec![4]);
+  assert_eq!((batch.appends, batch.trigger), (1, Trigger::Ready));
+  done.ok();
+
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(60))
+    .build(move |_val: Vec<u64>, done: Done| {
+      tx.send(done.trigger()).unwrap();
+      done.ok();
+    });
+  batcher.append(vec![5], None);
+  batcher.flush();
+  assert_eq!(rx.recv().unwrap(), Trigger::Flush);
+}
+
+#[test]
+fn append_and_wait() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send(val).unwrap();
+    thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      done.err(BatchError::run("write failed"));
+    });
+  });
+  match batcher.append_and_wait(vec![1, 2]) {
+    Err(BatchError::Run(err)) => assert_eq!(err.to_string(), "write failed"),
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert_eq!(rx.try_recv(), Ok(vec![1, 2]));
+}
+
+#[test]
+fn tokio_batcher() {
+  let (tx, rx) = mpsc::channel();
+  tokio::run(future::lazy(move || {
+    let batcher = Batcher::builder()
+      .max_batch_size(2)
+      .max_batch_delay(Duration::from_millis(50))
+      .capacity(2)
+      .build_tokio(move |val: Vec<u64>| {
+        tx.send(val).unwrap();
+        let when = Instant::now() + Duration::from_millis(10);
+        Delay::new(when).map_err(BatchError::run)
+      });
+    let appends = vec![
+      batcher.append(vec![1]),
+      batcher.append(vec![2]),
+      batcher.append(vec![3]),
+    ];
+    future::join_all(appends)
+      .map(|_| ())
+      .map_err(|e| panic!("batch errored; err={:?}", e))
+  }));
+  assert_eq!(rx.try_recv().unwrap(), vec![1, 2]);
+  assert_eq!(rx.try_recv().unwrap(), vec![3]);
+}
+
+#[test]
+fn custom_runtime() {
+  struct Threads;
+  impl Runtime for Threads {
+    fn spawn(&self, future: RuntimeFuture) {
+      thread::spawn(move || future.wait());
+    }
+    fn delay(&self, at: Instant) -> RuntimeFuture {
+      let (tx, rx) = futures::sync::oneshot::channel();
+      thread::spawn(move || {
+        thread::sleep(at.saturating_duration_since(Instant::now()));
+        let _ = tx.send(());
+      });
+      Box::new(rx.map_err(|_| ()))
+    }
+  }
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_millis(20))
+    .build_on(Threads, move |val: Vec<u64>| {
+      tx.send(val).unwrap();
+      future::ok(())
+    });
+  let first = batcher.append(vec![1]);
+  let second = batcher.append(vec![2, 3]);
+  assert!(first.join(second).wait().is_ok());
+  assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3]);
+
+  // Settings the task does not apply are rejected rather than ignored.
+  let res = Batcher::builder()
+    .max_batch_size(2)
+    .drop_policy(DropPolicy::Flush)
+    .try_build_on(Threads, |_: Vec<u64>| future::ok(()));
+  assert_eq!(res.err(), Some(ConfigError::NotTokio("drop_policy")));
+  let res = Batcher::builder()
+    .coalesce(|batch: &mut Vec<u64>| batch.dedup())
+    .try_build_on(Threads, |_| future::ok(()));
+  assert_eq!(res.err(), Some(ConfigError::NotTokio("coalesce")));
+}
+
+#[test]
+fn journal() {
+  fn open(path: &std::path::Path) -> Journal<u64> {
+    Journal::open(
+      path,
+      |val: &u64| val.to_le_bytes().to_vec(),
+      |bytes| {
+        let mut val = [0; 8];
+        val.copy_from_slice(bytes.get(..8)?);
+        Some(u64::from_le_bytes(val))
+      },
+    )
+    .unwrap()
+  }
+  let path = std::env::temp_dir()
+    .join(format!("atomic-batcher-journal-{}", std::process::id()));
+  let _ = std::fs::remove_file(&path);
+
+  // A batch which is never done, as if the process died while running it.
+  let (tx, rx) = mpsc::channel();
+  let crashed = Batcher::builder()
+    .journal(open(&path))
+    .build(move |_val: Vec<u64>, done: Done| tx.send(done).unwrap());
+  crashed.append(vec![1, 2], None);
+  crashed.append(vec![3], None);
+  let _running = rx.recv().unwrap();
+
+  let (tx, rx) = mpsc::channel();
+  let restarted = Batcher::builder().journal(open(&path)).build(
+    move |val: Vec<u64>, done: Done| {
+      tx.send(val).unwrap();
+      done.ok();
+    },
+  );
+  assert_eq!(rx.recv().unwrap(), vec![1, 2, 3]);
+  restarted.append(vec![4], None);
+  assert_eq!(rx.recv().unwrap(), vec![4]);
+  assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+  drop(restarted);
+
+  // A flushing drop whose batch times out leaves it in the journal.
+  let (tx, rx) = mpsc::channel();
+  let dropped = Batcher::builder()
+    .journal(open(&path))
+    .drop_policy(DropPolicy::Flush)
+    .batch_timeout(Duration::from_millis(20))
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  dropped.pause();
+  dropped.append(vec![5], None);
+  drop(dropped);
+  let (val, _stuck) = rx.recv().unwrap();
+  assert_eq!(val, vec![5]);
+  assert!(std::fs::metadata(&path).unwrap().len() > 0);
+  std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn snapshot_restore() {
+  let (tx, _running) = mpsc::channel();
+  let batcher = Batcher::builder().max_batch_size(2).build(
+    move |_val: Vec<u64>, done: Done| {
+      tx.send(done).unwrap();
+    },
+  );
+  batcher.append(vec![1], None);
+  batcher.append(vec![2, 3], None);
+  batcher.append(vec![4], None);
+  batcher.append_with_priority(vec![5], Priority::High, None);
+  let snapshot = batcher.snapshot();
+  assert_eq!(snapshot.values(), &[5, 2, 3, 4]);
+
+  let (tx, restored) = mpsc::channel();
+  let fresh = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send(val).unwrap();
+    done.ok();
+  });
+  fresh.restore(snapshot);
+  assert_eq!(restored.recv().unwrap(), vec![5, 2, 3, 4]);
+}
+
+#[test]
+fn circuit_breaker() {
+  let (tx, rx) = mpsc::channel();
+  let breaker = CircuitBreaker::new(2, Duration::from_millis(100));
+  let batcher = Batcher::builder().circuit_breaker(breaker).build(
+    move |val: Vec<u64>, done: Done| {
+      tx.send(val).unwrap();
+      done.err(BatchError::run("backend down"));
+    },
+  );
+  assert!(batcher.append_ticket(vec![1]).wait().is_err());
+  assert!(batcher.append_ticket(vec![2]).wait().is_err());
+  assert!(batcher.is_circuit_open());
+  let start = Instant::now();
+  let waiting = batcher.append_ticket(vec![3]);
+  assert!(waiting.wait().is_err());
+  assert!(start.elapsed() >= Duration::from_millis(100));
+  assert!(batcher.is_circuit_open());
+  let sent: Vec<_> = rx.try_iter().collect();
+  assert_eq!(sent, vec![vec![1], vec![2], vec![3]]);
+
+  let batcher = Batcher::builder()
+    .circuit_breaker(breaker.fail_fast())
+    .build(|_val: Vec<u64>, done: Done| done.err(BatchError::Timeout));
+  assert!(batcher.append_ticket(vec![1]).wait().is_err());
+  assert!(batcher.append_ticket(vec![2]).wait().is_err());
+  match batcher.append_ticket(vec![3]).wait() {
+    Err(BatchError::CircuitOpen) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  let res = Batcher::<u64>::builder()
+    .circuit_breaker(CircuitBreaker::new(0, Duration::from_secs(1)))
+    .try_build(|_val, _done| {});
+  assert_eq!(res.err(), Some(ConfigError::ZeroBreakerFailures));
+}
+
+#[test]
+fn dead_letter() {
+  let (tx, rx) = mpsc::channel();
+  let runner = |val: Vec<u64>, done: Done| {
+    let results = val
+      .iter()
+      .map(|val| match val % 2 {
+        0 => Err(BatchError::run("even value")),
+        _ => Ok(()),
+      })
+      .collect();
+    done.finish_each(results);
+  };
+  let dead_letter = DeadLetterLayer::new(move |val, err| {
+    tx.send((val, err)).unwrap();
+  });
+  let runner = runner.layer(RetryLayer::new(1)).layer(dead_letter);
+  let batcher = Batcher::with_runner(runner);
+  assert!(batcher.append_ticket(vec![1, 2, 3, 4]).wait().is_err());
+  let (val, err) = rx.recv().unwrap();
+  assert_eq!(val, vec![2, 4]);
+  assert_eq!(err.to_string(), "run failed: even value");
+  assert!(batcher.append_ticket(vec![5]).wait().is_ok());
+  assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn pipeline() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .pipeline(2)
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  let (order, acked) = mpsc::channel();
+  for i in 1..4 {
+    let order = order.clone();
+    let cb = move |res: Result<(), BatchError>| order.send((i, res)).unwrap();
+    batcher.append(vec![i], Some(Box::new(cb)));
+  }
+  let (first, first_done) = rx.try_recv().unwrap();
+  let (second, second_done) = rx.try_recv().unwrap();
+  assert_eq!((first, second), (vec![1], vec![2]));
+  assert!(rx.try_recv().is_err());
+  assert_eq!(batcher.in_flight_len(), 2);
+  // The second batch waits for the first one before its callback is called.
+  second_done.ok();
+  assert!(acked.try_recv().is_err());
+  assert_eq!(batcher.in_flight_len(), 1);
+  first_done.err(BatchError::Timeout);
+  let acks: Vec<_> =
+    acked.try_iter().map(|(i, res)| (i, res.is_ok())).collect();
+  assert_eq!(acks, vec![(1, false), (2, true)]);
+  let (third, third_done) = rx.try_recv().unwrap();
+  assert_eq!(third, vec![3]);
+  third_done.ok();
+  assert!(!batcher.is_running());
+}
+
+#[test]
+fn max_concurrent_batches() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_concurrent_batches(2)
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  let first = batcher.append_ticket(vec![1]);
+  let second = batcher.append_ticket(vec![2]);
+  let third = batcher.append_ticket(vec![3]);
+  let (_, first_done) = rx.try_recv().unwrap();
+  let (_, second_done) = rx.try_recv().unwrap();
+  assert!(rx.try_recv().is_err());
+  // Done before the first batch, the second one runs its callbacks first.
+  second_done.ok();
+  assert!(second.wait().is_ok());
+  let (val, third_done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![3]);
+  third_done.ok();
+  assert!(third.wait().is_ok());
+  assert!(batcher.is_running());
+  first_done.ok();
+  assert!(first.wait().is_ok());
+  let res = Batcher::<u64>::builder()
+    .max_concurrent_batches(0)
+    .try_build(|_val, _done| {});
+  assert_eq!(res.err(), Some(ConfigError::ZeroPipeline));
+}
+
+#[test]
+fn stats() {
+  struct NotDebug;
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .name("stats")
+    .build(move |_val: Vec<NotDebug>, done: Done| tx.send(done).unwrap());
+  batcher.append(vec![NotDebug, NotDebug], None);
+  batcher.append(vec![NotDebug], None);
+  thread::sleep(Duration::from_millis(10));
+  let stats = batcher.stats();
+  assert_eq!((stats.running, stats.in_flight_items), (1, 2));
+  assert_eq!(stats.metrics.pending, 1);
+  assert!(stats.oldest_pending.unwrap() >= Duration::from_millis(10));
+  assert!(stats.to_string().starts_with("1 pending (oldest "));
+  assert!(format!("{:?}", batcher).contains("\"stats\""));
+  rx.recv().unwrap().ok();
+  rx.recv().unwrap().ok();
+  let stats = batcher.stats();
+  assert_eq!(stats.oldest_pending, None);
+  assert!(stats.items_per_second > 0.0);
+}
+
+#[test]
+fn max_item_latency() {
+  let (tx, rx) = mpsc::channel();
+  let latency = Duration::from_millis(50);
+  let batcher = Batcher::builder()
+    .max_item_latency(latency)
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  let start = Instant::now();
+  batcher.append(vec![1], None);
+  thread::sleep(Duration::from_millis(10));
+  batcher.append(vec![2], None);
+  let (val, done) = rx.recv().unwrap();
+  assert_eq!(val, vec![1, 2]);
+  assert!(start.elapsed() >= latency);
+  // Values appended while a batch runs also wait for more to join them.
+  let start = Instant::now();
+  batcher.append(vec![3], None);
+  done.ok();
+  assert!(rx.try_recv().is_err());
+  let (val, done) = rx.recv().unwrap();
+  assert_eq!(val, vec![3]);
+  assert!(start.elapsed() >= latency);
+  done.ok();
+}
+
+#[test]
+fn flush_policy() {
+  #[derive(Clone)]
+  struct Sentinel;
+  impl FlushPolicy<u64> for Sentinel {
+    fn on_append(&mut self, batch: &[u64]) -> Decision {
+      match batch.last() {
+        Some(0) => Decision::Flush,
+        _ => Decision::Wait,
+      }
+    }
+  }
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder().flush_policy(Sentinel).build(
+    move |val: Vec<u64>, done: Done| {
+      tx.send(val).unwrap();
+      done.ok();
+    },
+  );
+  batcher.append(vec![1, 2], None);
+  batcher.append(vec![3], None);
+  thread::sleep(Duration::from_millis(20));
+  assert!(rx.try_recv().is_err());
+  batcher.append(vec![4, 0], None);
+  assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3, 4, 0]);
+  batcher.append(vec![5], None);
+  batcher.flush();
+  assert_eq!(rx.try_recv().unwrap(), vec![5]);
+}
+
+#[test]
+fn build_transformed() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder().build_transformed(
+    |val: Vec<u64>| {
+      let body: Vec<String> = val.iter().map(u64::to_string).collect();
+      body.join(",")
+    },
+    move |body: String, done: Done| {
+      tx.send(body).unwrap();
+      done.ok();
+    },
+  );
+  batcher.append(vec![1, 2, 3], None);
+  assert_eq!(rx.try_recv().unwrap(), "1,2,3");
+}
+
+#[test]
+fn build_compressed() {
+  let (tx, rx) = mpsc::channel();
+  // Keeps every other byte, halving the size.
+  let halve = |bytes: &[u8]| bytes.iter().step_by(2).cloned().collect();
+  let batcher = Batcher::builder().build_compressed(
+    4,
+    |val: &u16, bytes: &mut Vec<u8>| bytes.extend(&val.to_le_bytes()),
+    halve,
+    move |bytes: Vec<u8>, done: Done| {
+      tx.send(bytes).unwrap();
+      done.ok();
+    },
+  );
+  batcher.append(vec![1, 2, 3], None);
+  assert!(rx.try_recv().is_err());
+  batcher.append(vec![4, 5], None);
+  assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3, 4, 5]);
+  batcher.append(vec![6], None);
+  batcher.flush();
+  assert_eq!(rx.try_recv().unwrap(), vec![6]);
+}
+
+#[test]
+fn try_flush() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(10))
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  assert_eq!(batcher.try_flush(), FlushOutcome::Empty);
+  batcher.append(vec![1], None);
+  assert_eq!(batcher.try_flush(), FlushOutcome::Dispatched);
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![1]);
+  batcher.append(vec![2], None);
+  assert_eq!(batcher.try_flush(), FlushOutcome::Running);
+  done.ok();
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![2]);
+  done.ok();
+  assert_eq!(batcher.try_flush(), FlushOutcome::Empty);
+}
+
+#[test]
+fn callbacks_see_own_batch() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  let (results, got) = mpsc::channel();
+  for (i, val) in vec![vec![1], vec![2], vec![]].into_iter().enumerate() {
+    let results = results.clone();
+    let cb = move |res: Result<(), BatchError>| {
+      results.send((i, res.is_ok())).unwrap();
+    };
+    batcher.append(val, Some(Box::new(cb)));
+  }
+  // Registered while the first batch runs, the latter two see the second.
+  let (_, first) = rx.try_recv().unwrap();
+  first.err(BatchError::Timeout);
+  assert_eq!(got.try_recv().unwrap(), (0, false));
+  assert!(got.try_recv().is_err());
+  let (val, second) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![2]);
+  second.ok();
+  let rest: Vec<_> = got.try_iter().collect();
+  assert_eq!(rest, vec![(1, true), (2, true)]);
+}
+
+#[test]
+fn scoped_batcher() {
+  let words = vec![String::from("a"), String::from("b"), String::from("c")];
+  let mut batches = Vec::new();
+  // Not `Batcher::builder`, which is for values which are `'static`.
+  let res = BatcherBuilder::new().max_batch_size(2).scope(
+    |batch: Vec<&String>| {
+      batches.push(batch.len());
+      Ok(())
+    },
+    |batcher| {
+      thread::scope(|s| {
+        for word in &words {
+          s.spawn(move || batcher.append(vec![word]));
+        }
+      });
+      words.len()
+    },
+  );
+  assert_eq!(res.unwrap(), 3);
+  assert_eq!(batches.iter().sum::<usize>(), 3);
+  assert!(batches.iter().all(|len| *len <= 2));
+}
+
+#[test]
+fn scope_rejects_settings() {
+  let words = [String::from("a")];
+  let builder = BatcherBuilder::new()
+    .max_batch_size(2)
+    .max_batch_delay(Duration::from_millis(10));
+  let res = builder.try_scope(
+    |_batch: Vec<&String>| Ok(()),
+    |batcher| batcher.append(vec![&words[0]]),
+  );
+  assert_eq!(res.err(), Some(ConfigError::NotScoped("max_batch_delay")));
+  let builder = BatcherBuilder::new().coalesce(|batch: &mut Vec<&String>| {
+    batch.dedup();
+  });
+  let res = builder.try_scope(|_batch| Ok(()), |_batcher| ());
+  assert_eq!(res.err(), Some(ConfigError::NotScoped("coalesce")));
+  let res = BatcherBuilder::new()
+    .name("scoped")
+    .try_scope(|_batch: Vec<&String>| Ok(()), |_batcher| 1);
+  assert_eq!(res.unwrap().unwrap(), 1);
+}
+
+#[test]
+fn build_into() {
+  use std::collections::VecDeque;
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder().build_into(
+    move |mut batch: VecDeque<u64>, done: Done| {
+      batch.rotate_left(1);
+      tx.send(batch).unwrap();
+      done.ok();
+    },
+  );
+  batcher.append(vec![1, 2, 3], None);
+  assert_eq!(rx.try_recv().unwrap(), vec![2, 3, 1]);
+}
+
+#[test]
+fn batch_container() {
+  use std::collections::VecDeque;
+  let (tx, rx) = mpsc::channel();
+  let batcher: Arc<Batcher<u64, VecDeque<u64>>> = Batcher::builder()
+    .max_items_per_dispatch(2)
+    .build_into(move |batch, done| tx.send((batch, done)).unwrap());
+  batcher.append(vec![1], None);
+  let (first, done) = rx.try_recv().unwrap();
+  assert_eq!(first, vec![1]);
+  // The values wait in a `VecDeque` of the batcher meanwhile.
+  batcher.append(vec![2, 3, 4], None);
+  let handle = batcher.append_cancellable(vec![5, 6], None);
+  batcher.append(vec![7], None);
+  assert!(handle.cancel());
+  batcher.with_pending(|pending| pending.push_front(0));
+  assert_eq!(batcher.snapshot().into_values(), vec![0, 2, 3, 4, 7]);
+  done.ok();
+  let mut batches = Vec::new();
+  while let Ok((batch, done)) = rx.try_recv() {
+    batches.push(Vec::from(batch));
+    done.ok();
+  }
+  assert_eq!(batches, vec![vec![0, 2], vec![3, 4], vec![7]]);
+  assert!(batcher.is_empty());
+}
+
+#[test]
+fn drain_from() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  let (items, source) = mpsc::channel();
+  assert_eq!(batcher.drain_from(&source), 0);
+  assert!(rx.try_recv().is_err());
+  for i in 1..4 {
+    items.send(i).unwrap();
+  }
+  assert_eq!(batcher.drain_from(&source), 3);
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![1, 2, 3]);
+  done.ok();
+
+  let (mut stream_tx, mut stream) = futures::sync::mpsc::unbounded();
+  stream_tx.start_send(4).unwrap();
+  stream_tx.start_send(5).unwrap();
+  let drained = future::lazy(|| batcher.drain_stream(&mut stream)).wait();
+  assert_eq!(drained.unwrap(), 2);
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![4, 5]);
+  done.ok();
+}
+
+#[test]
+fn for_each_batch() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::for_each_batch(move |val: Vec<u64>| {
+    tx.send(val.len()).unwrap();
+    match val.len() {
+      1 => Ok(()),
+      _ => Err(BatchError::run("too many")),
+    }
+  });
+  assert!(batcher.append_ticket(vec![1]).wait().is_ok());
+  assert!(batcher.append_ticket(vec![2, 3]).wait().is_err());
+  assert!(!batcher.is_running());
+  assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+}
+
+#[test]
+fn dispatch_hooks() {
+  let (tx, rx) = mpsc::channel();
+  let before = tx.clone();
+  let batcher = Batcher::builder()
+    .before_dispatch(move |id, batch: &[u64]| {
+      before
+        .send(format!("before {} {:?}", id.get(), batch))
+        .unwrap();
+    })
+    .after_complete(move |id, res| {
+      tx.send(format!("after {} {}", id.get(), res.is_ok()))
+        .unwrap();
+    })
+    .build_for_each(|_val: Vec<u64>| Err(BatchError::Timeout));
+  assert!(batcher.append_ticket(vec![1, 2]).wait().is_err());
+  let events: Vec<_> = rx.try_iter().collect();
+  assert_eq!(events, vec!["before 1 [1, 2]", "after 1 false"]);
+}
+
+#[test]
+fn append_with_result() {
+  let mut offset = 0;
+  let batcher = Batcher::builder().build(move |val: Vec<u64>, done: Done| {
+    offset += val.len() as u64;
+    match val[0] {
+      0 => done.ok(),
+      _ => done.finish_with(Ok(offset)),
+    }
+  });
+  let (tx, rx) = mpsc::channel();
+  let results = tx.clone();
+  batcher.append_with_result(vec![1, 2], move |res: Result<Arc<u64>, _>| {
+    results.send(res.map(|offset| *offset)).unwrap();
+  });
+  let results = tx.clone();
+  batcher.append_with_result(vec![0], move |res: Result<Arc<u64>, _>| {
+    results.send(res.map(|offset| *offset)).unwrap();
+  });
+  // A callback expecting another type gets no value.
+  batcher.append_with_result(vec![3], move |res: Result<Arc<String>, _>| {
+    tx.send(res.map(|_| 0)).unwrap();
+  });
+  assert_eq!(rx.try_recv().unwrap().unwrap(), 2);
+  match rx.try_recv().unwrap() {
+    Err(BatchError::NoResult) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  match rx.try_recv().unwrap() {
+    Err(BatchError::NoResult) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+}
+
+#[test]
+fn append_map() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  let (responses, got) = mpsc::channel();
+  for val in [vec![1], vec![2, 3], vec![4]] {
+    let responses = responses.clone();
+    batcher.append_map(val, move |res: Result<Vec<String>, _>| {
+      responses.send(res.unwrap()).unwrap();
+    });
+  }
+  let (val, done) = rx.try_recv().unwrap();
+  done.respond(val.iter().map(|val| val.to_string()).collect());
+  let (val, done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![2, 3, 4]);
+  done.respond(val.iter().map(|val| (val * 10).to_string()).collect());
+  let got: Vec<_> = got.try_iter().collect();
+  assert_eq!(got, vec![vec!["1"], vec!["20", "30"], vec!["40"]]);
+}
+
+#[test]
+fn batched_fn() {
+  let (tx, rx) = mpsc::channel();
+  let (release, wait) = mpsc::channel::<()>();
+  let square = BatchedFn::new(move |reqs: Vec<u64>| {
+    tx.send(reqs.len()).unwrap();
+    wait.recv().unwrap();
+    Ok(reqs.iter().map(|req| req * req).collect())
+  });
+  let first = {
+    let square = square.clone();
+    thread::spawn(move || square.call(2).wait())
+  };
+  while rx.try_recv().is_err() {
+    thread::yield_now();
+  }
+  // Calls made while the backend runs share its next call.
+  let second = square.call(3);
+  let third = square.call(4);
+  // The second batch runs on the thread which was done with the first.
+  release.send(()).unwrap();
+  release.send(()).unwrap();
+  assert_eq!(first.join().unwrap().unwrap(), 4);
+  assert_eq!(second.wait().unwrap(), 9);
+  assert_eq!(third.wait().unwrap(), 16);
+  assert_eq!(rx.try_recv().unwrap(), 2);
+}
+
+#[test]
+fn weak_handle() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send(val).unwrap();
+    done.ok();
+  });
+  let weak = batcher.handle().downgrade();
+  assert!(weak.append_and_wait(vec![1]).is_ok());
+  assert_eq!(rx.try_recv().unwrap(), vec![1]);
+  drop(batcher);
+  assert!(weak.upgrade().is_none());
+  match weak.append_ticket(vec![2]).wait() {
+    Err(BatchError::Closed) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  let (failed, closed) = mpsc::channel();
+  weak.append(
+    vec![3],
+    Some(Box::new(move |res| failed.send(res).unwrap())),
+  );
+  assert!(closed.try_recv().unwrap().is_err());
+}
+
+#[test]
+fn pause_resume() {
+  let (tx, rx) = mpsc::channel();
+  let batcher =
+    Batcher::builder()
+      .capacity(2)
+      .build(move |val: Vec<u64>, done: Done| {
+        tx.send(val).unwrap();
+        done.ok();
+      });
+  batcher.pause();
+  assert!(batcher.is_paused());
+  batcher.append(vec![1], None);
+  batcher.append(vec![2], None);
+  assert!(batcher.try_append(vec![3], None).is_err());
+  assert_eq!(batcher.try_flush(), FlushOutcome::Paused);
+  assert!(rx.try_recv().is_err());
+  batcher.resume();
+  assert_eq!(rx.try_recv().unwrap(), vec![1, 2]);
+  batcher.append(vec![3], None);
+  assert_eq!(rx.try_recv().unwrap(), vec![3]);
+}
+
+#[test]
+fn manual_batcher() {
+  let manual = ManualBatcher::with_builder(
+    Batcher::builder().max_batch_delay(Duration::from_secs(60)),
+  );
+  assert!(!manual.complete_current(Ok(())));
+  manual.append(vec![1], None);
+  manual.handle().append(vec![2], None);
+  assert_eq!(manual.current(), None);
+  assert_eq!(manual.tick(), FlushOutcome::Dispatched);
+  assert_eq!(manual.current(), Some(vec![1, 2]));
+  let ticket = manual.batcher().append_ticket(vec![3]);
+  assert!(manual.complete_current(Err(BatchError::Timeout)));
+  assert_eq!(manual.current(), Some(vec![3]));
+  assert!(manual.complete_current(Ok(())));
+  assert!(ticket.wait().is_ok());
+  assert_eq!(manual.tick(), FlushOutcome::Empty);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn testing_runners() {
+  use atomic_batcher::testing::{GatedRunner, RecordingRunner};
+  let recording = RecordingRunner::new();
+  let batcher = Batcher::builder()
+    .max_batch_size(2)
+    .build_runner(recording.clone());
+  batcher.append(vec![1, 2, 3], None);
+  recording.assert_batches(&[&[1, 2, 3]]);
+
+  let gated = GatedRunner::new();
+  let batcher = Batcher::with_runner(gated.clone());
+  let first = batcher.append_ticket(vec![1]);
+  let second = batcher.append_ticket(vec![2]);
+  assert_eq!(gated.held(), 1);
+  assert_eq!(gated.release(Err(BatchError::Timeout)), Some(vec![1]));
+  assert!(first.wait().is_err());
+  assert_eq!(gated.release_all(), 1);
+  assert!(second.wait().is_ok());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn mock_clock() {
+  use atomic_batcher::testing::MockClock;
+  let clock = MockClock::new();
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(60))
+    .batch_timeout(Duration::from_secs(600))
+    .clock(clock.clone())
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  let (expired_tx, expired) = mpsc::channel();
+  batcher.append(vec![1], None);
+  let cb = move |res| expired_tx.send(res).unwrap();
+  batcher.append_with_ttl(vec![2], Duration::from_secs(30), Some(Box::new(cb)));
+  clock.advance(Duration::from_secs(59));
+  assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+  // The delay is over a minute in, by which the second value expired.
+  clock.advance(Duration::from_secs(1));
+  let (val, _timed_out) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+  assert_eq!(val, vec![1]);
+  let res = expired.recv_timeout(Duration::from_secs(1)).unwrap();
+  assert!(matches!(res, Err(BatchError::Expired)));
+  // Ten minutes later the batch times out, and the next one runs.
+  let ticket = batcher.append_ticket(vec![3]);
+  clock.advance(Duration::from_secs(600));
+  let (val, done) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+  assert_eq!(val, vec![3]);
+  done.ok();
+  assert!(ticket.wait().is_ok());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn mock_clock_times_out_flushing_drop() {
+  use atomic_batcher::testing::{MockClock, RecordingRunner};
+  let clock = MockClock::new();
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .batch_timeout(Duration::from_secs(600))
+    .drop_policy(DropPolicy::Flush)
+    .clock(clock.clone())
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  batcher.pause();
+  let ticket = batcher.append_ticket(vec![1]);
+  let dropping = thread::spawn(move || drop(batcher));
+  // The drop runs the batch and waits for it by the mock clock.
+  let (val, _held) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+  assert_eq!(val, vec![1]);
+  clock.advance(Duration::from_secs(600));
+  dropping.join().unwrap();
+  assert!(matches!(ticket.wait(), Err(BatchError::Timeout)));
+  // Recordings go by the mock clock too.
+  let recording = RecordingRunner::new().clock(clock.clone());
+  let batcher = Batcher::builder()
+    .clock(clock.clone())
+    .build_runner(recording.clone());
+  let start = clock.now();
+  clock.advance(Duration::from_secs(5));
+  batcher.append(vec![2], None);
+  let records = recording.records();
+  assert_eq!(records[0].0 - start, Duration::from_secs(5));
+}
+
+#[cfg(feature = "check")]
+#[test]
+fn invariants_hold() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_size(2)
+    .max_items_per_dispatch(3)
+    .pipeline(2)
+    .build(move |batch: Vec<u64>, done| {
+      tx.send((batch, done)).unwrap();
+    });
+  let mut seed = 7u64;
+  let mut done = Vec::new();
+  let mut handles = Vec::new();
+  for step in 0..200 {
+    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+    let values = vec![step; (seed >> 33) as usize % 5];
+    match (seed >> 40) % 4 {
+      0 => handles.push(batcher.append_cancellable(values, None)),
+      1 => batcher.append(values, Some(Box::new(|_| {}))),
+      2 => {
+        let _ = batcher.cancel_pending();
+      }
+      _ => batcher.append(values, None),
+    }
+    if (seed >> 50).is_multiple_of(3) {
+      if let Some(handle) = handles.pop() {
+        handle.cancel();
+      }
+    }
+    done.extend(rx.try_iter());
+    if (seed >> 45).is_multiple_of(2) && !done.is_empty() {
+      let (_, d): (Vec<u64>, Done) = done.remove(0);
+      d.ok();
+    }
+    batcher.invariants();
+  }
+  while batcher.is_running() || !done.is_empty() {
+    done.extend(rx.try_iter());
+    if !done.is_empty() {
+      done.remove(0).1.ok();
+    }
+  }
+  batcher.invariants();
+  assert_eq!(batcher.metrics().pending, 0);
+}
+
+#[test]
+fn group_flushes_and_closes_all() {
+  let group = BatcherGroup::new();
+  let (tx, rx) = mpsc::channel();
+  let numbers = Batcher::builder()
+    .name("numbers")
+    .max_batch_delay(Duration::from_secs(60))
+    .build(move |batch: Vec<u32>, done| {
+      tx.send(batch.len()).unwrap();
+      done.ok();
+    });
+  let words = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(60))
+    .build(|_: Vec<&str>, done| done.ok());
+  group.add(numbers.clone());
+  group.add(words.clone());
+  assert_eq!(group.len(), 2);
+
+  numbers.append(vec![1, 2], None);
+  words.append(vec!["a"], None);
+  group.flush_all();
+  assert_eq!(rx.try_recv(), Ok(2));
+  let total = group.total_stats();
+  assert_eq!(total.metrics.appended, 3);
+  assert_eq!(total.metrics.completed, 2);
+  assert_eq!(group.stats()[0].0, Some("numbers".to_string()));
+
+  words.append(vec!["b"], None);
+  assert!(group.close_all().is_ok());
+  assert_eq!(words.metrics().completed, 2);
+  assert!(words.append_and_wait(vec!["c"]).is_err());
+}
+
+#[test]
+fn chained_batchers() {
+  let (tx, rx) = mpsc::channel();
+  let pages = Batcher::builder().max_batch_size(2).build(
+    move |pages: Vec<String>, done| {
+      tx.send(pages.clone()).unwrap();
+      if pages.iter().any(|page| page.contains('3')) {
+        done.finish(Err(BatchError::Timeout));
+      } else {
+        done.ok();
+      }
+    },
+  );
+  let rows = Batcher::builder().build_chained(pages, |rows: Vec<u32>| {
+    vec![rows.iter().map(|row| row.to_string()).collect::<String>()]
+  });
+  assert!(rows.append_and_wait(vec![1, 2]).is_ok());
+  assert_eq!(rx.try_recv(), Ok(vec!["12".to_string()]));
+  assert!(rows.append_and_wait(vec![3]).is_err());
+}
+
+#[test]
+fn fanout_runner() {
+  let (tx, rx) = mpsc::channel();
+  let fanout = FanoutRunner::new()
+    .sink(move |batch: Vec<u32>, done: Done| {
+      tx.send(batch).unwrap();
+      done.ok();
+    })
+    .sink(|batch: Vec<u32>, done: Done| {
+      if batch.contains(&0) {
+        done.err(BatchError::Timeout);
+      } else {
+        done.ok();
+      }
+    });
+  let batcher = Batcher::with_runner(fanout);
+  assert!(batcher.append_and_wait(vec![1, 2]).is_ok());
+  assert_eq!(rx.try_recv(), Ok(vec![1, 2]));
+  match batcher.append_and_wait(vec![0]) {
+    Err(BatchError::Fanout(errors)) => {
+      assert_eq!(errors.len(), 1);
+      assert_eq!(errors[0].0, 1);
+    }
+    res => panic!("unexpected {:?}", res),
+  }
+
+  let quorum = FanoutRunner::new()
+    .sink(|_: Vec<u32>, done: Done| done.err(BatchError::Timeout))
+    .sink(|_: Vec<u32>, done: Done| done.ok())
+    .quorum(1);
+  let batcher = Batcher::with_runner(quorum);
+  assert!(batcher.append_and_wait(vec![1]).is_ok());
+}
+
+#[test]
+fn sharded_batcher() {
+  let (tx, rx) = mpsc::channel();
+  let tx = Mutex::new(tx);
+  let sharded = ShardedBatcher::new(
+    2,
+    |val: &u64| *val,
+    move |shard, batch, done: Done| {
+      tx.lock().unwrap().send((shard, batch)).unwrap();
+      done.ok();
+    },
+  );
+  assert_eq!(sharded.shards(), 2);
+  assert_eq!(sharded.shard_of(&3), 1);
+  assert!(sharded.append_and_wait(vec![1, 2, 3, 4]).is_ok());
+  let mut batches: Vec<_> = rx.try_iter().collect();
+  batches.sort();
+  assert_eq!(batches, vec![(0, vec![2, 4]), (1, vec![1, 3])]);
+
+  let builder = Batcher::builder();
+  let runners: Vec<fn(Vec<u64>, Done)> =
+    vec![|_, done| done.ok(), |_, done| done.err(BatchError::Timeout)];
+  let sharded =
+    ShardedBatcher::with_runners(builder, |val: &u64| *val, runners).unwrap();
+  assert!(sharded.append_and_wait(vec![2]).is_ok());
+  assert!(sharded.append_and_wait(vec![2, 3]).is_err());
+  assert!(ShardedBatcher::<u64>::with_runners(
+    Batcher::builder(),
+    |val: &u64| *val,
+    Vec::<fn(Vec<u64>, Done)>::new(),
+  )
+  .is_err());
+}
+
+#[test]
+fn tumbling_windows() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .window(Duration::from_millis(200))
+    .build(move |batch: Vec<u32>, done| tx.send((batch, done)).unwrap());
+  batcher.append(vec![1], None);
+  batcher.append(vec![2], None);
+  let (batch, done) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+  assert_eq!(batch, vec![1, 2]);
+  assert_eq!(done.trigger(), Trigger::Time);
+
+  // Later windows close while the first batch is still running.
+  batcher.append(vec![3], None);
+  thread::sleep(Duration::from_millis(250));
+  batcher.append(vec![4], None);
+  thread::sleep(Duration::from_millis(250));
+  done.ok();
+  let (batch, done) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+  assert_eq!(batch, vec![3]);
+  done.ok();
+  let (batch, done) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+  assert_eq!(batch, vec![4]);
+  done.ok();
+}
+
+#[test]
+fn expired_values_are_left_out() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |batch: Vec<u32>, done| {
+    tx.send((batch, done)).unwrap();
+  });
+  batcher.append(vec![1], None);
+  let (_, first) = rx.recv().unwrap();
+  let stale = batcher.append_ticket(vec![]);
+  let (etx, erx) = mpsc::channel();
+  batcher.append_with_ttl(
+    vec![2, 3],
+    Duration::from_millis(10),
+    Some(Box::new(move |res| etx.send(res).unwrap())),
+  );
+  batcher.append_with_ttl(vec![4], Duration::from_secs(60), None);
+  thread::sleep(Duration::from_millis(20));
+  first.ok();
+  match erx.recv_timeout(Duration::from_secs(1)) {
+    Ok(Err(BatchError::Expired)) => {}
+    res => panic!("unexpected {:?}", res),
+  }
+  let (batch, done) = rx.recv().unwrap();
+  assert_eq!(batch, vec![4]);
+  done.ok();
+  assert!(stale.wait().is_ok());
+}
+
+#[test]
+fn idempotency_keys() {
+  let (tx, rx) = mpsc::channel();
+  let mut failed = false;
+  let runner = (move |_: Vec<u32>, done: Done| {
+    tx.send(done.idempotency_key()).unwrap();
+    if failed {
+      done.ok();
+    } else {
+      failed = true;
+      done.err(BatchError::Timeout);
+    }
+  })
+  .layer(RetryLayer::new(1));
+  let batcher = Batcher::with_runner(runner);
+  assert!(batcher.append_and_wait(vec![1]).is_ok());
+  let first = rx.try_recv().unwrap();
+  assert_eq!(rx.try_recv(), Ok(first));
+  assert!(batcher.append_and_wait(vec![2]).is_ok());
+  assert_ne!(rx.try_recv().unwrap(), first);
+  assert_eq!(format!("{}", first).len(), 32);
+
+  #[derive(Clone)]
+  struct Keyed;
+  impl FlushPolicy<u32> for Keyed {
+    fn on_append(&mut self, _: &[u32]) -> Decision {
+      Decision::Flush
+    }
+    fn idempotency_key(&mut self, batch: &[u32]) -> Option<IdempotencyKey> {
+      Some(IdempotencyKey::new(u128::from(batch[0])))
+    }
+  }
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder().flush_policy(Keyed).build_batches(
+    move |batch: Batch<u32>, done| {
+      tx.send(batch.idempotency_key.get()).unwrap();
+      done.ok();
+    },
+  );
+  batcher.append(vec![7], None);
+  assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(7));
+}
+
+#[test]
+fn cancellation_token_shuts_down() {
+  let token = CancellationToken::new();
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .cancellation(token.clone())
+    .build(move |batch: Vec<u32>, done| tx.send((batch, done)).unwrap());
+  let running = batcher.append_ticket(vec![1]);
+  let waiting = batcher.append_ticket(vec![2]);
+  let (_, done) = rx.recv().unwrap();
+  token.cancel();
+  assert!(token.is_cancelled());
+  match waiting.wait() {
+    Err(BatchError::Cancelled) => {}
+    res => panic!("unexpected {:?}", res),
+  }
+  done.ok();
+  assert!(running.wait().is_ok());
+  assert!(rx.try_recv().is_err());
+  assert!(batcher.append_and_wait(vec![3]).is_err());
+
+  // A batcher built with a cancelled token starts out shut down.
+  let batcher = Batcher::builder()
+    .cancellation(token)
+    .build(|_: Vec<u32>, done| done.ok());
+  assert!(batcher.append_and_wait(vec![1]).is_err());
+}
+
+#[test]
+fn cancellation_token_forgets_dropped_batchers() {
+  let token = CancellationToken::new();
+  let build = || {
+    Batcher::builder()
+      .cancellation(token.clone())
+      .build(|_: Vec<u32>, done| done.ok())
+  };
+  let kept = build();
+  for _ in 0..100 {
+    drop(build());
+  }
+  assert!(format!("{:?}", token).contains("listeners: 1"));
+  drop(kept);
+  assert!(format!("{:?}", token).contains("listeners: 0"));
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn logs_batches() {
+  use log::{Level, Log, Metadata, Record};
+  struct Capture(Mutex<Vec<(Level, String)>>);
+  impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+      true
+    }
+    fn log(&self, record: &Record) {
+      let line = format!("{}", record.args());
+      self.0.lock().unwrap().push((record.level(), line));
+    }
+    fn flush(&self) {}
+  }
+  static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+  log::set_logger(&CAPTURE).unwrap();
+  log::set_max_level(log::LevelFilter::Trace);
+
+  let batcher = Batcher::builder()
+    .name("logged")
+    .log_levels(Level::Info, Level::Error)
+    .build(|_: Vec<u32>, done: Done| done.err(BatchError::Timeout));
+  let _ = batcher.append_and_wait(vec![1]);
+  let lines = CAPTURE.0.lock().unwrap().clone();
+  assert!(lines.iter().any(|(level, line)| *level == Level::Info
+    && line.starts_with("logged: batch 1 started with 1 values")));
+  assert!(lines.iter().any(|(level, line)| *level == Level::Error
+    && line.starts_with("logged: batch 1 failed")));
+}
+
+#[test]
+fn tokio_batch_timeout_aborts() {
+  let (tx, rx) = mpsc::channel();
+  let (dropped_tx, dropped) = mpsc::channel::<()>();
+  tokio::run(future::lazy(move || {
+    let batcher = Batcher::builder()
+      .max_batch_size(1)
+      .batch_timeout(Duration::from_millis(20))
+      .abort_timed_out()
+      .build_tokio(move |val: Vec<u64>| {
+        let stuck = val == vec![1];
+        let guard = dropped_tx.clone();
+        future::poll_fn(move || {
+          let _ = &guard;
+          if stuck {
+            Ok(Async::NotReady)
+          } else {
+            Ok(Async::Ready(()))
+          }
+        })
+      });
+    let first = batcher.append(vec![1]).then(move |res| {
+      tx.send(res.is_err()).unwrap();
+      Ok(())
+    });
+    let second = batcher.append(vec![2]);
+    first
+      .join(second)
+      .map(|_| ())
+      .map_err(|e: BatchError| panic!("{}", e))
+  }));
+  assert_eq!(rx.try_recv(), Ok(true));
+  // The stuck future was dropped, rather than left running.
+  assert_eq!(dropped.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+}
+
+#[test]
+fn progress_extends_batch_timeout() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .batch_timeout(Duration::from_millis(100))
+    .build(move |val: Vec<u64>, done: Done| {
+      let tx = tx.clone();
+      thread::spawn(move || {
+        for committed in 1..=val.len() {
+          thread::sleep(Duration::from_millis(30));
+          done.progress(committed);
+          tx.send(()).unwrap();
+        }
+        done.ok();
+      });
+    });
+  let ticket = batcher.append_ticket(vec![1, 2, 3, 4, 5]);
+  rx.recv().unwrap();
+  rx.recv().unwrap();
+  assert!(batcher.stats().committed_items >= 2);
+  // Five steps take longer than the timeout, but each restarts it.
+  assert!(ticket.wait().is_ok());
+  assert_eq!(batcher.metrics().failed, 0);
+}
+
+#[test]
+fn resume_after_committed_prefix() {
+  let (tx, rx) = mpsc::channel();
+  let runner = move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap();
+  let batcher = Batcher::with_runner(runner.layer(RetryLayer::new(1)));
+  let first = batcher.append_ticket(vec![0]);
+  let (_, done) = rx.recv().unwrap();
+  let second = batcher.append_ticket(vec![1, 2]);
+  let third = batcher.append_ticket(vec![3, 4]);
+  done.ok();
+  let (val, done) = rx.recv().unwrap();
+  assert_eq!(val, vec![1, 2, 3, 4]);
+  done.err_after(2, BatchError::run("disk full"));
+  // Only the values after the committed ones run again.
+  let (val, done) = rx.recv().unwrap();
+  assert_eq!(val, vec![3, 4]);
+  done.ok();
+  assert!(first.wait().is_ok());
+  assert!(second.wait().is_ok());
+  assert!(third.wait().is_ok());
+
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val, done| tx.send((val, done)).unwrap());
+  let first = batcher.append_ticket(vec![0]);
+  let (_, done) = rx.recv().unwrap();
+  let second = batcher.append_ticket(vec![1, 2]);
+  let third = batcher.append_ticket(vec![3, 4]);
+  done.ok();
+  let (_, done): (Vec<u64>, Done) = rx.recv().unwrap();
+  done.err_after(3, BatchError::run("disk full"));
+  assert!(first.wait().is_ok());
+  assert!(second.wait().is_ok());
+  assert!(third.wait().is_err());
+}
+
+#[test]
+fn downcast_run_error() {
+  let batcher = Batcher::new(|_val: Vec<u64>, done: Done| {
+    let err = io::Error::new(io::ErrorKind::WriteZero, "short write");
+    done.err(BatchError::run(err))
+  });
+  let err = batcher.append_and_wait(vec![1]).unwrap_err();
+  let io_err = err.downcast_ref::<io::Error>().expect("an io error");
+  assert_eq!(io_err.kind(), io::ErrorKind::WriteZero);
+  assert!(err.downcast_ref::<fmt::Error>().is_none());
+  assert!(BatchError::Closed.downcast_ref::<io::Error>().is_none());
+}
+
+#[test]
+fn chunk_errors() {
+  let run = |val: Vec<u64>, done: Done| match val[0] {
+    1 => done.err(BatchError::run("first")),
+    5 => done.err(BatchError::run("third")),
+    _ => done.ok(),
+  };
+  let policies = [
+    (ChunkErrors::First, "run failed: first"),
+    (ChunkErrors::Last, "run failed: third"),
+    (
+      ChunkErrors::All,
+      "2 chunks failed; run failed: first; run failed: third",
+    ),
+  ];
+  for &(policy, expected) in &policies {
+    let batcher = Batcher::builder()
+      .max_items_per_dispatch(2)
+      .chunk_errors(policy)
+      .build(run);
+    let err = batcher.append_and_wait(vec![1, 2, 3, 4, 5, 6]).unwrap_err();
+    assert_eq!(err.to_string(), expected);
+  }
+}
+
+#[test]
+fn append_unchecked() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(10))
+    .capacity(2)
+    .build(move |val: Vec<u64>, done: Done| {
+      tx.send((val, done.trigger())).unwrap();
+      done.ok();
+    });
+  batcher.append(vec![1], None);
+  // Neither lingering nor the capacity hold the caller's batch up, and the
+  // values appended before run first.
+  let ticket = batcher.append_ticket(vec![]);
+  batcher.append_unchecked(vec![2, 3, 4], None);
+  assert_eq!(rx.recv().unwrap(), (vec![1], Trigger::Ready));
+  assert_eq!(rx.recv().unwrap(), (vec![2, 3, 4], Trigger::Ready));
+  assert!(ticket.wait().is_ok());
+  batcher.close().unwrap();
+  let (tx, closed) = mpsc::channel();
+  batcher.append_unchecked(
+    vec![5],
+    Some(Box::new(move |res| tx.send(res).unwrap())),
+  );
+  assert!(closed.recv().unwrap().is_err());
+}
+
+#[test]
+fn segmented_queue() {
+  let lens = |queue: &SegmentedQueue<u64>| -> Vec<usize> {
+    queue.segments().map(<[u64]>::len).collect()
+  };
+  let mut queue: SegmentedQueue<u64> = (0..10_000).collect();
+  assert_eq!(lens(&queue), vec![4096, 4096, 1808]);
+  let rest = queue.split_off(5000);
+  assert_eq!(lens(&queue), vec![4096, 904]);
+  assert_eq!(lens(&rest), vec![3192, 1808]);
+  assert_eq!(rest.into_vec(), (5000..10_000).collect::<Vec<_>>());
+
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_items_per_dispatch(6000)
+    .build_pooled_into(move |batch: &mut SegmentedQueue<u64>, done: Done| {
+      tx.send(lens(batch)).unwrap();
+      done.ok();
+    });
+  batcher.pause();
+  batcher.append_iter(0..10_000, None);
+  batcher.resume();
+  // Only the segment the runs are split in is moved.
+  assert_eq!(rx.try_recv().unwrap(), vec![4096, 1904]);
+  assert_eq!(rx.try_recv().unwrap(), vec![2192, 1808]);
+}
+
+#[test]
+fn local_batcher() {
+  let batches = Rc::new(RefCell::new(Vec::new()));
+  let dones = Rc::new(RefCell::new(Vec::new()));
+  let (ran, held) = (batches.clone(), dones.clone());
+  let batcher = LocalBatcher::new(move |val: Vec<Rc<u64>>, done| {
+    ran
+      .borrow_mut()
+      .push(val.iter().map(|val| **val).collect::<Vec<_>>());
+    held.borrow_mut().push(done);
+  });
+  let results = Rc::new(RefCell::new(Vec::new()));
+  for i in 1..4 {
+    let results = results.clone();
+    let cb = move |res: Result<(), BatchError>| {
+      results.borrow_mut().push((i, res.is_ok()))
+    };
+    batcher.append(vec![Rc::new(i)], Some(Box::new(cb)));
+  }
+  assert_eq!(*batches.borrow(), vec![vec![1]]);
+  assert_eq!(batcher.pending(), 2);
+  let done: LocalDone = dones.borrow_mut().pop().unwrap();
+  done.ok();
+  assert_eq!(*batches.borrow(), vec![vec![1], vec![2, 3]]);
+  let done = dones.borrow_mut().pop().unwrap();
+  done.err(BatchError::run("failed"));
+  assert_eq!(*results.borrow(), vec![(1, true), (2, false), (3, false)]);
+  assert!(!batcher.is_running());
+}
+
+#[test]
+fn extend_batcher() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send(val).unwrap();
+    done.ok();
+  });
+  (1..4).map(|i| i * 10).collect_into_batcher(&batcher);
+  let mut appender = &*batcher;
+  appender.extend(vec![4, 5]);
+  let mut handle = batcher.handle();
+  handle.extend(Some(6));
+  let batches: Vec<Vec<u64>> = rx.try_iter().collect();
+  assert_eq!(batches, vec![vec![10, 20, 30], vec![4, 5], vec![6]]);
+}
+
+#[test]
+fn map_items() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<String>, done: Done| {
+    tx.send(val).unwrap();
+    done.ok();
+  });
+  let typed = batcher.map_items(|id: u64| format!("id={}", id));
+  typed.append(vec![1, 2], None);
+  typed.clone().append_one(3, None);
+  assert!(typed.append_and_wait(vec![4]).is_ok());
+  let batches: Vec<Vec<String>> = rx.try_iter().collect();
+  assert_eq!(batches[0], vec!["id=1", "id=2"]);
+  assert_eq!(batches[1..], [vec!["id=3"], vec!["id=4"]]);
+}
+
+#[test]
+fn with_pending() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_secs(10))
+    .build(move |val: Vec<u64>, done: Done| {
+      tx.send(val).unwrap();
+      done.ok();
+    });
+  let first = batcher.append_ticket(vec![3, 1, 2]);
+  let second = batcher.append_ticket(vec![2, 5]);
+  let pruned = batcher.with_pending(|val| {
+    val.sort();
+    val.dedup();
+    val.retain(|&val| val != 5);
+    val.len()
+  });
+  assert_eq!(pruned, 3);
+  batcher.flush();
+  assert_eq!(rx.try_recv(), Ok(vec![1, 2, 3]));
+  assert!(first.wait().is_ok());
+  assert!(second.wait().is_ok());
+
+  // Grown to a full batch, it runs without waiting for the delay.
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_size(3)
+    .max_batch_delay(Duration::from_secs(10))
+    .build(move |val: Vec<u64>, done: Done| {
+      tx.send(val).unwrap();
+      done.ok();
+    });
+  batcher.append(vec![1], None);
+  batcher.with_pending(|val| val.extend(vec![2, 3]));
+  assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(vec![1, 2, 3]));
+
+  // Merged values still expire, with the earliest of them.
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap()
+  });
+  batcher.append(vec![1], None);
+  let (_, done) = rx.recv().unwrap();
+  let (expired_tx, expired) = mpsc::channel();
+  let cb = move |res| expired_tx.send(res).unwrap();
+  batcher.append_with_ttl(
+    vec![2],
+    Duration::from_millis(10),
+    Some(Box::new(cb)),
+  );
+  let ticket = batcher.append_ticket(vec![3]);
+  batcher.with_pending(|val| val.reverse());
+  thread::sleep(Duration::from_millis(30));
+  done.ok();
+  let res = expired.recv_timeout(Duration::from_secs(1)).unwrap();
+  assert!(matches!(res, Err(BatchError::Expired)));
+  assert!(matches!(ticket.wait(), Err(BatchError::Expired)));
+  assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn take_pending() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap()
+  });
+  let running = batcher.append_ticket(vec![1]);
+  let (_, done) = rx.recv().unwrap();
+  let waiting = batcher.append_ticket(vec![2, 3]);
+  batcher.append_with_priority(vec![4], Priority::High, None);
+  assert_eq!(batcher.take_pending(), vec![4, 2, 3]);
+  match waiting.wait() {
+    Err(BatchError::Cancelled) => {}
+    res => panic!("unexpected result {:?}", res),
+  }
+  assert!(batcher.take_pending().is_empty());
+  done.ok();
+  assert!(running.wait().is_ok());
+  assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn watch_state() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::new(move |val: Vec<u64>, done: Done| {
+    tx.send((val, done)).unwrap()
+  });
+  let mut watch = batcher.watch_state();
+  assert_eq!(watch.get(), BatcherState::Idle);
+  batcher.append(vec![1], None);
+  assert_eq!(watch.changed(), BatcherState::Running);
+  let (_, done) = rx.recv().unwrap();
+  let closer = batcher.clone();
+  let closing = thread::spawn(move || closer.close());
+  let draining = |state| state == BatcherState::Draining;
+  let timeout = Some(Duration::from_secs(5));
+  assert_eq!(
+    watch.wait_for(draining, timeout),
+    Some(BatcherState::Draining)
+  );
+  done.ok();
+  let closed = |state| state == BatcherState::Closed;
+  assert_eq!(watch.wait_for(closed, timeout), Some(BatcherState::Closed));
+  assert!(closing.join().unwrap().is_ok());
+  let idle = |state| state == BatcherState::Idle;
+  assert_eq!(watch.wait_for(idle, Some(Duration::from_millis(10))), None);
+}
+
+#[test]
+fn max_sync_dispatches() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_size(1)
+    .max_sync_dispatches(2)
+    .build(move |val: Vec<u64>, done: Done| {
+      tx.send((val, thread::current().id())).unwrap();
+      done.ok();
+    });
+  let more = batcher.clone();
+  let cb = move |_| {
+    for i in 1..4 {
+      more.append(vec![i], None);
+    }
+  };
+  batcher.append(vec![0], Some(Box::new(cb)));
+  let ran: Vec<_> = rx.iter().take(4).collect();
+  let batches: Vec<_> = ran.iter().map(|(val, _)| val.clone()).collect();
+  assert_eq!(batches, vec![vec![0], vec![1], vec![2], vec![3]]);
+  // After two batches in a row the timer thread takes over.
+  let appending = thread::current().id();
+  assert_eq!((ran[0].1, ran[1].1), (appending, appending));
+  assert_ne!(ran[2].1, appending);
+}
+
+#[test]
+fn min_batch_size() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .min_batch_size(3, Duration::from_millis(200))
+    .build(move |val: Vec<u64>, done: Done| {
+      tx.send(val).unwrap();
+      done.ok();
+    });
+  batcher.append(vec![0], None);
+  batcher.append(vec![1], None);
+  assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+  batcher.append(vec![2], None);
+  assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(vec![0, 1, 2]));
+  // Short of three values, the batch runs once it waited long enough.
+  let start = Instant::now();
+  batcher.append(vec![3], None);
+  assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(vec![3]));
+  assert!(start.elapsed() >= Duration::from_millis(150));
+}
+
+#[test]
+fn core_batcher() {
+  use std::sync::atomic::{AtomicBool, Ordering};
+  let sent = Rc::new(RefCell::new(Vec::new()));
+  let radio = sent.clone();
+  let signal = Arc::new(AtomicBool::new(false));
+  let mut batcher = CoreBatcher::new(signal.clone(), move |val: Vec<u16>| {
+    radio.borrow_mut().push(val);
+  });
+  let acked = Rc::new(RefCell::new(0));
+  let ack = acked.clone();
+  batcher.append(vec![1, 2], None);
+  batcher.append_one(3, None);
+  batcher.append(vec![4], Some(Box::new(move || *ack.borrow_mut() += 1)));
+  assert!(!batcher.poll());
+  assert_eq!(batcher.pending(), 2);
+  // The transmission went out, e.g. signalled by an interrupt.
+  signal.store(true, Ordering::SeqCst);
+  assert!(batcher.poll());
+  assert_eq!(*acked.borrow(), 0);
+  signal.store(true, Ordering::SeqCst);
+  assert!(batcher.poll());
+  assert_eq!(*acked.borrow(), 1);
+  assert!(!batcher.is_running());
+  assert_eq!(*sent.borrow(), vec![vec![1, 2], vec![3, 4]]);
+}
+
+#[test]
+fn poll_complete() {
+  let (tx, rx) = mpsc::channel();
+  let batcher = Batcher::builder()
+    .max_batch_delay(Duration::from_millis(20))
+    .batch_timeout(Duration::from_millis(40))
+    .without_timer()
+    .build(move |val: Vec<u64>, done: Done| tx.send((val, done)).unwrap());
+  let ticket = batcher.append_ticket(vec![1]);
+  let outcome = batcher.poll_complete();
+  assert_eq!((outcome.dispatched, outcome.timed_out), (0, 0));
+  let due = outcome.next_due.unwrap();
+  // Without a timer thread nothing happens until the batcher is polled.
+  thread::sleep(due - Instant::now() + Duration::from_millis(10));
+  assert!(rx.try_recv().is_err());
+  let outcome = batcher.poll_complete();
+  assert_eq!((outcome.dispatched, outcome.timed_out), (1, 0));
+  let (val, _done) = rx.try_recv().unwrap();
+  assert_eq!(val, vec![1]);
+  let due = outcome.next_due.unwrap();
+  thread::sleep(due.saturating_duration_since(Instant::now()));
+  let outcome = batcher.poll_complete();
+  assert_eq!((outcome.dispatched, outcome.timed_out), (0, 1));
+  assert_eq!(outcome.next_due, None);
+  assert!(matches!(ticket.wait(), Err(BatchError::Timeout)));
 }